@@ -0,0 +1,694 @@
+use std::sync::Arc;
+
+use jiff::{SignedDuration, Timestamp};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info, warn};
+use zeroize::Zeroizing;
+
+use crate::brightwheel::{BrightwheelClient, LoginError};
+use crate::to_redacted_json_debug;
+
+pub const COOKIES_PATH: &str = "cookies.json";
+
+// How long a verification code is assumed to stay valid after it was sent.
+const MFA_CODE_LIFETIME: SignedDuration = SignedDuration::from_mins(10);
+
+// How long the credentials are kept for the MFA step, however many codes are sent.
+const PENDING_LOGIN_LIFETIME: SignedDuration = SignedDuration::from_mins(30);
+
+// Keys a sessions/start response might list the MFA delivery methods, or the one it
+// used, under.
+const MFA_METHODS_KEYS: [&str; 3] = ["2fa_methods", "mfa_methods", "available_2fa_methods"];
+const MFA_METHOD_KEYS: [&str; 2] = ["2fa_method", "mfa_method"];
+
+// The login steps of BrightwheelClient, so the state machine can run against a fake.
+pub trait AuthApi {
+    // mfa_method_opt picks how the verification code is delivered.
+    async fn sessions_start(&self, email: &str, password: &str, mfa_method_opt: Option<&str>) -> Result<Value, LoginError>;
+
+    async fn sessions(&self, email: &str, password: &str, mfa_code_opt: Option<&str>) -> Result<Value, LoginError>;
+
+    async fn verify_session(&self) -> Result<String, LoginError>;
+
+    // Keeps the session for the next launch.
+    fn save_session(&self);
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MfaMethod {
+    Email,
+    Sms,
+}
+
+impl MfaMethod {
+    fn as_str(self) -> &'static str {
+        match self {
+            MfaMethod::Email => "email",
+            MfaMethod::Sms => "sms",
+        }
+    }
+
+    fn parse(name: &str) -> Option<MfaMethod> {
+        match name.to_ascii_lowercase().as_str() {
+            "email" => Some(MfaMethod::Email),
+            "sms" | "text" | "phone" => Some(MfaMethod::Sms),
+            _ => None,
+        }
+    }
+}
+
+// What the MFA view shows: how the code can be and was sent, and until when it's good.
+#[derive(Serialize, Clone, Debug)]
+pub struct MfaStatus {
+    pub methods: Vec<MfaMethod>,
+    pub method: MfaMethod,
+    pub expires_at: Timestamp,
+}
+
+// The login flow. Failed steps land in Error, which keeps the client and remembers
+// which step failed, so the user can retry it (or start over) without a restart.
+pub enum AppState<C = BrightwheelClient> {
+    Start(StartState<C>),
+    NeedsMfa(NeedsMfaState<C>),
+    LoggedIn(LoggedInState<C>),
+    Error(ErrorState<C>),
+}
+
+pub struct StartState<C = BrightwheelClient> {
+    pub bw_client: C,
+}
+
+// Waiting for a verification code. The credentials are kept so the code can be
+// submitted (or resent) without asking for them again, until PENDING_LOGIN_LIFETIME
+// runs out; they're wiped from memory when the state goes away.
+pub struct NeedsMfaState<C = BrightwheelClient> {
+    pub bw_client: C,
+    pending: PendingMfa,
+}
+
+// The client is shared with running syncs, so the app state is only locked long
+// enough to clone it.
+pub struct LoggedInState<C = BrightwheelClient> {
+    pub bw_client: Arc<C>,
+}
+
+pub struct ErrorState<C = BrightwheelClient> {
+    pub message: String,
+    bw_client: C,
+    // The step to go back to on retry.
+    retry_step: RetryStep,
+}
+
+enum RetryStep {
+    Start,
+    NeedsMfa(PendingMfa),
+}
+
+struct PendingMfa {
+    email: Zeroizing<String>,
+    password: Zeroizing<String>,
+    methods: Vec<MfaMethod>,
+    method: MfaMethod,
+    started_at: Timestamp,
+    code_sent_at: Timestamp,
+}
+
+impl PendingMfa {
+    fn new(email: &str, password: &str, response_json: &Value, now: Timestamp) -> PendingMfa {
+        let mut methods = Vec::new();
+        for key in MFA_METHODS_KEYS {
+            for item in response_json.get(key).and_then(Value::as_array).into_iter().flatten() {
+                let name_opt = item.as_str()
+                    .or_else(|| ["type", "method", "name"].iter().find_map(|key| item.get(key)?.as_str()));
+                if let Some(method) = name_opt.and_then(MfaMethod::parse) {
+                    if !methods.contains(&method) {
+                        methods.push(method);
+                    }
+                }
+            }
+        }
+        let sent_method_opt = MFA_METHOD_KEYS.iter()
+            .find_map(|key| response_json.get(key)?.as_str())
+            .and_then(MfaMethod::parse);
+        if let Some(sent_method) = sent_method_opt {
+            if !methods.contains(&sent_method) {
+                methods.push(sent_method);
+            }
+        }
+        // Codes go by email unless brightwheel says otherwise.
+        if methods.is_empty() {
+            methods.push(MfaMethod::Email);
+        }
+        PendingMfa {
+            email: Zeroizing::new(email.into()),
+            password: Zeroizing::new(password.into()),
+            method: sent_method_opt.unwrap_or(methods[0]),
+            methods,
+            started_at: now,
+            code_sent_at: now,
+        }
+    }
+
+    fn expires_at(&self) -> Timestamp {
+        self.code_sent_at + MFA_CODE_LIFETIME
+    }
+
+    fn wipe_at(&self) -> Timestamp {
+        self.started_at + PENDING_LOGIN_LIFETIME
+    }
+
+    fn status(&self) -> MfaStatus {
+        MfaStatus { methods: self.methods.clone(), method: self.method, expires_at: self.expires_at() }
+    }
+}
+
+impl<C: AuthApi> AppState<C> {
+    // Logs in from Start. From an error or the MFA step, this starts over first.
+    // Already being logged in is left alone.
+    pub async fn login(self, email: &str, password: &str) -> AppState<C> {
+        match self {
+            AppState::Start(start_state) => start_state.login(email, password).await,
+            AppState::NeedsMfa(needs_mfa_state) => needs_mfa_state.restart().login(email, password).await,
+            AppState::Error(error_state) => error_state.restart().login(email, password).await,
+            AppState::LoggedIn(_) => self,
+        }
+    }
+
+    // Submits a verification code from NeedsMfa, or retries one after a failed code.
+    // Any other state is left alone.
+    pub async fn login_mfa(self, mfa_code: &str) -> AppState<C> {
+        match self.expire_pending(Timestamp::now()).retry_mfa() {
+            AppState::NeedsMfa(needs_mfa_state) => needs_mfa_state.complete_login(mfa_code).await,
+            state => state,
+        }
+    }
+
+    // Sends a new verification code, switching to mfa_method_opt if given.
+    pub async fn resend_mfa_code(self, mfa_method_opt: Option<MfaMethod>) -> AppState<C> {
+        match self.expire_pending(Timestamp::now()).retry_mfa() {
+            AppState::NeedsMfa(needs_mfa_state) => needs_mfa_state.resend_code(mfa_method_opt).await,
+            state => state,
+        }
+    }
+
+    // Abandons a login in progress.
+    pub fn restart(self) -> AppState<C> {
+        match self {
+            AppState::NeedsMfa(needs_mfa_state) => AppState::Start(needs_mfa_state.restart()),
+            AppState::Error(error_state) => AppState::Start(error_state.restart()),
+            _ => self,
+        }
+    }
+
+    // The view to show for this state.
+    pub fn tab_name(&self) -> &'static str {
+        match self {
+            AppState::Start(_) => "login",
+            AppState::NeedsMfa(_) => "mfa",
+            AppState::LoggedIn(_) => "loggedin",
+            AppState::Error(error_state) => match error_state.retry_step {
+                RetryStep::Start => "login",
+                RetryStep::NeedsMfa(_) => "mfa",
+            },
+        }
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            AppState::Error(error_state) => Some(&error_state.message),
+            _ => None,
+        }
+    }
+
+    pub fn mfa_status(&self) -> Option<MfaStatus> {
+        match self {
+            AppState::NeedsMfa(needs_mfa_state) => Some(needs_mfa_state.pending.status()),
+            AppState::Error(ErrorState { retry_step: RetryStep::NeedsMfa(pending), .. }) => Some(pending.status()),
+            _ => None,
+        }
+    }
+
+    // When the credentials held for the MFA step are due to be wiped.
+    pub fn pending_wipe_at(&self) -> Option<Timestamp> {
+        match self {
+            AppState::NeedsMfa(needs_mfa_state) => Some(needs_mfa_state.pending.wipe_at()),
+            AppState::Error(ErrorState { retry_step: RetryStep::NeedsMfa(pending), .. }) => Some(pending.wipe_at()),
+            _ => None,
+        }
+    }
+
+    // Drops the credentials held for the MFA step once they are due to be wiped,
+    // sending the user back to the login form.
+    pub fn expire_pending(self, now: Timestamp) -> AppState<C> {
+        if self.pending_wipe_at().is_none_or(|wipe_at| now < wipe_at) {
+            return self;
+        }
+        info!("pending login timed out; wiping its credentials");
+        match self {
+            AppState::NeedsMfa(NeedsMfaState { bw_client, .. }) | AppState::Error(ErrorState { bw_client, .. }) => {
+                AppState::Error(ErrorState::new(bw_client, LoginError::LoginTimedOut, RetryStep::Start))
+            },
+            _ => self,
+        }
+    }
+
+    // Goes back to the MFA step after an error there.
+    fn retry_mfa(self) -> AppState<C> {
+        match self {
+            AppState::Error(error_state) if matches!(error_state.retry_step, RetryStep::NeedsMfa(_)) => error_state.retry(),
+            _ => self,
+        }
+    }
+}
+
+impl<C: AuthApi> StartState<C> {
+    pub async fn login(self, email: &str, password: &str) -> AppState<C> {
+        let bw_client = self.bw_client;
+
+        let response_json = match bw_client.sessions_start(email, password, None).await {
+            Ok(response_json) => response_json,
+            Err(e) => return AppState::Error(ErrorState::new(bw_client, e, RetryStep::Start)),
+        };
+        debug!("/sessions/start response_json: {}", to_redacted_json_debug(&response_json));
+        match response_json.get("2fa_required") {
+            Some(Value::Bool(true)) => AppState::NeedsMfa(NeedsMfaState {
+                bw_client,
+                pending: PendingMfa::new(email, password, &response_json, Timestamp::now()),
+            }),
+            Some(Value::Bool(false)) => match bw_client.sessions(email, password, None).await {
+                Ok(response_json) => {
+                    debug!("/sessions response_json: {}", to_redacted_json_debug(&response_json));
+                    finish_login(bw_client, RetryStep::Start).await
+                },
+                Err(e) => AppState::Error(ErrorState::new(bw_client, e, RetryStep::Start)),
+            },
+            Some(_) => AppState::Error(ErrorState::new(
+                bw_client, LoginError::Other("2fa_required is not a bool".into()), RetryStep::Start
+            )),
+            // No MFA step; sessions/start may already have signed us in.
+            None => finish_login(bw_client, RetryStep::Start).await,
+        }
+    }
+}
+
+impl<C: AuthApi> NeedsMfaState<C> {
+    pub async fn complete_login(self, mfa_code: &str) -> AppState<C> {
+        let NeedsMfaState { bw_client, pending } = self;
+        if Timestamp::now() > pending.expires_at() {
+            return AppState::Error(ErrorState::new(bw_client, LoginError::MfaCodeExpired, RetryStep::NeedsMfa(pending)));
+        }
+        match bw_client.sessions(&pending.email, &pending.password, Some(mfa_code)).await {
+            Ok(response_json) => {
+                debug!("/sessions response_json: {}", to_redacted_json_debug(&response_json));
+                finish_login(bw_client, RetryStep::NeedsMfa(pending)).await
+            },
+            Err(e) => AppState::Error(ErrorState::new(bw_client, e, RetryStep::NeedsMfa(pending))),
+        }
+    }
+
+    // Starting the login again is what makes brightwheel send another code.
+    pub async fn resend_code(self, mfa_method_opt: Option<MfaMethod>) -> AppState<C> {
+        let NeedsMfaState { bw_client, mut pending } = self;
+        let method = mfa_method_opt.unwrap_or(pending.method);
+        match bw_client.sessions_start(&pending.email, &pending.password, Some(method.as_str())).await {
+            Ok(response_json) => {
+                debug!("/sessions/start response_json: {}", to_redacted_json_debug(&response_json));
+                pending.method = method;
+                pending.code_sent_at = Timestamp::now();
+                AppState::NeedsMfa(NeedsMfaState { bw_client, pending })
+            },
+            Err(e) => AppState::Error(ErrorState::new(bw_client, e, RetryStep::NeedsMfa(pending))),
+        }
+    }
+
+    pub fn restart(self) -> StartState<C> {
+        StartState { bw_client: self.bw_client }
+    }
+}
+
+impl<C> ErrorState<C> {
+    fn new(bw_client: C, error: LoginError, failed_step: RetryStep) -> ErrorState<C> {
+        warn!("login failed: {:?}", error);
+        // Only a bad code can be fixed from the MFA step; anything wrong with the
+        // account itself needs the email and password again.
+        let retry_step = match error {
+            LoginError::BadCredentials | LoginError::AccountLocked | LoginError::LoginTimedOut => RetryStep::Start,
+            _ => failed_step,
+        };
+        ErrorState { message: error.to_string(), bw_client, retry_step }
+    }
+
+    // Goes back to the step that failed.
+    pub fn retry(self) -> AppState<C> {
+        match self.retry_step {
+            RetryStep::Start => AppState::Start(StartState { bw_client: self.bw_client }),
+            RetryStep::NeedsMfa(pending) => AppState::NeedsMfa(NeedsMfaState { bw_client: self.bw_client, pending }),
+        }
+    }
+
+    pub fn restart(self) -> StartState<C> {
+        StartState { bw_client: self.bw_client }
+    }
+}
+
+// A sessions response that looks like success isn't proof of a working session, so
+// check with users/me before keeping the cookies.
+async fn finish_login<C: AuthApi>(bw_client: C, step: RetryStep) -> AppState<C> {
+    match bw_client.verify_session().await {
+        Ok(user_id) => {
+            debug!("logged in as {}", user_id);
+            bw_client.save_session();
+            AppState::LoggedIn(LoggedInState { bw_client: Arc::new(bw_client) })
+        },
+        Err(e) => AppState::Error(ErrorState::new(bw_client, e, step)),
+    }
+}
+
+impl AuthApi for BrightwheelClient {
+    async fn sessions_start(&self, email: &str, password: &str, mfa_method_opt: Option<&str>) -> Result<Value, LoginError> {
+        self.post_sessions_start(email, password, mfa_method_opt).await
+    }
+
+    async fn sessions(&self, email: &str, password: &str, mfa_code_opt: Option<&str>) -> Result<Value, LoginError> {
+        self.post_sessions(email, password, mfa_code_opt).await
+    }
+
+    async fn verify_session(&self) -> Result<String, LoginError> {
+        BrightwheelClient::verify_session(self).await
+    }
+
+    #[allow(deprecated)]
+    fn save_session(&self) {
+        let mut writer = std::fs::File::create(COOKIES_PATH)
+            .map(std::io::BufWriter::new)
+            .unwrap();
+        if let Err(e) = self.cookie_store_arc_mutex.lock().unwrap().save_json(&mut writer) {
+            warn!("could not save cookies: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::{Cell, RefCell}, collections::VecDeque, future::Future};
+
+    use serde_json::json;
+
+    use super::*;
+
+    // Answers each login call with the next scripted response.
+    #[derive(Default)]
+    struct FakeAuth {
+        start_responses: RefCell<VecDeque<Result<Value, LoginError>>>,
+        sessions_responses: RefCell<VecDeque<Result<Value, LoginError>>>,
+        verify_responses: RefCell<VecDeque<Result<String, LoginError>>>,
+        mfa_methods: RefCell<Vec<Option<String>>>,
+        // (email, password, code) for each sessions call.
+        sessions_calls: RefCell<Vec<(String, String, Option<String>)>>,
+        saved: Cell<bool>,
+    }
+
+    impl FakeAuth {
+        fn start(self, response: Result<Value, LoginError>) -> FakeAuth {
+            self.start_responses.borrow_mut().push_back(response);
+            self
+        }
+
+        fn sessions(self, response: Result<Value, LoginError>) -> FakeAuth {
+            self.sessions_responses.borrow_mut().push_back(response);
+            self
+        }
+
+        fn verify(self, response: Result<String, LoginError>) -> FakeAuth {
+            self.verify_responses.borrow_mut().push_back(response);
+            self
+        }
+
+        fn codes(&self) -> Vec<Option<String>> {
+            Vec::from_iter(self.sessions_calls.borrow().iter().map(|(_, _, code_opt)| code_opt.clone()))
+        }
+    }
+
+    impl AuthApi for FakeAuth {
+        async fn sessions_start(&self, _email: &str, _password: &str, mfa_method_opt: Option<&str>) -> Result<Value, LoginError> {
+            self.mfa_methods.borrow_mut().push(mfa_method_opt.map(String::from));
+            self.start_responses.borrow_mut().pop_front().expect("unexpected sessions/start")
+        }
+
+        async fn sessions(&self, email: &str, password: &str, mfa_code_opt: Option<&str>) -> Result<Value, LoginError> {
+            self.sessions_calls.borrow_mut().push((email.into(), password.into(), mfa_code_opt.map(String::from)));
+            self.sessions_responses.borrow_mut().pop_front().expect("unexpected sessions")
+        }
+
+        async fn verify_session(&self) -> Result<String, LoginError> {
+            self.verify_responses.borrow_mut().pop_front().expect("unexpected users/me")
+        }
+
+        fn save_session(&self) {
+            self.saved.set(true);
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+    }
+
+    fn start(fake: FakeAuth) -> AppState<FakeAuth> {
+        AppState::Start(StartState { bw_client: fake })
+    }
+
+    // Waiting for a code sent sent_mins_ago by email.
+    fn needs_mfa(fake: FakeAuth, sent_mins_ago: i64) -> AppState<FakeAuth> {
+        let sent_at = Timestamp::now() - SignedDuration::from_mins(sent_mins_ago);
+        AppState::NeedsMfa(NeedsMfaState {
+            bw_client: fake,
+            pending: PendingMfa::new("me@example.com", "hunter2", &json!({ "2fa_required": true }), sent_at),
+        })
+    }
+
+    fn login(state: AppState<FakeAuth>) -> AppState<FakeAuth> {
+        block_on(state.login("me@example.com", "hunter2"))
+    }
+
+    fn login_mfa(state: AppState<FakeAuth>, code: &str) -> AppState<FakeAuth> {
+        block_on(state.login_mfa(code))
+    }
+
+    fn client(state: &AppState<FakeAuth>) -> &FakeAuth {
+        match state {
+            AppState::Start(start_state) => &start_state.bw_client,
+            AppState::NeedsMfa(needs_mfa_state) => &needs_mfa_state.bw_client,
+            AppState::LoggedIn(logged_in_state) => &logged_in_state.bw_client,
+            AppState::Error(error_state) => &error_state.bw_client,
+        }
+    }
+
+    #[test]
+    fn start_without_mfa_logs_in() {
+        let fake = FakeAuth::default()
+            .start(Ok(json!({ "2fa_required": false })))
+            .sessions(Ok(json!({ "user": {} })))
+            .verify(Ok("u1".into()));
+        let state = login(start(fake));
+        assert!(matches!(state, AppState::LoggedIn(_)));
+        assert!(client(&state).saved.get());
+        assert_eq!(client(&state).codes(), [None]);
+    }
+
+    #[test]
+    fn start_without_2fa_field_is_verified() {
+        let state = login(start(FakeAuth::default().start(Ok(json!({}))).verify(Ok("u1".into()))));
+        assert!(matches!(state, AppState::LoggedIn(_)));
+
+        let fake = FakeAuth::default()
+            .start(Ok(json!({})))
+            .verify(Err(LoginError::Other("HTTP 401".into())));
+        let state = login(start(fake));
+        assert!(matches!(state, AppState::Error(_)));
+        assert_eq!(state.tab_name(), "login");
+        assert!(!client(&state).saved.get());
+    }
+
+    #[test]
+    fn start_needing_mfa_asks_for_a_code() {
+        let fake = FakeAuth::default().start(Ok(json!({ "2fa_required": true, "2fa_methods": ["sms", "email"] })));
+        let state = login(start(fake));
+        assert!(matches!(state, AppState::NeedsMfa(_)));
+        assert_eq!(state.tab_name(), "mfa");
+        let mfa_status = state.mfa_status().unwrap();
+        assert_eq!(mfa_status.methods, [MfaMethod::Sms, MfaMethod::Email]);
+        assert_eq!(mfa_status.method, MfaMethod::Sms);
+    }
+
+    #[test]
+    fn mfa_methods_are_read_from_the_response() {
+        let now = Timestamp::now();
+        let pending = PendingMfa::new("e", "p", &json!({
+            "mfa_methods": [{ "type": "email" }, { "type": "text" }, { "type": "carrier pigeon" }],
+            "2fa_method": "sms",
+        }), now);
+        assert_eq!(pending.methods, [MfaMethod::Email, MfaMethod::Sms]);
+        assert_eq!(pending.method, MfaMethod::Sms);
+        assert_eq!(pending.expires_at(), now + MFA_CODE_LIFETIME);
+
+        let pending = PendingMfa::new("e", "p", &json!({ "2fa_required": true }), now);
+        assert_eq!(pending.methods, [MfaMethod::Email]);
+    }
+
+    #[test]
+    fn bad_password_can_be_retried() {
+        let fake = FakeAuth::default()
+            .start(Err(LoginError::BadCredentials))
+            .start(Ok(json!({ "2fa_required": false })))
+            .sessions(Ok(json!({})))
+            .verify(Ok("u1".into()));
+        let state = login(start(fake));
+        assert!(matches!(state, AppState::Error(_)));
+        assert_eq!(state.tab_name(), "login");
+        assert_eq!(state.message(), Some("Incorrect email or password."));
+
+        let state = login(state);
+        assert!(matches!(state, AppState::LoggedIn(_)));
+        assert_eq!(state.message(), None);
+    }
+
+    #[test]
+    fn mfa_code_logs_in_with_the_stored_credentials() {
+        let fake = FakeAuth::default().sessions(Ok(json!({}))).verify(Ok("u1".into()));
+        let state = login_mfa(needs_mfa(fake, 1), "123456");
+        assert!(matches!(state, AppState::LoggedIn(_)));
+        assert_eq!(
+            *client(&state).sessions_calls.borrow(),
+            [("me@example.com".to_string(), "hunter2".to_string(), Some("123456".to_string()))]
+        );
+    }
+
+    #[test]
+    fn invalid_code_returns_to_the_mfa_step() {
+        let fake = FakeAuth::default()
+            .sessions(Err(LoginError::InvalidMfaCode))
+            .sessions(Ok(json!({})))
+            .verify(Ok("u1".into()));
+        let state = login_mfa(needs_mfa(fake, 1), "000000");
+        assert!(matches!(state, AppState::Error(_)));
+        assert_eq!(state.tab_name(), "mfa");
+        assert!(state.mfa_status().is_some());
+
+        let state = login_mfa(state, "123456");
+        assert!(matches!(state, AppState::LoggedIn(_)));
+    }
+
+    #[test]
+    fn expired_code_is_not_sent() {
+        let state = login_mfa(needs_mfa(FakeAuth::default(), 11), "123456");
+        assert_eq!(state.message(), Some("That verification code has expired. Request a new one."));
+        assert_eq!(state.tab_name(), "mfa");
+        assert!(client(&state).codes().is_empty());
+    }
+
+    #[test]
+    fn resending_the_code_restarts_the_clock() {
+        let fake = FakeAuth::default()
+            .start(Ok(json!({ "2fa_required": true })))
+            .sessions(Ok(json!({})))
+            .verify(Ok("u1".into()));
+        let state = login_mfa(needs_mfa(fake, 11), "123456");
+
+        let state = block_on(state.resend_mfa_code(Some(MfaMethod::Sms)));
+        assert!(matches!(state, AppState::NeedsMfa(_)));
+        let mfa_status = state.mfa_status().unwrap();
+        assert_eq!(mfa_status.method, MfaMethod::Sms);
+        assert!(mfa_status.expires_at > Timestamp::now());
+        assert_eq!(*client(&state).mfa_methods.borrow(), [Some("sms".to_string())]);
+
+        let state = login_mfa(state, "123456");
+        assert!(matches!(state, AppState::LoggedIn(_)));
+    }
+
+    #[test]
+    fn failed_resend_stays_at_the_mfa_step() {
+        let fake = FakeAuth::default().start(Err(LoginError::TooManyAttempts));
+        let state = block_on(needs_mfa(fake, 1).resend_mfa_code(None));
+        assert!(matches!(state, AppState::Error(_)));
+        assert_eq!(state.tab_name(), "mfa");
+        assert_eq!(*client(&state).mfa_methods.borrow(), [Some("email".to_string())]);
+    }
+
+    #[test]
+    fn locked_account_at_the_mfa_step_goes_back_to_start() {
+        let fake = FakeAuth::default().sessions(Err(LoginError::AccountLocked));
+        let state = login_mfa(needs_mfa(fake, 1), "123456");
+        assert_eq!(state.tab_name(), "login");
+        assert!(state.mfa_status().is_none());
+
+        // A code can't be retried from here; only a fresh login can.
+        let state = login_mfa(state, "123456");
+        assert!(matches!(state, AppState::Error(_)));
+        assert_eq!(client(&state).codes().len(), 1);
+    }
+
+    #[test]
+    fn restart_goes_back_to_start() {
+        let state = needs_mfa(FakeAuth::default(), 1).restart();
+        assert!(matches!(state, AppState::Start(_)));
+
+        let state = login_mfa(needs_mfa(FakeAuth::default().sessions(Err(LoginError::TooManyAttempts)), 1), "123456");
+        assert_eq!(state.tab_name(), "mfa");
+        assert!(matches!(state.restart(), AppState::Start(_)));
+    }
+
+    #[test]
+    fn login_from_the_mfa_step_starts_over() {
+        let fake = FakeAuth::default().start(Ok(json!({ "2fa_required": true })));
+        let state = login(needs_mfa(fake, 1));
+        assert!(matches!(state, AppState::NeedsMfa(_)));
+        assert!(client(&state).start_responses.borrow().is_empty());
+    }
+
+    #[test]
+    fn pending_login_times_out() {
+        let state = needs_mfa(FakeAuth::default(), 1);
+        let wipe_at = state.pending_wipe_at().unwrap();
+        let state = state.expire_pending(wipe_at - SignedDuration::from_secs(1));
+        assert!(matches!(state, AppState::NeedsMfa(_)));
+
+        let state = state.expire_pending(wipe_at);
+        assert_eq!(state.message(), Some("The login timed out. Enter your email and password again."));
+        assert_eq!(state.tab_name(), "login");
+        assert!(state.pending_wipe_at().is_none());
+    }
+
+    #[test]
+    fn timed_out_login_does_not_send_the_code() {
+        let state = login_mfa(needs_mfa(FakeAuth::default(), 31), "123456");
+        assert_eq!(state.tab_name(), "login");
+        assert!(client(&state).codes().is_empty());
+
+        let state = block_on(needs_mfa(FakeAuth::default(), 31).resend_mfa_code(None));
+        assert_eq!(state.tab_name(), "login");
+        assert!(client(&state).mfa_methods.borrow().is_empty());
+    }
+
+    #[test]
+    fn resending_does_not_extend_the_pending_login() {
+        let fake = FakeAuth::default().start(Ok(json!({ "2fa_required": true })));
+        let state = needs_mfa(fake, 20);
+        let wipe_at = state.pending_wipe_at().unwrap();
+        let state = block_on(state.resend_mfa_code(None));
+        assert_eq!(state.pending_wipe_at(), Some(wipe_at));
+    }
+
+    #[test]
+    fn wrong_state_calls_are_ignored() {
+        let state = login_mfa(start(FakeAuth::default()), "123456");
+        assert!(matches!(state, AppState::Start(_)));
+        let state = block_on(state.resend_mfa_code(None));
+        assert!(matches!(state, AppState::Start(_)));
+
+        let logged_in = login(start(FakeAuth::default().start(Ok(json!({}))).verify(Ok("u1".into()))));
+        let state = login(logged_in).restart();
+        assert!(matches!(state, AppState::LoggedIn(_)));
+    }
+}
@@ -1,9 +1,32 @@
-use std::{collections::HashMap, io::{BufRead, Read}, path::PathBuf, sync::{Arc, Mutex}, time::Duration};
+use std::{
+    collections::{HashMap, HashSet}, fmt, io::{self, BufRead, BufReader, Read}, path::PathBuf,
+    sync::{mpsc::{sync_channel, Receiver, SyncSender}, Arc, Mutex}, thread, time::Duration,
+};
 
 use map_macro::hash_map;
 
 const URL_BASE: &str = "https://schools.mybrightwheel.com/api/v1/";
 
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+
+// Upper bound on pages fetched from one activity feed, in case a server never stops.
+const MAX_ACTIVITY_PAGES: usize = 10_000;
+
+// Page size for message threads, messages and announcements, and the most pages
+// fetched from one of those lists.
+const MESSAGES_PAGE_SIZE: usize = 100;
+const MAX_MESSAGES_PAGES: usize = 1000;
+
+// Activities parsed ahead of the sync engine, per page.
+const PAGE_CHANNEL_CAPACITY: usize = 16;
+
+// Response body chunks fetched ahead of a blocking reader.
+const BODY_CHANNEL_CAPACITY: usize = 8;
+
+// Keys an activities response might report its total or next-page cursor under.
+const TOTAL_KEYS: [&str; 3] = ["total_count", "total", "count"];
+const CURSOR_KEYS: [&str; 2] = ["next_cursor", "cursor"];
+
 const COOKIE_NAME: &str = "_brightwheel_v2";
 const COOKIE_DOMAIN: &str = ".mybrightwheel.com";
 
@@ -17,12 +40,20 @@ const AUTH_HEADERS_JSON: &str = r#"{
     "User-Agent": "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:139.0) Gecko/20100101 Firefox/139.0"
 }"#;
 
+use bytes::Bytes;
 use reqwest::{
-    blocking::{Client, Response}, cookie::{Jar}, header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, ORIGIN, REFERER, USER_AGENT}
+    Client, Response, StatusCode, cookie::{Jar}, header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, ORIGIN, REFERER, USER_AGENT}
 };
 use reqwest_cookie_store::CookieStoreMutex;
-use serde::Serialize;
-use serde_json::{json, Value};
+use jiff::{civil::Date, Timestamp};
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor}, Deserialize, Deserializer, Serialize,
+};
+use serde_json::{json, value::RawValue, Value};
+use tokio::{runtime::Handle, sync::mpsc};
+use tracing::{debug, debug_span, warn, Span};
+
+use crate::logging::{redact_error, redact_json};
 
 pub struct BrightwheelClient {
     client: Client,
@@ -30,11 +61,447 @@ pub struct BrightwheelClient {
     auth_headers: HeaderMap,
 }
 
-#[derive(Serialize, Debug)]
+// Bounds on an activity's created_at; either end may be open.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DateRange {
+    pub since: Option<Timestamp>,
+    pub until: Option<Timestamp>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Activity {
+    pub object_id: String,
+    pub created_at: Timestamp,
+    #[serde(default)]
+    pub action_type: Option<String>,
+    #[serde(default)]
+    pub note: Option<String>,
+    // Usually one photo, but may be an array of them.
+    #[serde(default, deserialize_with = "deserialize_media")]
+    pub media: Vec<Media>,
+    // The photos of a multi-photo post, when they come separately from media.
+    #[serde(default, deserialize_with = "deserialize_aliased_list")]
+    pub media_list: Vec<Media>,
+    #[serde(default)]
+    pub video_info: Option<VideoInfo>,
+}
+
+impl Aliased for Activity {
+    const ALIASES: &'static [(&'static str, &'static [&'static str])] = &[("media_list", &["media_items"])];
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Media {
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub object_id: Option<String>,
+    pub image_url: String,
+}
+
+impl Aliased for Media {}
+
+impl Activity {
+    // Every photo in the activity, in order, each once.
+    pub fn photos(&self) -> Vec<&Media> {
+        let mut photos: Vec<&Media> = Vec::new();
+        for media in self.media.iter().chain(&self.media_list) {
+            if !photos.iter().any(|photo| photo.image_url == media.image_url) {
+                photos.push(media);
+            }
+        }
+        photos
+    }
+}
+
+fn deserialize_media<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Media>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Array(items) => Vec::from_iter(items.into_iter().filter_map(|item| Media::from_json(item).ok())),
+        media @ Value::Object(_) => Vec::from_iter(Media::from_json(media).ok()),
+        _ => Vec::new(),
+    })
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct VideoInfo {
+    pub downloadable_url: String,
+}
+
+// Why brightwheel turned a login attempt down.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoginError {
+    BadCredentials,
+    InvalidMfaCode,
+    MfaCodeExpired,
+    // The MFA step was left open too long and its credentials were wiped.
+    LoginTimedOut,
+    AccountLocked,
+    TooManyAttempts,
+    // Anything else, with whatever brightwheel or the connection said.
+    Other(String),
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoginError::BadCredentials => formatter.write_str("Incorrect email or password."),
+            LoginError::InvalidMfaCode => formatter.write_str("That verification code is not valid. Check it and try again."),
+            LoginError::MfaCodeExpired => formatter.write_str("That verification code has expired. Request a new one."),
+            LoginError::LoginTimedOut => formatter.write_str("The login timed out. Enter your email and password again."),
+            LoginError::AccountLocked => formatter.write_str(
+                "This brightwheel account is locked. Unlock it through brightwheel, then try again."
+            ),
+            LoginError::TooManyAttempts => formatter.write_str("Too many login attempts. Wait a few minutes and try again."),
+            LoginError::Other(msg) => write!(formatter, "Login failed: {}", msg),
+        }
+    }
+}
+
+impl LoginError {
+    // Classifies a failed sessions response from its status and error payload.
+    // mfa is whether the request carried a verification code.
+    pub fn from_response(status: StatusCode, body: &Value, mfa: bool) -> LoginError {
+        let message_opt = error_message(body);
+        let message = message_opt.as_deref().unwrap_or("").to_ascii_lowercase();
+        if status == StatusCode::LOCKED || message.contains("locked") {
+            LoginError::AccountLocked
+        }
+        else if status == StatusCode::TOO_MANY_REQUESTS || message.contains("too many") {
+            LoginError::TooManyAttempts
+        }
+        else if message.contains("expired") {
+            LoginError::MfaCodeExpired
+        }
+        else if message.contains("code") || message.contains("2fa") || message.contains("verification") {
+            LoginError::InvalidMfaCode
+        }
+        else if message.contains("password") || message.contains("email") || message.contains("credentials") {
+            LoginError::BadCredentials
+        }
+        else if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::UNPROCESSABLE_ENTITY) {
+            if mfa { LoginError::InvalidMfaCode } else { LoginError::BadCredentials }
+        }
+        else {
+            LoginError::Other(message_opt.unwrap_or_else(|| format!("HTTP {}", status)))
+        }
+    }
+}
+
+// A conversation between a guardian and the school, from guardians/{id}/message_threads.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MessageThread {
+    pub object_id: String,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub title: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_aliased")]
+    pub student: Option<StudentSummary>,
+}
+
+impl Aliased for MessageThread {
+    const ALIASES: &'static [(&'static str, &'static [&'static str])] = &[("title", &["subject", "name"])];
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Message {
+    pub object_id: String,
+    pub created_at: Timestamp,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub text: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_aliased")]
+    pub sender: Option<Sender>,
+    #[serde(default, deserialize_with = "deserialize_aliased_list")]
+    pub attachments: Vec<Attachment>,
+}
+
+impl Aliased for Message {
+    const ALIASES: &'static [(&'static str, &'static [&'static str])] = &[
+        ("text", &["body", "message"]),
+        ("sender", &["author", "sent_by"]),
+    ];
+}
+
+// A school-wide post, from guardians/{id}/announcements.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Announcement {
+    pub object_id: String,
+    pub created_at: Timestamp,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub title: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub text: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_aliased")]
+    pub sender: Option<Sender>,
+    #[serde(default, deserialize_with = "deserialize_aliased_list")]
+    pub attachments: Vec<Attachment>,
+}
+
+impl Aliased for Announcement {
+    const ALIASES: &'static [(&'static str, &'static [&'static str])] = &[
+        ("title", &["subject"]),
+        ("text", &["body", "message"]),
+        ("sender", &["author", "sent_by"]),
+    ];
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Sender {
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub first_name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub last_name: Option<String>,
+    // Set instead of the names for staff and schools.
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub name: Option<String>,
+}
+
+impl Aliased for Sender {}
+
+// A photo, PDF or other file attached to a message or announcement. One without a URL
+// is left out of its message.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Attachment {
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub object_id: Option<String>,
+    pub url: String,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub filename: Option<String>,
+}
+
+impl Aliased for Attachment {
+    const ALIASES: &'static [(&'static str, &'static [&'static str])] = &[
+        ("url", &["file_url", "download_url", "image_url"]),
+        ("filename", &["name", "file_name"]),
+    ];
+}
+
+impl Sender {
+    pub fn display_name(&self) -> String {
+        let name = Vec::from_iter(self.first_name.iter().chain(&self.last_name).map(String::as_str)).join(" ");
+        if name.is_empty() { self.name.clone().unwrap_or_default() } else { name }
+    }
+}
+
+// Whether a login response reports an error. Successful responses may still carry
+// "error": null or "errors": [], so only a value with something in it counts.
+fn has_error(body: &Value) -> bool {
+    ["error", "errors"].iter().any(|key| match body.get(key) {
+        None | Some(Value::Null | Value::Bool(false)) => false,
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Object(obj)) => !obj.is_empty(),
+        Some(_) => true,
+    })
+}
+
+// The error text in a brightwheel error payload, which may sit under "error",
+// "message" or "errors" (a string, a list, or a map of field to messages).
+fn error_message(body: &Value) -> Option<String> {
+    fn collect(value: &Value, messages: &mut Vec<String>) {
+        match value {
+            Value::String(message) => messages.push(message.clone()),
+            Value::Array(items) => items.iter().for_each(|item| collect(item, messages)),
+            Value::Object(obj) => obj.values().for_each(|item| collect(item, messages)),
+            _ => {}
+        }
+    }
+
+    let mut messages = Vec::new();
+    for key in ["error", "message", "errors", "error_description"] {
+        if let Some(value) = body.get(key) {
+            collect(value, &mut messages);
+        }
+    }
+    if messages.is_empty() { None } else { Some(messages.join("; ")) }
+}
+
+// Types brightwheel sends with the same field under more than one name. A derived
+// alias fails with "duplicate field" when a response carries two of the names at once,
+// so from_json first folds them into the field's own name: the first one present and
+// not null wins, and the rest are dropped. Nested types are read through
+// deserialize_aliased and deserialize_aliased_list so their names get folded too.
+pub trait Aliased: DeserializeOwned {
+    // Each field with its other names, in order of preference.
+    const ALIASES: &'static [(&'static str, &'static [&'static str])] = &[];
+
+    fn from_json(mut value: Value) -> Result<Self, serde_json::Error> {
+        if let Value::Object(obj) = &mut value {
+            for (field, names) in Self::ALIASES {
+                let mut found_opt = obj.remove(*field).filter(|item| !item.is_null());
+                for name in *names {
+                    let item_opt = obj.remove(*name);
+                    if found_opt.is_none() {
+                        found_opt = item_opt.filter(|item| !item.is_null());
+                    }
+                }
+                if let Some(found) = found_opt {
+                    obj.insert(field.to_string(), found);
+                }
+            }
+        }
+        serde_json::from_value(value)
+    }
+}
+
+// A field left out (None) rather than failing its whole object when it has an unexpected shape.
+fn deserialize_lenient<'de, D: Deserializer<'de>, T: DeserializeOwned>(deserializer: D) -> Result<Option<T>, D::Error> {
+    Ok(serde_json::from_value(Value::deserialize(deserializer)?).ok())
+}
+
+fn deserialize_aliased<'de, D: Deserializer<'de>, T: Aliased>(deserializer: D) -> Result<Option<T>, D::Error> {
+    Ok(T::from_json(Value::deserialize(deserializer)?).ok())
+}
+
+// A list that keeps the items it can read and skips the rest.
+fn deserialize_aliased_list<'de, D: Deserializer<'de>, T: Aliased>(deserializer: D) -> Result<Vec<T>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Array(items) => Vec::from_iter(items.into_iter().filter_map(|item| T::from_json(item).ok())),
+        _ => Vec::new(),
+    })
+}
+
+// The signed-in user, from users/me. Only object_id is always there; the rest depends
+// on the kind of account, and is left out where it doesn't parse.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UserProfile {
+    pub object_id: String,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub first_name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub last_name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub email: Option<String>,
+    // The students this user is a guardian of.
+    #[serde(default, deserialize_with = "deserialize_aliased_list")]
+    pub guardian_relationships: Vec<GuardianRelationship>,
+    #[serde(default, deserialize_with = "deserialize_aliased_list")]
+    pub schools: Vec<School>,
+    #[serde(default, deserialize_with = "deserialize_permissions")]
+    pub permissions: Vec<String>,
+}
+
+impl Aliased for UserProfile {
+    const ALIASES: &'static [(&'static str, &'static [&'static str])] = &[
+        ("guardian_relationships", &["relationships", "guardianships"]),
+    ];
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GuardianRelationship {
+    #[serde(default, deserialize_with = "deserialize_aliased")]
+    pub student: Option<StudentSummary>,
+    // e.g. "parent", "guardian", "family"
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub relationship: Option<String>,
+}
+
+impl Aliased for GuardianRelationship {
+    const ALIASES: &'static [(&'static str, &'static [&'static str])] = &[("relationship", &["relationship_type"])];
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StudentSummary {
+    pub object_id: String,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub first_name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub last_name: Option<String>,
+}
+
+impl Aliased for StudentSummary {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct School {
+    pub object_id: String,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub name: Option<String>,
+}
+
+impl Aliased for School {}
+
+impl UserProfile {
+    pub fn display_name(&self) -> String {
+        let name = Vec::from_iter(self.first_name.iter().chain(&self.last_name).map(String::as_str)).join(" ");
+        if name.is_empty() { self.email.clone().unwrap_or_else(|| self.object_id.clone()) } else { name }
+    }
+}
+
+// Permissions come either as a list of names or as a map of name to whether it's granted.
+fn deserialize_permissions<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Array(items) => Vec::from_iter(items.iter().filter_map(|item| item.as_str().map(String::from))),
+        Value::Object(obj) => Vec::from_iter(
+            obj.iter().filter(|(_, granted)| granted.as_bool() == Some(true)).map(|(name, _)| name.clone())
+        ),
+        _ => Vec::new(),
+    })
+}
+
+// A student from guardians/{id}/students. The school and rooms are as of now; rooms
+// change from year to year.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct Student {
     pub object_id: String,
     pub first_name: String,
     pub last_name: String,
+    #[serde(default, deserialize_with = "deserialize_aliased")]
+    pub school: Option<School>,
+    #[serde(default, deserialize_with = "deserialize_rooms")]
+    pub rooms: Vec<Room>,
+    // e.g. "active", "graduated", "withdrawn"
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub enrollment_status: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_photo_url")]
+    pub profile_photo_url: Option<String>,
+}
+
+impl Aliased for Student {
+    const ALIASES: &'static [(&'static str, &'static [&'static str])] = &[
+        ("rooms", &["room", "classroom", "classrooms"]),
+        ("enrollment_status", &["status"]),
+        ("profile_photo_url", &["profile_photo", "photo_url"]),
+    ];
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Room {
+    pub object_id: String,
+    #[serde(default, deserialize_with = "deserialize_lenient")]
+    pub name: Option<String>,
+}
+
+impl Aliased for Room {}
+
+// The photo comes either as a URL or as an object holding one, under any of the names
+// folded into profile_photo_url; anything else is no photo.
+fn deserialize_photo_url<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(url) if !url.is_empty() => Some(url),
+        Value::Object(obj) => ["url", "large", "original"].iter()
+            .find_map(|key| obj.get(*key).and_then(Value::as_str).filter(|url| !url.is_empty()))
+            .map(String::from),
+        _ => None,
+    })
+}
+
+// Rooms come either as one object or as a list of them.
+fn deserialize_rooms<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Room>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Array(items) => Vec::from_iter(items.into_iter().filter_map(|item| Room::from_json(item).ok())),
+        room @ Value::Object(_) => Vec::from_iter(Room::from_json(room).ok()),
+        _ => Vec::new(),
+    })
+}
+
+// Each entry in a guardians/{id}/students response holds the student under "student".
+fn parse_students(json: &Value) -> Result<Vec<Result<Student, String>>, String> {
+    let entries = json.get("students").and_then(Value::as_array).ok_or("no students in the response")?;
+    Ok(Vec::from_iter(entries.iter().enumerate().map(|(i, entry)| {
+        let student = entry.get("student").unwrap_or(entry);
+        Student::from_json(student.clone()).map_err(|e| {
+            match student.get("object_id").and_then(Value::as_str) {
+                Some(object_id) => format!("student {}: {}", object_id, e),
+                None => format!("student {} in the list: {}", i + 1, e),
+            }
+        })
+    })))
 }
 
 impl BrightwheelClient {
@@ -68,98 +535,216 @@ impl BrightwheelClient {
         }
     }
 
-    pub fn post_sessions_start(&self, email: &str, password: &str) -> Response {
+    // Starts a login; when MFA is on, this also sends a code (again), by mfa_method_opt
+    // if given.
+    pub async fn post_sessions_start(
+        &self, email: &str, password: &str, mfa_method_opt: Option<&str>,
+    ) -> Result<Value, LoginError> {
+        let mut json_val = Self::authentication_json(email, password, None);
+        if let Some(mfa_method) = mfa_method_opt {
+            json_val.as_object_mut().unwrap().insert("2fa_method".into(), mfa_method.into());
+        }
         let request = self.client.post(
             format!("{}/sessions/start", URL_BASE)
         )
             .headers(self.auth_headers.clone())
-            .json(&Self::authentication_json(email, password, None))
+            .json(&json_val)
             .build().unwrap();
-        self.client.execute(request).unwrap()
+        Self::login_json(self.client.execute(request).await, false).await
     }
 
-    pub fn post_sessions(&self, email: &str, password: &str, mfa_code_opt: Option<&str>) -> Response {
+    pub async fn post_sessions(&self, email: &str, password: &str, mfa_code_opt: Option<&str>) -> Result<Value, LoginError> {
         let request = self.client.post(
             format!("{}/sessions", URL_BASE)
         )
             .headers(self.auth_headers.clone())
             .json(&Self::authentication_json(email, password, mfa_code_opt))
             .build().unwrap();
-        self.client.execute(request).unwrap()
+        Self::login_json(self.client.execute(request).await, mfa_code_opt.is_some()).await
     }
 
-    pub fn get_users_me(&self) -> Response {
-        let request = self.client.get(format!("{}/users/me", URL_BASE)).build().unwrap();
-        self.client.execute(request).unwrap()
+    // The JSON object from a sessions response, or why the login was refused. Some
+    // failures come back as 200 with an error payload, so the body is checked too.
+    async fn login_json(response_result: reqwest::Result<Response>, mfa: bool) -> Result<Value, LoginError> {
+        let response = response_result.map_err(|e| LoginError::Other(redact_error(e)))?;
+        let status = response.status();
+        let body = response.json::<Value>().await.unwrap_or(Value::Null);
+        if !status.is_success() || has_error(&body) {
+            debug!("login refused ({}): {}", status, redact_json(&body));
+            Err(LoginError::from_response(status, &body, mfa))
+        }
+        else if !body.is_object() {
+            Err(LoginError::Other("unexpected response from brightwheel".into()))
+        }
+        else {
+            Ok(body)
+        }
     }
 
-    pub fn get_user_id(&self) -> String {
-        let response = self.get_users_me();
-        let json = response.json::<Value>().unwrap();
-        println!("users/me json: {:?}", json);
-        match json {
-            Value::Object(obj) => {
-                match obj.get("object_id").unwrap() {
-                    Value::String(user_id) => user_id.clone(),
-                    _ => panic!()
-                }
-            },
-            _ => panic!()
-        }
-    }
-
-    pub fn get_guardians_students(&self, user_id: &String) -> Response {
-        let request = self.client.get(format!("{}/guardians/{}/students", URL_BASE, user_id)).build().unwrap();
-        self.client.execute(request).unwrap()
-    }
-
-    pub fn get_students(&self, user_id: &String) -> Vec<Student> {
-        let response = self.get_guardians_students(user_id);
-        let json = response.json::<Value>().unwrap();
-        println!("guardians/{}/students json: {:?}", user_id, json);
-
-        Vec::from_iter(
-            match &json {
-                Value::Object(obj) => {
-                    match obj.get("students").unwrap() {
-                        Value::Array(arr) => {
-                            arr.iter().map(|item| {
-                                match item {
-                                    Value::Object(item_obj) => {
-                                        let student_val = item_obj.get("student").unwrap();
-                                        match student_val {
-                                            Value::Object(student_obj) => {
-                                                let object_id = student_obj.get("object_id").unwrap().as_str().unwrap().into();
-                                                let first_name = student_obj.get("first_name").unwrap().as_str().unwrap().into();
-                                                let last_name = student_obj.get("last_name").unwrap().as_str().unwrap().into();
-                                                Student {
-                                                    object_id,
-                                                    first_name,
-                                                    last_name,
-                                                }
-                                            },
-                                            _ => panic!()
-                                        }
-                                    },
-                                    _ => panic!()
-                                }
-                            })
-                        },
-                        _ => panic!()
+    // Checks that the session cookie actually works, returning the user's id.
+    pub async fn verify_session(&self) -> Result<String, LoginError> {
+        let response = self.client.get(format!("{}/users/me", URL_BASE)).send().await
+            .map_err(|e| LoginError::Other(redact_error(e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(LoginError::Other(format!("brightwheel did not accept the session (HTTP {})", status)));
+        }
+        let json = response.json::<Value>().await.map_err(|e| LoginError::Other(redact_error(e)))?;
+        json.get("object_id").and_then(Value::as_str).map(String::from)
+            .ok_or_else(|| LoginError::Other("brightwheel did not return the signed-in user".into()))
+    }
+
+    pub async fn get_profile(&self) -> Result<UserProfile, String> {
+        let response = self.client.get(format!("{}/users/me", URL_BASE)).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(redact_error)?;
+        let json = response.json::<Value>().await.map_err(|e| format!("users/me: {}", redact_error(e)))?;
+        let profile = UserProfile::from_json(json).map_err(|e| format!("users/me: {}", e))?;
+        debug!(
+            "users/me: {} ({} students, {} schools)",
+            profile.object_id, profile.guardian_relationships.len(), profile.schools.len()
+        );
+        Ok(profile)
+    }
+
+    pub async fn get_guardians_students(&self, user_id: &str) -> Result<Response, String> {
+        self.client.get(format!("{}/guardians/{}/students", URL_BASE, user_id)).send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(redact_error)
+    }
+
+    // The account's students, each one that couldn't be read as an error of its own.
+    pub async fn get_students(&self, user_id: &str) -> Result<Vec<Result<Student, String>>, String> {
+        let response = self.get_guardians_students(user_id).await?;
+        let json = response.json::<Value>().await
+            .map_err(|e| format!("guardians/{}/students: {}", user_id, redact_error(e)))?;
+        debug!("guardians/{}/students json: {}", user_id, redact_json(&json));
+        parse_students(&json).map_err(|msg| format!("guardians/{}/students: {}", user_id, msg))
+    }
+
+    pub async fn get_message_threads(&self, user_id: &str) -> Result<Vec<MessageThread>, String> {
+        self.get_paged(&format!("guardians/{}/message_threads", user_id), &["message_threads", "threads", "conversations"]).await
+    }
+
+    pub async fn get_thread_messages(&self, thread_id: &str) -> Result<Vec<Message>, String> {
+        self.get_paged(&format!("message_threads/{}/messages", thread_id), &["messages"]).await
+    }
+
+    pub async fn get_announcements(&self, user_id: &str) -> Result<Vec<Announcement>, String> {
+        self.get_paged(&format!("guardians/{}/announcements", user_id), &["announcements"]).await
+    }
+
+    // Every item of a paged list endpoint, found under the first of keys present in each
+    // page. Items that don't parse are skipped. Stops at a short page, or at a page with
+    // nothing new in case the server ignores the page parameter.
+    async fn get_paged<T: Aliased>(&self, path: &str, keys: &[&str]) -> Result<Vec<T>, String> {
+        let mut items = Vec::new();
+        let mut seen_ids = HashSet::new();
+        for page in 0..MAX_MESSAGES_PAGES {
+            let json = self.client.get(format!("{}/{}", URL_BASE, path))
+                .query(&[("page", page.to_string()), ("page_size", MESSAGES_PAGE_SIZE.to_string())])
+                .send().await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| format!("{}: {}", path, redact_error(e)))?
+                .json::<Value>().await
+                .map_err(|e| format!("{}: {}", path, redact_error(e)))?;
+            let page_items = keys.iter()
+                .find_map(|key| json.get(*key).and_then(Value::as_array))
+                .cloned()
+                .unwrap_or_default();
+            debug!("{} page {}: {} items", path, page, page_items.len());
+
+            let page_len = page_items.len();
+            let mut new_items = 0;
+            for item in page_items {
+                if let Some(object_id) = item.get("object_id").and_then(Value::as_str) {
+                    if !seen_ids.insert(object_id.to_string()) {
+                        continue;
                     }
-                },
-                _ => panic!()
+                }
+                new_items += 1;
+                match T::from_json(item) {
+                    Ok(parsed) => items.push(parsed),
+                    Err(e) => warn!("{}: skipping an item: {}", path, e),
+                }
             }
-        )
+            if page_len < MESSAGES_PAGE_SIZE || new_items == 0 {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    pub async fn get_daily_report(&self, student_id: &str, date: Date) -> Result<Option<Value>, String> {
+        self.get_record(&format!("students/{}/daily_reports/{}", student_id, date)).await
+    }
+
+    pub async fn get_observation(&self, student_id: &str, observation_id: &str) -> Result<Option<Value>, String> {
+        self.get_record(&format!("students/{}/observations/{}", student_id, observation_id)).await
     }
 
-    pub fn get_students_activities(&self, student_id: &String, page_size: usize, page: usize) -> Response {
+    // The JSON at path, or None if there is no such record.
+    async fn get_record(&self, path: &str) -> Result<Option<Value>, String> {
+        let response = self.client.get(format!("{}/{}", URL_BASE, path)).send().await
+            .map_err(|e| format!("{}: {}", path, redact_error(e)))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            debug!("{}: not found", path);
+            return Ok(None);
+        }
+        let json = response.error_for_status()
+            .map_err(|e| format!("{}: {}", path, redact_error(e)))?
+            .json::<Value>().await
+            .map_err(|e| format!("{}: {}", path, redact_error(e)))?;
+        debug!("{}: {}", path, redact_json(&json));
+        Ok(Some(json))
+    }
+
+    pub async fn get_students_activities(
+        &self, student_id: &String, page_size: usize, page: usize, cursor_opt: Option<&str>, range: &DateRange,
+    ) -> Result<Response, String> {
+        let mut query = vec![("page_size", page_size.to_string()), ("page", page.to_string())];
+        if let Some(cursor) = cursor_opt {
+            query.push(("cursor", cursor.to_string()));
+        }
+        if let Some(since) = range.since {
+            query.push(("start_date", since.to_string()));
+        }
+        if let Some(until) = range.until {
+            query.push(("end_date", until.to_string()));
+        }
         let request = self.client.get(
             format!("{}/students/{}/activities", URL_BASE, student_id)
         ).query(
-            &[("page_size", page_size), ("page", page)]
+            &query
         ).build().unwrap();
-        self.client.execute(request).unwrap()
+        self.client.execute(request).await
+            .and_then(|response| response.error_for_status())
+            .map_err(redact_error)
+    }
+
+    // Size of the file at src_url from a HEAD request, if the server reports one.
+    pub async fn head_content_length(&self, src_url: &reqwest::Url) -> Result<Option<u64>, String> {
+        let response = self.client.head(src_url.clone())
+            .timeout(Duration::from_secs(30))
+            .send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(redact_error)?;
+        Ok(
+            response.headers().get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+        )
+    }
+
+    pub async fn get_download(&self, src_url: &reqwest::Url) -> Result<Response, String> {
+        let request = self.client.get(
+            src_url.clone()
+        ).timeout(
+            Duration::from_secs(100)
+        ).build().map_err(redact_error)?;
+        self.client.execute(request).await
+            .and_then(|response| response.error_for_status())
+            .map_err(redact_error)
     }
 
     fn authentication_json(email: &str, password: &str, mfa_code_opt: Option<&str>) -> Value {
@@ -175,15 +760,915 @@ impl BrightwheelClient {
         }
         json_val
     }
+}
 
-    pub fn download_file(&self, src_url: &reqwest::Url, dst_path: &PathBuf) {
-        let request = self.client.get(
-            src_url.clone()
-        ).timeout(
-            Duration::from_secs(100)
-        ).build().unwrap();
-        let mut file: std::fs::File = std::fs::File::create(dst_path).unwrap();
-        let mut response = self.client.execute(request).unwrap();
-        response.copy_to(&mut file).unwrap();
+// The parts of the brightwheel API the sync engine uses, so it can run against a fake.
+// Shared with the sync's download workers, hence Sync.
+pub trait BrightwheelApi: Sync {
+    fn profile(&self) -> Result<UserProfile, String>;
+
+    // Students that couldn't be read come back as errors, so the rest can still sync.
+    fn students(&self, user_id: &str) -> Result<Vec<Result<Student, String>>, String>;
+
+    // The body of one page of a student's activity feed, newest first. cursor_opt is the
+    // cursor from the previous page, for servers that hand them out.
+    fn activities_page(
+        &self, student_id: &str, page_size: usize, page: usize, cursor_opt: Option<&str>, range: &DateRange,
+    ) -> Result<Box<dyn Read + Send>, String>;
+
+    fn message_threads(&self, user_id: &str) -> Result<Vec<MessageThread>, String>;
+
+    // Every message in a thread, in no particular order.
+    fn messages(&self, thread_id: &str) -> Result<Vec<Message>, String>;
+
+    fn announcements(&self, user_id: &str) -> Result<Vec<Announcement>, String>;
+
+    // The student's daily report for date, if there is one.
+    fn daily_report(&self, student_id: &str, date: Date) -> Result<Option<Value>, String>;
+
+    // The observation behind an activity, if there is one.
+    fn observation(&self, student_id: &str, activity_id: &str) -> Result<Option<Value>, String>;
+
+    // Size of the file at src_url from a HEAD request, if the server reports one.
+    fn content_length(&self, src_url: &reqwest::Url) -> Result<Option<u64>, String>;
+
+    fn open_download(&self, src_url: &reqwest::Url) -> Result<Box<dyn Read>, String>;
+
+    // Walks a student's activity feed lazily, fetching pages as they are needed.
+    fn activities(&self, student_id: &str, range: DateRange, page_size: usize) -> Activities<'_, Self> where Self: Sized {
+        Activities {
+            api: self,
+            student_id: student_id.into(),
+            range,
+            page_size,
+            page: 0,
+            cursor_opt: None,
+            receiver_opt: None,
+            page_span: Span::none(),
+            new_items: 0,
+            seen_ids: HashSet::new(),
+            total_opt: None,
+            done: false,
+        }
+    }
+}
+
+// Iterator over a student's activities. Each page body is parsed on its own thread as
+// it arrives, one activity at a time, and handed over through a small channel, so
+// memory stays flat however large the page and the first downloads can start while
+// the rest of the page is still coming in.
+//
+// Rather than trusting page lengths (servers clamp page_size), it stops at an empty
+// page, once a reported total is reached, when a cursor-based feed runs out of cursors,
+// when a page holds nothing new (a server ignoring the page parameter), or after
+// MAX_ACTIVITY_PAGES. Activities seen on an earlier page are skipped, since new posts
+// shift the feed while it is being walked.
+pub struct Activities<'a, A: BrightwheelApi> {
+    api: &'a A,
+    student_id: String,
+    range: DateRange,
+    page_size: usize,
+    page: usize,
+    cursor_opt: Option<String>,
+    // Events from the parser thread for the page being read.
+    receiver_opt: Option<Receiver<PageEvent>>,
+    // Covers fetching, parsing and summing up the current page.
+    page_span: Span,
+    // Activities on the current page not seen on an earlier one.
+    new_items: usize,
+    seen_ids: HashSet<String>,
+    total_opt: Option<usize>,
+    done: bool,
+}
+
+enum PageEvent {
+    Activity(Result<Activity, String>),
+    // Sent once the whole body has been read.
+    End(Result<PageSummary, String>),
+}
+
+#[derive(Default)]
+struct PageSummary {
+    activities: usize,
+    total_opt: Option<usize>,
+    next_cursor_opt: Option<String>,
+}
+
+impl<A: BrightwheelApi> Activities<'_, A> {
+    fn start_page(&mut self) -> Result<(), String> {
+        if self.page >= MAX_ACTIVITY_PAGES {
+            warn!("gave up on the activity feed after {} pages", self.page);
+            self.done = true;
+            return Ok(());
+        }
+        let page = self.page;
+        self.page_span = debug_span!("page", page);
+        let _span = self.page_span.enter();
+        let reader = self.api.activities_page(
+            &self.student_id, self.page_size, page, self.cursor_opt.as_deref(), &self.range
+        ).map_err(|msg| format!("page {}: {}", page, msg))?;
+
+        let (sender, receiver) = sync_channel(PAGE_CHANNEL_CAPACITY);
+        let span = self.page_span.clone();
+        thread::spawn(move || {
+            let _span = span.entered();
+            let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+            let result = deserializer.deserialize_map(PageVisitor { sender: &sender, page })
+                .map_err(|e| format!("page {}: {}", page, e));
+            // The iterator may already be gone if the sync stopped early.
+            let _ = sender.send(PageEvent::End(result));
+        });
+        self.receiver_opt = Some(receiver);
+        self.new_items = 0;
+        Ok(())
+    }
+
+    fn finish_page(&mut self, summary: PageSummary) {
+        let _span = self.page_span.clone().entered();
+        let page = self.page;
+        self.page += 1;
+        debug!("page {}: {} activities, {} new", page, summary.activities, self.new_items);
+        if summary.activities == 0 {
+            self.done = true;
+            return;
+        }
+        if self.new_items == 0 {
+            warn!("page {} only repeats earlier activities; stopping", page);
+            self.done = true;
+        }
+
+        if self.total_opt.is_none() {
+            self.total_opt = summary.total_opt;
+        }
+        if self.total_opt.is_some_and(|total| self.seen_ids.len() >= total) {
+            self.done = true;
+        }
+
+        if self.cursor_opt.is_some() || summary.next_cursor_opt.is_some() {
+            match summary.next_cursor_opt {
+                Some(next_cursor) if self.cursor_opt.as_ref() != Some(&next_cursor) => {
+                    self.cursor_opt = Some(next_cursor);
+                },
+                _ => self.done = true,
+            }
+        }
+    }
+}
+
+impl<A: BrightwheelApi> Iterator for Activities<'_, A> {
+    type Item = Result<Activity, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(receiver) = &self.receiver_opt {
+                match receiver.recv() {
+                    Ok(PageEvent::Activity(Ok(activity))) => {
+                        if self.seen_ids.insert(activity.object_id.clone()) {
+                            self.new_items += 1;
+                            return Some(Ok(activity));
+                        }
+                    },
+                    Ok(PageEvent::Activity(Err(msg))) => {
+                        self.new_items += 1;
+                        return Some(Err(msg));
+                    },
+                    Ok(PageEvent::End(Ok(summary))) => {
+                        self.receiver_opt = None;
+                        self.finish_page(summary);
+                    },
+                    Ok(PageEvent::End(Err(msg))) => {
+                        self.receiver_opt = None;
+                        self.done = true;
+                        return Some(Err(msg));
+                    },
+                    Err(_) => {
+                        self.receiver_opt = None;
+                        self.done = true;
+                        return Some(Err(format!("page {}: parser stopped", self.page)));
+                    },
+                }
+                continue;
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(msg) = self.start_page() {
+                self.done = true;
+                return Some(Err(msg));
+            }
+        }
+    }
+}
+
+// Reads the top level of an activities response, passing each activity on as soon as
+// it has been parsed and keeping only the paging fields.
+struct PageVisitor<'s> {
+    sender: &'s SyncSender<PageEvent>,
+    page: usize,
+}
+
+impl<'de> Visitor<'de> for PageVisitor<'_> {
+    type Value = PageSummary;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an activities response object")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<PageSummary, M::Error> {
+        let mut summary = PageSummary::default();
+        let mut saw_activities = false;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "activities" {
+                saw_activities = true;
+                summary.activities = map.next_value_seed(ActivitiesSeed { sender: self.sender, page: self.page })?;
+            }
+            else if TOTAL_KEYS.contains(&key.as_str()) {
+                if let Some(total) = map.next_value::<Value>()?.as_u64() {
+                    summary.total_opt = Some(total as usize);
+                }
+            }
+            else if CURSOR_KEYS.contains(&key.as_str()) {
+                summary.next_cursor_opt = map.next_value::<Value>()?.as_str()
+                    .filter(|cursor| !cursor.is_empty())
+                    .map(String::from);
+            }
+            else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        if !saw_activities {
+            return Err(de::Error::custom("no activities in response"));
+        }
+        Ok(summary)
+    }
+}
+
+struct ActivitiesSeed<'s> {
+    sender: &'s SyncSender<PageEvent>,
+    page: usize,
+}
+
+impl<'de> DeserializeSeed<'de> for ActivitiesSeed<'_> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ActivitiesSeed<'_> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of activities")
+    }
+
+    // Each activity is first read whole as raw JSON, so one that doesn't match Activity
+    // is reported on its own instead of ending the page.
+    fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<usize, S::Error> {
+        let mut count = 0;
+        while let Some(raw) = seq.next_element::<Box<RawValue>>()? {
+            count += 1;
+            let activity_result = serde_json::from_str(raw.get())
+                .and_then(Activity::from_json)
+                .map_err(|e| format!("page {}: could not read activity: {}", self.page, e));
+            if self.sender.send(PageEvent::Activity(activity_result)).is_err() {
+                return Err(de::Error::custom("stopped reading"));
+            }
+        }
+        Ok(count)
+    }
+}
+
+// Blocking view of a BrightwheelClient for the sync engine, which runs on its own
+// threads. Requests run on the tokio runtime, and response bodies are pulled by a task
+// on the runtime and handed over chunk by chunk, so they can be read with std::io.
+pub struct BlockingBrightwheelClient {
+    client: Arc<BrightwheelClient>,
+    runtime: Handle,
+}
+
+impl BlockingBrightwheelClient {
+    // Must not be used from inside the runtime's own tasks.
+    pub fn new(client: Arc<BrightwheelClient>, runtime: Handle) -> Self {
+        Self { client, runtime }
+    }
+
+    fn stream_body(&self, response: Response) -> BodyReader {
+        let (sender, receiver) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+        self.runtime.spawn(async move {
+            let mut response = response;
+            loop {
+                let chunk_result = match response.chunk().await {
+                    Ok(Some(chunk)) => Ok(chunk),
+                    Ok(None) => break,
+                    Err(e) => Err(redact_error(e)),
+                };
+                let failed = chunk_result.is_err();
+                // A closed channel means the reader was dropped; stop pulling the body.
+                if sender.send(chunk_result).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        BodyReader { receiver, chunk: Bytes::new() }
+    }
+}
+
+// Reads a response body streamed from a task on the runtime.
+struct BodyReader {
+    receiver: mpsc::Receiver<Result<Bytes, String>>,
+    chunk: Bytes,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(msg)) => return Err(io::Error::other(msg)),
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+impl BrightwheelApi for BlockingBrightwheelClient {
+    fn profile(&self) -> Result<UserProfile, String> {
+        self.runtime.block_on(self.client.get_profile())
+    }
+
+    fn students(&self, user_id: &str) -> Result<Vec<Result<Student, String>>, String> {
+        self.runtime.block_on(self.client.get_students(user_id))
+    }
+
+    fn activities_page(
+        &self, student_id: &str, page_size: usize, page: usize, cursor_opt: Option<&str>, range: &DateRange,
+    ) -> Result<Box<dyn Read + Send>, String> {
+        let response = self.runtime.block_on(
+            self.client.get_students_activities(&student_id.to_string(), page_size, page, cursor_opt, range)
+        )?;
+        Ok(Box::new(self.stream_body(response)))
+    }
+
+    fn message_threads(&self, user_id: &str) -> Result<Vec<MessageThread>, String> {
+        self.runtime.block_on(self.client.get_message_threads(user_id))
+    }
+
+    fn messages(&self, thread_id: &str) -> Result<Vec<Message>, String> {
+        self.runtime.block_on(self.client.get_thread_messages(thread_id))
+    }
+
+    fn announcements(&self, user_id: &str) -> Result<Vec<Announcement>, String> {
+        self.runtime.block_on(self.client.get_announcements(user_id))
+    }
+
+    fn daily_report(&self, student_id: &str, date: Date) -> Result<Option<Value>, String> {
+        self.runtime.block_on(self.client.get_daily_report(student_id, date))
+    }
+
+    fn observation(&self, student_id: &str, activity_id: &str) -> Result<Option<Value>, String> {
+        self.runtime.block_on(self.client.get_observation(student_id, activity_id))
+    }
+
+    fn content_length(&self, src_url: &reqwest::Url) -> Result<Option<u64>, String> {
+        self.runtime.block_on(self.client.head_content_length(src_url))
+    }
+
+    fn open_download(&self, src_url: &reqwest::Url) -> Result<Box<dyn Read>, String> {
+        let response = self.runtime.block_on(self.client.get_download(src_url))?;
+        Ok(Box::new(self.stream_body(response)))
+    }
+}
+
+// Serves one activity page body from (page_size, page, cursor).
+#[cfg(test)]
+type PageFn = Box<dyn Fn(usize, usize, Option<&str>) -> Box<dyn Read + Send> + Send + Sync>;
+
+// The one fake BrightwheelApi for tests: serves a fixed set of students, activity feeds,
+// messages, records and media files, and records what was asked of it.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockApi {
+    pub students: Vec<(String, String, String)>,
+    // Listed after the students, as students that couldn't be read.
+    pub student_errors: Vec<String>,
+    pub activities: HashMap<String, Vec<Value>>,
+    // Serves activity page bodies by (page_size, page, cursor) in place of activities.
+    pub pages: Option<PageFn>,
+    pub files: HashMap<String, Vec<u8>>,
+    pub downloads: Mutex<Vec<String>>,
+    // (page, cursor) of every activity page requested.
+    pub page_requests: Mutex<Vec<(usize, Option<String>)>>,
+    // Profile photo URLs by student object_id; changeable between syncs.
+    pub profile_photos: Mutex<HashMap<String, String>>,
+    pub threads: Vec<(Value, Vec<Value>)>,
+    pub announcements: Vec<Value>,
+    pub daily_reports: HashMap<(String, Date), Value>,
+    pub observations: HashMap<String, Value>,
+    pub records_fetched: Mutex<usize>,
+    // Holds each download open this long, to see how many overlap.
+    pub download_delay: Duration,
+    // Downloads open right now, and the most ever open at once.
+    pub open_downloads: Mutex<(usize, usize)>,
+}
+
+#[cfg(test)]
+impl MockApi {
+    pub fn with_pages<F>(mut self, pages: F) -> MockApi
+    where F: Fn(usize, usize, Option<&str>) -> Box<dyn Read + Send> + Send + Sync + 'static {
+        self.pages = Some(Box::new(pages));
+        self
+    }
+
+    pub fn with_student(mut self, object_id: &str, first_name: &str, last_name: &str, activities: Vec<Value>) -> MockApi {
+        self.students.push((object_id.into(), first_name.into(), last_name.into()));
+        self.activities.insert(object_id.into(), activities);
+        self
+    }
+
+    pub fn with_file(mut self, url: &str, bytes: &[u8]) -> MockApi {
+        self.files.insert(url.into(), bytes.to_vec());
+        self
+    }
+
+    pub fn with_thread(mut self, thread: Value, messages: Vec<Value>) -> MockApi {
+        self.threads.push((thread, messages));
+        self
+    }
+
+    pub fn with_announcement(mut self, announcement: Value) -> MockApi {
+        self.announcements.push(announcement);
+        self
+    }
+}
+
+#[cfg(test)]
+impl BrightwheelApi for MockApi {
+    fn profile(&self) -> Result<UserProfile, String> {
+        Ok(serde_json::from_value(json!({ "object_id": "guardian-1", "first_name": "Grace" })).unwrap())
+    }
+
+    fn students(&self, _user_id: &str) -> Result<Vec<Result<Student, String>>, String> {
+        let students = self.students.iter().map(|(object_id, first_name, last_name)| Ok(Student {
+            object_id: object_id.clone(),
+            first_name: first_name.clone(),
+            last_name: last_name.clone(),
+            rooms: vec![Room { object_id: format!("room-{}", object_id), name: Some("Toddlers".into()) }],
+            profile_photo_url: self.profile_photos.lock().unwrap().get(object_id).cloned(),
+            ..Default::default()
+        }));
+        Ok(Vec::from_iter(students.chain(self.student_errors.iter().map(|msg| Err(msg.clone())))))
+    }
+
+    // Ignores the range, like a server without date filters.
+    fn activities_page(
+        &self, student_id: &str, page_size: usize, page: usize, cursor_opt: Option<&str>, _range: &DateRange,
+    ) -> Result<Box<dyn Read + Send>, String> {
+        self.page_requests.lock().unwrap().push((page, cursor_opt.map(String::from)));
+        if let Some(pages) = &self.pages {
+            return Ok(pages(page_size, page, cursor_opt));
+        }
+        let all = &self.activities[student_id];
+        let start = (page * page_size).min(all.len());
+        let end = (start + page_size).min(all.len());
+        let body = json!({ "page": page, "page_size": page_size, "activities": all[start..end] });
+        Ok(Box::new(io::Cursor::new(serde_json::to_vec(&body).unwrap())))
+    }
+
+    fn message_threads(&self, _user_id: &str) -> Result<Vec<MessageThread>, String> {
+        Ok(Vec::from_iter(self.threads.iter().map(|(thread, _)| MessageThread::from_json(thread.clone()).unwrap())))
+    }
+
+    fn messages(&self, thread_id: &str) -> Result<Vec<Message>, String> {
+        let (_, messages) = self.threads.iter().find(|(thread, _)| thread["object_id"] == thread_id).unwrap();
+        Ok(Vec::from_iter(messages.iter().map(|message| Message::from_json(message.clone()).unwrap())))
+    }
+
+    fn announcements(&self, _user_id: &str) -> Result<Vec<Announcement>, String> {
+        Ok(Vec::from_iter(self.announcements.iter().map(|announcement| Announcement::from_json(announcement.clone()).unwrap())))
+    }
+
+    fn daily_report(&self, student_id: &str, date: Date) -> Result<Option<Value>, String> {
+        *self.records_fetched.lock().unwrap() += 1;
+        Ok(self.daily_reports.get(&(student_id.to_string(), date)).cloned())
+    }
+
+    fn observation(&self, _student_id: &str, activity_id: &str) -> Result<Option<Value>, String> {
+        *self.records_fetched.lock().unwrap() += 1;
+        Ok(self.observations.get(activity_id).cloned())
+    }
+
+    fn content_length(&self, src_url: &reqwest::Url) -> Result<Option<u64>, String> {
+        Ok(self.files.get(src_url.as_str()).map(|bytes| bytes.len() as u64))
+    }
+
+    fn open_download(&self, src_url: &reqwest::Url) -> Result<Box<dyn Read>, String> {
+        self.downloads.lock().unwrap().push(src_url.to_string());
+        {
+            let mut open_downloads = self.open_downloads.lock().unwrap();
+            open_downloads.0 += 1;
+            open_downloads.1 = open_downloads.1.max(open_downloads.0);
+        }
+        thread::sleep(self.download_delay);
+        self.open_downloads.lock().unwrap().0 -= 1;
+        match self.files.get(src_url.as_str()) {
+            Some(bytes) => Ok(Box::new(io::Cursor::new(bytes.clone()))),
+            None => Err("HTTP status client error (404 Not Found)".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::atomic::{AtomicUsize, Ordering}};
+
+    use super::*;
+
+    // An activity feed served by a closure of (page_size, page, cursor).
+    fn feed(pages: impl Fn(usize, usize, Option<&str>) -> Value + Send + Sync + 'static) -> MockApi {
+        MockApi::default().with_pages(move |page_size, page, cursor| {
+            Box::new(Cursor::new(serde_json::to_vec(&pages(page_size, page, cursor)).unwrap()))
+        })
+    }
+
+    fn activity(n: usize) -> Value {
+        json!({ "object_id": format!("a{}", n), "created_at": "2024-05-17T09:30:12Z", "media": null })
+    }
+
+    fn ids<A: BrightwheelApi>(activities: Activities<A>) -> Vec<String> {
+        Vec::from_iter(activities.map(|activity| activity.unwrap().object_id))
+    }
+
+    #[test]
+    fn clamped_page_size_still_walks_everything() {
+        // 250 activities, but the server never returns more than 100 at a time.
+        let api = feed(|_page_size, page, _cursor| {
+            let start = (page * 100).min(250);
+            let end = (start + 100).min(250);
+            json!({ "page": page, "page_size": 100, "activities": Vec::from_iter((start..end).map(activity)) })
+        });
+        assert_eq!(ids(api.activities("s1", DateRange::default(), 1000)).len(), 250);
+        assert_eq!(api.page_requests.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn stops_when_the_server_ignores_the_page_number() {
+        let api = feed(|_page_size, _page, _cursor| json!({ "activities": [activity(0), activity(1)] }));
+        assert_eq!(ids(api.activities("s1", DateRange::default(), 2)), vec!["a0", "a1"]);
+        assert_eq!(api.page_requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn skips_activities_repeated_across_pages() {
+        // A new post arrives between the two pages and shifts a1 onto page 1.
+        let api = feed(|_page_size, page, _cursor| match page {
+            0 => json!({ "activities": [activity(0), activity(1)] }),
+            1 => json!({ "activities": [activity(1), activity(2)] }),
+            _ => json!({ "activities": [] }),
+        });
+        assert_eq!(ids(api.activities("s1", DateRange::default(), 2)), vec!["a0", "a1", "a2"]);
+    }
+
+    #[test]
+    fn stops_at_the_reported_total() {
+        let api = feed(|_page_size, page, _cursor| {
+            json!({ "total_count": 3, "activities": [activity(page * 2), activity(page * 2 + 1)] })
+        });
+        assert_eq!(ids(api.activities("s1", DateRange::default(), 2)).len(), 4);
+        assert_eq!(api.page_requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn follows_cursors() {
+        let api = feed(|_page_size, _page, cursor| match cursor {
+            None => json!({ "activities": [activity(0)], "next_cursor": "c1" }),
+            Some("c1") => json!({ "activities": [activity(1)], "next_cursor": "c2" }),
+            _ => json!({ "activities": [activity(2)], "next_cursor": null }),
+        });
+        assert_eq!(ids(api.activities("s1", DateRange::default(), 1)), vec!["a0", "a1", "a2"]);
+        assert_eq!(
+            Vec::from_iter(api.page_requests.lock().unwrap().iter().map(|(_, cursor)| cursor.clone())),
+            vec![None, Some("c1".into()), Some("c2".into())]
+        );
+    }
+
+    #[test]
+    fn bad_activities_are_reported_without_ending_the_page() {
+        let api = feed(|_page_size, page, _cursor| match page {
+            0 => json!({ "activities": [activity(0), { "object_id": "broken" }, activity(1)] }),
+            _ => json!({ "activities": [] }),
+        });
+        let results = Vec::from_iter(api.activities("s1", DateRange::default(), 10));
+        assert_eq!(results.len(), 3);
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap().object_id, "a1");
+    }
+
+    #[test]
+    fn is_lazy() {
+        let api = feed(|_page_size, page, _cursor| json!({ "activities": [activity(page)] }));
+        let first = api.activities("s1", DateRange::default(), 1).next().unwrap().unwrap();
+        assert_eq!(first.object_id, "a0");
+        assert_eq!(api.page_requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn paging_fields_may_follow_the_activities() {
+        let api = feed(|_page_size, page, _cursor| {
+            json!({ "activities": [activity(page * 2), activity(page * 2 + 1)], "total_count": 3 })
+        });
+        assert_eq!(ids(api.activities("s1", DateRange::default(), 2)).len(), 4);
+        assert_eq!(api.page_requests.lock().unwrap().len(), 2);
+    }
+
+    // Serves a fixed body and counts how much of it has been read.
+    struct CountingReader {
+        body: Cursor<Vec<u8>>,
+        read: Arc<AtomicUsize>,
+    }
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.body.read(buf)?;
+            self.read.fetch_add(n, Ordering::SeqCst);
+            Ok(n)
+        }
+    }
+
+    // Serves body as the first page, counting how much of it has been read.
+    fn body_feed(body: Vec<u8>, read: Arc<AtomicUsize>) -> MockApi {
+        MockApi::default().with_pages(move |_page_size, page, _cursor| {
+            let body = if page == 0 { body.clone() } else { br#"{"activities": []}"#.to_vec() };
+            Box::new(CountingReader { body: Cursor::new(body), read: read.clone() })
+        })
+    }
+
+    #[test]
+    fn activities_arrive_before_the_page_is_read() {
+        // 200 activities carrying 20 KB of video_info each.
+        let padding = "x".repeat(20_000);
+        let activities = Vec::from_iter((0..200).map(|n| json!({
+            "object_id": format!("a{}", n),
+            "created_at": "2024-05-17T09:30:12Z",
+            "video_info": { "downloadable_url": "https://cdn.example.com/v.mp4", "thumbnails": padding },
+        })));
+        let body = serde_json::to_vec(&json!({ "activities": activities })).unwrap();
+        let body_len = body.len();
+        let read = Arc::new(AtomicUsize::new(0));
+        let api = body_feed(body, read.clone());
+
+        let mut activities = api.activities("s1", DateRange::default(), 1000);
+        assert_eq!(activities.next().unwrap().unwrap().object_id, "a0");
+        assert!(read.load(Ordering::SeqCst) < body_len / 4);
+        assert_eq!(activities.count(), 199);
+    }
+
+    #[test]
+    fn a_truncated_page_keeps_what_was_read() {
+        let mut body = serde_json::to_vec(&json!({ "activities": [activity(0), activity(1)] })).unwrap();
+        body.truncate(body.len() - 2);
+        let api = body_feed(body, Arc::new(AtomicUsize::new(0)));
+
+        let results = Vec::from_iter(api.activities("s1", DateRange::default(), 1000));
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[1].as_ref().unwrap().object_id, "a1");
+        assert!(results[2].is_err());
+    }
+
+    #[test]
+    fn body_reader_joins_chunks_and_reports_errors() {
+        let (sender, receiver) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+        for chunk in ["{\"activ", "", "ities\": []}"] {
+            sender.blocking_send(Ok(Bytes::from(chunk))).unwrap();
+        }
+        drop(sender);
+        let mut body = String::new();
+        BodyReader { receiver, chunk: Bytes::new() }.read_to_string(&mut body).unwrap();
+        assert_eq!(body, r#"{"activities": []}"#);
+
+        let (sender, receiver) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+        sender.blocking_send(Ok(Bytes::from("{"))).unwrap();
+        sender.blocking_send(Err("connection reset".into())).unwrap();
+        let error = BodyReader { receiver, chunk: Bytes::new() }.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(), "connection reset");
+    }
+
+    #[test]
+    fn download_errors_leave_out_url_signatures() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let client = BrightwheelClient::new(reqwest_cookie_store::CookieStore::default());
+        // Nothing listens on port 1, so the request fails with the URL in reqwest's error.
+        let url = reqwest::Url::parse("http://127.0.0.1:1/media/photo.jpg?X-Amz-Signature=s3cr3t").unwrap();
+
+        let error = runtime.block_on(client.get_download(&url)).unwrap_err();
+        assert!(!error.contains("s3cr3t"), "{}", error);
+        assert!(error.contains("http://127.0.0.1:1/media/photo.jpg"), "{}", error);
+
+        let error = runtime.block_on(client.head_content_length(&url)).unwrap_err();
+        assert!(!error.contains("s3cr3t"), "{}", error);
+    }
+
+    #[test]
+    fn login_errors_are_classified() {
+        let cases = [
+            (StatusCode::UNAUTHORIZED, json!({ "error": "Invalid email or password." }), false, LoginError::BadCredentials),
+            (StatusCode::UNAUTHORIZED, json!({}), false, LoginError::BadCredentials),
+            (StatusCode::UNAUTHORIZED, json!({}), true, LoginError::InvalidMfaCode),
+            (StatusCode::UNPROCESSABLE_ENTITY, json!({ "errors": { "2fa_code": ["is invalid"] } }), true, LoginError::InvalidMfaCode),
+            (StatusCode::UNAUTHORIZED, json!({ "error": "Code has expired" }), true, LoginError::MfaCodeExpired),
+            (StatusCode::UNAUTHORIZED, json!({ "error": "Your account is locked." }), false, LoginError::AccountLocked),
+            (StatusCode::LOCKED, json!({}), false, LoginError::AccountLocked),
+            (StatusCode::TOO_MANY_REQUESTS, json!({}), true, LoginError::TooManyAttempts),
+            (StatusCode::OK, json!({ "message": "Too many failed attempts" }), false, LoginError::TooManyAttempts),
+            (StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": "oops" }), false, LoginError::Other("oops".into())),
+            (StatusCode::BAD_GATEWAY, Value::Null, false, LoginError::Other("HTTP 502 Bad Gateway".into())),
+        ];
+        for (status, body, mfa, expected) in cases {
+            assert_eq!(LoginError::from_response(status, &body, mfa), expected, "{} {}", status, body);
+        }
+    }
+
+    #[test]
+    fn login_responses_only_count_errors_with_something_in_them() {
+        let cases = [
+            (json!({ "object_id": "u1" }), false),
+            (json!({ "object_id": "u1", "error": null }), false),
+            (json!({ "object_id": "u1", "errors": null }), false),
+            (json!({ "object_id": "u1", "error": "" }), false),
+            (json!({ "object_id": "u1", "errors": [] }), false),
+            (json!({ "object_id": "u1", "errors": {} }), false),
+            (json!({ "object_id": "u1", "error": false }), false),
+            (json!({ "error": "Invalid email or password." }), true),
+            (json!({ "errors": ["is invalid"] }), true),
+            (json!({ "errors": { "2fa_code": ["is invalid"] } }), true),
+            (json!({ "error": true }), true),
+            (json!({ "error": 401 }), true),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(has_error(&body), expected, "{}", body);
+        }
+    }
+
+    #[test]
+    fn profile_reads_whatever_users_me_includes() {
+        let profile = UserProfile::from_json(json!({
+            "object_id": "u1",
+            "first_name": "Grace",
+            "last_name": "Hopper",
+            "email": "grace@example.com",
+            "relationships": [
+                { "student": { "object_id": "s1", "first_name": "Ada" }, "relationship_type": "parent" },
+            ],
+            "schools": [{ "object_id": "sc1", "name": "Little Sprouts" }],
+            "permissions": { "can_message": true, "can_check_in": false },
+            "user_type": "guardian",
+        })).unwrap();
+        assert_eq!(profile.display_name(), "Grace Hopper");
+        assert_eq!(profile.guardian_relationships[0].student.as_ref().unwrap().object_id, "s1");
+        assert_eq!(profile.guardian_relationships[0].relationship.as_deref(), Some("parent"));
+        assert_eq!(profile.schools[0].name.as_deref(), Some("Little Sprouts"));
+        assert_eq!(profile.permissions, ["can_message"]);
+
+        let profile = UserProfile::from_json(json!({ "object_id": "u2", "permissions": ["a"] })).unwrap();
+        assert_eq!(profile.display_name(), "u2");
+        assert_eq!(profile.permissions, ["a"]);
+    }
+
+    #[test]
+    fn profile_survives_odd_fields_and_repeated_names() {
+        let profile = UserProfile::from_json(json!({
+            "object_id": "u1",
+            "first_name": null,
+            "last_name": 7,
+            "email": { "address": "grace@example.com" },
+            "relationships": null,
+            "guardianships": [
+                { "student": { "object_id": "s1" }, "relationship": "parent", "relationship_type": "guardian" },
+                { "student": { "first_name": "no id" }, "relationship_type": ["parent"] },
+                "not a relationship",
+            ],
+            "schools": [{ "object_id": "sc1" }, { "name": "no id" }],
+            "permissions": "all",
+        })).unwrap();
+        assert_eq!(profile.display_name(), "u1");
+        assert_eq!(profile.guardian_relationships.len(), 2);
+        assert_eq!(profile.guardian_relationships[0].student.as_ref().unwrap().object_id, "s1");
+        assert_eq!(profile.guardian_relationships[0].relationship.as_deref(), Some("parent"));
+        assert_eq!(profile.guardian_relationships[1], GuardianRelationship { student: None, relationship: None });
+        assert_eq!(Vec::from_iter(profile.schools.iter().map(|school| school.object_id.as_str())), ["sc1"]);
+        assert!(profile.permissions.is_empty());
+    }
+
+    #[test]
+    fn students_keep_school_and_rooms() {
+        let student = Student::from_json(json!({
+            "object_id": "s1", "first_name": "Ada", "last_name": "Lovelace",
+            "school": { "object_id": "sc1", "name": "Little Sprouts" },
+            "room": { "object_id": "r1", "name": "Toddlers" },
+            "enrollment_status": "active",
+        })).unwrap();
+        assert_eq!(student.school.unwrap().name.as_deref(), Some("Little Sprouts"));
+        assert_eq!(student.rooms, [Room { object_id: "r1".into(), name: Some("Toddlers".into()) }]);
+        assert_eq!(student.enrollment_status.as_deref(), Some("active"));
+        assert_eq!(student.profile_photo_url, None);
+
+        let student = Student::from_json(json!({
+            "object_id": "s2", "first_name": "Grace", "last_name": "Hopper",
+            "classrooms": [{ "object_id": "r2" }, { "name": "no id" }],
+            "profile_photo": { "url": "https://cdn.example.com/grace.jpg" },
+        })).unwrap();
+        assert_eq!(student.profile_photo_url.as_deref(), Some("https://cdn.example.com/grace.jpg"));
+        assert_eq!(student.school, None);
+        assert_eq!(Vec::from_iter(student.rooms.iter().map(|room| room.object_id.as_str())), ["r2"]);
+    }
+
+    #[test]
+    fn students_that_cannot_be_read_are_reported_and_the_rest_kept() {
+        let students = parse_students(&json!({ "students": [
+            { "student": {
+                "object_id": "s1", "first_name": "Ada", "last_name": "Lovelace",
+                "school": "Little Sprouts",
+                "room": { "object_id": "r1" }, "rooms": [{ "object_id": "r2" }],
+                "status": "active", "enrollment_status": null,
+            } },
+            { "student": { "object_id": "s2", "first_name": "Grace" } },
+            { "student": { "first_name": "Alan", "last_name": 7 } },
+        ] })).unwrap();
+
+        let ada = students[0].as_ref().unwrap();
+        assert_eq!(ada.school, None);
+        assert_eq!(ada.rooms, [Room { object_id: "r2".into(), name: None }]);
+        assert_eq!(ada.enrollment_status.as_deref(), Some("active"));
+        assert!(students[1].as_ref().unwrap_err().starts_with("student s2: missing field `last_name`"));
+        assert!(students[2].as_ref().unwrap_err().starts_with("student 3 in the list: "));
+
+        assert!(parse_students(&json!({ "error": "nope" })).is_err());
+    }
+
+    #[test]
+    fn messages_take_the_first_of_each_repeated_name() {
+        let message = Message::from_json(json!({
+            "object_id": "m1",
+            "created_at": "2024-05-17T09:30:12Z",
+            "body": "Field trip tomorrow!",
+            "message": "(older copy)",
+            "author": null,
+            "sent_by": { "name": "Little Sprouts", "first_name": 7 },
+            "attachments": [
+                { "url": "https://cdn.example.com/form.pdf", "file_url": "https://cdn.example.com/old.pdf", "name": "form.pdf", "file_name": "old.pdf" },
+                { "download_url": "https://cdn.example.com/menu.pdf" },
+                { "name": "no url" },
+            ],
+        })).unwrap();
+        assert_eq!(message.text.as_deref(), Some("Field trip tomorrow!"));
+        assert_eq!(message.sender.unwrap().display_name(), "Little Sprouts");
+        assert_eq!(
+            Vec::from_iter(message.attachments.iter().map(|attachment| (attachment.url.as_str(), attachment.filename.as_deref()))),
+            [("https://cdn.example.com/form.pdf", Some("form.pdf")), ("https://cdn.example.com/menu.pdf", None)]
+        );
+
+        let announcement = Announcement::from_json(json!({
+            "object_id": "an1",
+            "created_at": "2024-05-17T09:30:12Z",
+            "title": "Closed Monday",
+            "subject": "Holiday",
+            "text": null,
+            "message": "(older copy)",
+            "body": "For Memorial Day.",
+        })).unwrap();
+        assert_eq!(announcement.title.as_deref(), Some("Closed Monday"));
+        assert_eq!(announcement.text.as_deref(), Some("For Memorial Day."));
+        assert!(announcement.attachments.is_empty());
+
+        let thread = MessageThread::from_json(json!({ "object_id": "t1", "subject": "Naps", "name": "Room 2" })).unwrap();
+        assert_eq!(thread.title.as_deref(), Some("Naps"));
+    }
+
+    #[test]
+    fn profile_photo_is_read_from_the_first_name_that_has_one() {
+        let photo_url = |fields: Value| {
+            let mut student = json!({ "object_id": "s1", "first_name": "Ada", "last_name": "Lovelace" });
+            student.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+            Student::from_json(student).unwrap().profile_photo_url
+        };
+        assert_eq!(photo_url(json!({ "photo_url": "https://cdn.example.com/a.jpg" })).as_deref(), Some("https://cdn.example.com/a.jpg"));
+        assert_eq!(
+            photo_url(json!({
+                "profile_photo_url": "https://cdn.example.com/a.jpg",
+                "profile_photo": { "url": "https://cdn.example.com/b.jpg" },
+                "photo_url": "https://cdn.example.com/c.jpg",
+            })).as_deref(),
+            Some("https://cdn.example.com/a.jpg")
+        );
+        assert_eq!(
+            photo_url(json!({ "profile_photo_url": null, "profile_photo": { "large": "https://cdn.example.com/b.jpg" } })).as_deref(),
+            Some("https://cdn.example.com/b.jpg")
+        );
+        assert_eq!(photo_url(json!({ "profile_photo": { "url": "" }, "photo_url": 7 })), None);
+        assert_eq!(photo_url(json!({})), None);
     }
 }
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use crate::brightwheel::UserProfile;
+use crate::library::CACHE_DIR;
+use crate::storage::LibraryStorage;
+
+// What the library holds beyond the media itself, kept in the library so it travels
+// with it (including to S3).
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Catalog {
+    // Every account that has synced into this library, by user id.
+    #[serde(default)]
+    pub accounts: BTreeMap<String, CatalogAccount>,
+    // Every file a sync has saved, by its path in the library.
+    #[serde(default)]
+    pub items: BTreeMap<String, CatalogItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CatalogAccount {
+    pub profile: UserProfile,
+    pub profile_updated_at: Timestamp,
+    #[serde(default)]
+    pub last_synced_at: Option<Timestamp>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CatalogItem {
+    // User id of the account it was synced from.
+    pub account: String,
+    #[serde(default)]
+    pub student: Option<String>,
+    // object_id of the activity, message or announcement it came from, or of the student
+    // for profile photos.
+    pub source: String,
+    pub saved_at: Timestamp,
+}
+
+impl Catalog {
+    pub fn path() -> String {
+        format!("{}/catalog.json", CACHE_DIR)
+    }
+
+    pub fn load<S: LibraryStorage + ?Sized>(storage: &S) -> Result<Catalog, String> {
+        if !storage.exists(&Self::path())? {
+            return Ok(Catalog::default());
+        }
+        serde_json::from_slice(&storage.read(&Self::path())?).map_err(|e| format!("{}: {}", Self::path(), e))
+    }
+
+    pub fn save<S: LibraryStorage + ?Sized>(&self, storage: &S) -> Result<(), String> {
+        storage.create_dir(CACHE_DIR)?;
+        let bytes = serde_json::to_vec_pretty(self).unwrap();
+        storage.write_from(&Self::path(), &mut bytes.as_slice())?;
+        Ok(())
+    }
+
+    pub fn record_profile(&mut self, profile: &UserProfile, now: Timestamp) -> &mut CatalogAccount {
+        let account = self.accounts.entry(profile.object_id.clone()).or_insert_with(|| CatalogAccount {
+            profile: profile.clone(),
+            profile_updated_at: now,
+            last_synced_at: None,
+        });
+        account.profile = profile.clone();
+        account.profile_updated_at = now;
+        account
+    }
+}
+
+// Records profile in the library's catalog, that it synced at synced_at_opt if given,
+// and the items that sync saved.
+pub fn update_account<S: LibraryStorage + ?Sized>(
+    storage: &S, profile: &UserProfile, synced_at_opt: Option<Timestamp>, items: BTreeMap<String, CatalogItem>,
+) -> Result<(), String> {
+    let now = Timestamp::now();
+    let mut catalog = Catalog::load(storage)?;
+    let account = catalog.record_profile(profile, now);
+    if synced_at_opt.is_some() {
+        account.last_synced_at = synced_at_opt;
+    }
+    catalog.items.extend(items);
+    catalog.save(storage)
+}
@@ -0,0 +1,579 @@
+use std::{collections::BTreeMap, fs, io, path::{Path, PathBuf}};
+
+use jiff::tz::TimeZone;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{gallery, library::{self, LibraryItem, LibraryMonth, LibraryStudent, MediaKind}};
+
+const ALBUM_CSS: &str = r#"
+body { font-family: Helvetica, Arial, sans-serif; margin: 2em; color: #0f0f0f; background: #f6f6f6; }
+h1, h2 { font-weight: 500; }
+a { color: #396cd8; text-decoration: none; }
+.grid { display: flex; flex-wrap: wrap; gap: 8px; }
+.tile { width: 200px; }
+.tile img { width: 200px; height: 200px; object-fit: cover; border-radius: 6px; }
+.item { margin-bottom: 2em; }
+.item img, .item video { max-width: 100%; max-height: 80vh; border-radius: 6px; }
+.caption { max-width: 60em; white-space: pre-wrap; }
+.time { color: #777; font-size: 0.9em; }
+"#;
+
+// Characters escaped in relative links; '/' is kept so paths stay paths.
+const HREF_ENCODE_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'?').add(b'<').add(b'>');
+
+#[derive(Serialize, Debug)]
+pub struct ExportSummary {
+    pub path: String,
+    pub zip_path: Option<String>,
+    pub days: usize,
+    pub items: usize,
+}
+
+const XMP_TEMPLATE: &str = r#"<?xpacket begin="{bom}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:exif="http://ns.adobe.com/exif/1.0/"
+    xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"{date_attrs}>{description}
+   <dc:subject><rdf:Bag><rdf:li>{student}</rdf:li></rdf:Bag></dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImporterTarget {
+    // Google Takeout layout: album folders with per-file *.json sidecars.
+    Takeout,
+    // Student/month folders with *.jpg.xmp sidecars, for the immich CLI with --album.
+    Immich,
+    // Student/month folders with *.jpg.xmp sidecars, indexed by PhotoPrism as folder albums.
+    PhotoPrism,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImporterExportSummary {
+    pub path: String,
+    pub albums: usize,
+    pub items: usize,
+}
+
+struct AlbumDay<'a> {
+    date: String,
+    items: Vec<&'a LibraryItem>,
+}
+
+// Renders a self-contained static site for one student's synced folder: an index by month,
+// one page per day, thumbnails and full-size media, all linked relatively so it can be
+// opened straight from a USB stick.
+pub fn export_album(root: &Path, student_name: &str, dest: &Path, zip: bool) -> Result<ExportSummary, String> {
+    let student_path = library::resolve(root, student_name).ok_or("invalid student name")?;
+    let student = library::scan_student(root, &student_path)
+        .ok_or_else(|| format!("nothing synced for {}", student_name))?;
+
+    info!("exporting album for {} to {:?}", student_name, dest);
+    fs::create_dir_all(dest.join("days")).map_err(|e| e.to_string())?;
+
+    // Days are grouped across the whole student, not per month folder: the folders are
+    // by UTC month, so one local day can have items in two of them.
+    let days = group_by_day(&student.months, &TimeZone::system());
+    let mut item_count = 0;
+    let mut months: BTreeMap<&str, Vec<&AlbumDay>> = BTreeMap::new();
+    for day in &days {
+        for item in &day.items {
+            copy_item(root, dest, item)?;
+        }
+        write_file(&dest.join("days").join(format!("{}.html", day.date)), &render_day_page(&student, day))?;
+        item_count += day.items.len();
+        months.entry(&day.date[..7]).or_default().push(day);
+    }
+    let index_sections = Vec::from_iter(months.iter().map(|(month, month_days)| {
+        let day_links = Vec::from_iter(month_days.iter().map(|day| format!(
+            "<li><a href=\"days/{date}.html\">{date}</a> ({count})</li>",
+            date = day.date, count = day.items.len()
+        )));
+        format!(
+            "<h2>{}</h2>\n<div class=\"grid\">{}</div>\n<ul>{}</ul>",
+            escape_html(month),
+            render_month_covers(month_days),
+            day_links.join("\n")
+        )
+    }));
+    write_file(&dest.join("index.html"), &render_page(&student.name, &index_sections.join("\n")))?;
+
+    let zip_path = if zip {
+        let zip_path = dest.with_extension("zip");
+        zip_directory(dest, &zip_path)?;
+        Some(zip_path.to_string_lossy().into_owned())
+    }
+    else {
+        None
+    };
+
+    Ok(ExportSummary {
+        path: dest.to_string_lossy().into_owned(),
+        zip_path,
+        days: days.len(),
+        items: item_count,
+    })
+}
+
+// Groups a student's items by calendar day in tz, oldest first. Items with no timestamp
+// go on a "YYYY-MM-00" day of the month folder they were found in.
+fn group_by_day<'a>(months: &'a [LibraryMonth], tz: &TimeZone) -> Vec<AlbumDay<'a>> {
+    let mut days: BTreeMap<String, Vec<&LibraryItem>> = BTreeMap::new();
+    for month in months {
+        for item in &month.items {
+            let date = item.taken_at
+                .map(|ts| ts.to_zoned(tz.clone()).strftime("%F").to_string())
+                .unwrap_or_else(|| format!("{}-00", month.month));
+            days.entry(date).or_default().push(item);
+        }
+    }
+    Vec::from_iter(days.into_iter().map(|(date, mut items)| {
+        items.sort_by_key(|item| item.taken_at);
+        AlbumDay { date, items }
+    }))
+}
+
+fn media_path(item: &LibraryItem) -> String {
+    format!("media/{}", item.path)
+}
+
+fn thumb_path(item: &LibraryItem) -> String {
+    format!("thumbs/{}", Path::new(&item.path).with_extension("jpg").to_string_lossy())
+}
+
+fn href(path: &str) -> String {
+    escape_html(&utf8_percent_encode(path, HREF_ENCODE_SET).to_string())
+}
+
+fn copy_item(root: &Path, dest: &Path, item: &LibraryItem) -> Result<(), String> {
+    let src_path = library::resolve(root, &item.path).ok_or("invalid path")?;
+    copy_if_changed(&src_path, &dest.join(media_path(item)))?;
+    if item.kind == MediaKind::Photo {
+        match gallery::thumbnail(root, &item.path) {
+            Ok(thumb_src_path) => copy_if_changed(&thumb_src_path, &dest.join(thumb_path(item)))?,
+            Err(msg) => warn!("no thumbnail for {}: {}", item.path, msg),
+        }
+    }
+    Ok(())
+}
+
+// Re-exporting into the same folder only copies files that are new or changed.
+fn copy_if_changed(src_path: &Path, dst_path: &Path) -> Result<(), String> {
+    let src_len = fs::metadata(src_path).map_err(|e| e.to_string())?.len();
+    if fs::metadata(dst_path).is_ok_and(|m| m.len() == src_len) {
+        return Ok(());
+    }
+    fs::create_dir_all(dst_path.parent().unwrap()).map_err(|e| e.to_string())?;
+    fs::copy(src_path, dst_path).map_err(|e| format!("{:?}: {}", dst_path, e))?;
+    Ok(())
+}
+
+fn render_month_covers(days: &[&AlbumDay]) -> String {
+    Vec::from_iter(days.iter().filter_map(|day| {
+        let cover = day.items.iter().find(|item| item.kind == MediaKind::Photo)?;
+        Some(format!(
+            "<a class=\"tile\" href=\"days/{}.html\"><img src=\"{}\" loading=\"lazy\" alt=\"\"></a>",
+            day.date, href(&thumb_path(cover))
+        ))
+    })).join("\n")
+}
+
+fn render_day_page(student: &LibraryStudent, day: &AlbumDay) -> String {
+    let items = Vec::from_iter(day.items.iter().map(|item| {
+        let media_href = format!("../{}", href(&media_path(item)));
+        let media = match item.kind {
+            MediaKind::Photo => format!(
+                "<a href=\"{}\"><img src=\"../{}\" alt=\"\"></a>", media_href, href(&thumb_path(item))
+            ),
+            MediaKind::Video => format!("<video src=\"{}\" controls preload=\"metadata\"></video>", media_href),
+        };
+        let time = item.taken_at
+            .map(|ts| ts.to_zoned(TimeZone::system()).strftime("%-I:%M %p").to_string())
+            .unwrap_or_default();
+        let caption = item.caption.as_deref().map(escape_html).unwrap_or_default();
+        format!(
+            "<div class=\"item\">{}\n<p class=\"time\">{}</p>\n<p class=\"caption\">{}</p></div>",
+            media, time, caption
+        )
+    })).join("\n");
+
+    render_page(
+        &format!("{} \u{2013} {}", student.name, day.date),
+        &format!("<p><a href=\"../index.html\">&larr; All days</a></p>\n{}", items),
+    )
+}
+
+fn render_page(title: &str, body: &str) -> String {
+    format!(
+        "<!doctype html>\n<html lang=\"en\">\n<head>\n<meta charset=\"UTF-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1.0\">\n\
+         <title>{title}</title>\n<style>{css}</style>\n</head>\n<body>\n<h1>{title}</h1>\n{body}\n</body>\n</html>\n",
+        title = escape_html(title), css = ALBUM_CSS, body = body
+    )
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("{:?}: {}", path, e))
+}
+
+fn zip_directory(src_dir: &Path, zip_path: &Path) -> Result<(), String> {
+    let file = fs::File::create(zip_path).map_err(|e| e.to_string())?;
+    let mut writer = zip::ZipWriter::new(io::BufWriter::new(file));
+    let options = zip::write::SimpleFileOptions::default();
+
+    let mut pending = vec![src_dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = Vec::from_iter(
+            fs::read_dir(&dir).map_err(|e| e.to_string())?.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        );
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let name = zip_entry_name(src_dir, &path);
+            writer.start_file(name, options).map_err(|e| e.to_string())?;
+            let mut file = fs::File::open(&path).map_err(|e| format!("{:?}: {}", path, e))?;
+            io::copy(&mut file, &mut writer).map_err(|e| format!("{:?}: {}", path, e))?;
+        }
+    }
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn zip_entry_name(src_dir: &Path, path: &Path) -> String {
+    let album_name = src_dir.file_name().map(PathBuf::from).unwrap_or_default();
+    let rel = album_name.join(path.strip_prefix(src_dir).unwrap());
+    Vec::from_iter(rel.components().map(|c| c.as_os_str().to_string_lossy().into_owned())).join("/")
+}
+
+// Writes every student's library in the layout and sidecar format an importer understands.
+// Albums are one per student and one per student/month.
+pub fn export_for_importer(root: &Path, target: ImporterTarget, dest: &Path) -> Result<ImporterExportSummary, String> {
+    info!("exporting library for {:?} to {:?}", target, dest);
+    let students = library::scan_library(root);
+    let mut albums = 0;
+    let mut items = 0;
+
+    for student in &students {
+        match target {
+            ImporterTarget::Takeout => {
+                let photos_dir = dest.join("Takeout").join("Google Photos");
+                let all_items = Vec::from_iter(student.months.iter().flat_map(|month| &month.items));
+                write_takeout_album(root, &photos_dir.join(&student.name), &student.name, &student.name, &all_items)?;
+                albums += 1;
+                for month in &student.months {
+                    let title = format!("{} {}", student.name, month.month);
+                    let month_items = Vec::from_iter(&month.items);
+                    write_takeout_album(root, &photos_dir.join(&title), &title, &student.name, &month_items)?;
+                    albums += 1;
+                }
+            },
+            ImporterTarget::Immich | ImporterTarget::PhotoPrism => {
+                for month in &student.months {
+                    for item in &month.items {
+                        let src_path = library::resolve(root, &item.path).ok_or("invalid path")?;
+                        let dst_path = dest.join(&student.name).join(&month.month).join(src_path.file_name().unwrap());
+                        copy_if_changed(&src_path, &dst_path)?;
+                        write_file(&sidecar_path_with_suffix(&dst_path, "xmp"), &render_xmp(item, &student.name))?;
+                    }
+                    albums += 1;
+                }
+            },
+        }
+        items += student.months.iter().map(|month| month.items.len()).sum::<usize>();
+    }
+
+    Ok(ImporterExportSummary {
+        path: dest.to_string_lossy().into_owned(),
+        albums,
+        items,
+    })
+}
+
+fn write_takeout_album(root: &Path, album_dir: &Path, title: &str, student_name: &str, items: &[&LibraryItem]) -> Result<(), String> {
+    fs::create_dir_all(album_dir).map_err(|e| e.to_string())?;
+    let metadata = json!({
+        "title": title,
+        "description": format!("brightwheel photos of {}", student_name),
+        "access": "protected",
+    });
+    write_file(&album_dir.join("metadata.json"), &serde_json::to_string_pretty(&metadata).unwrap())?;
+
+    for item in items {
+        let src_path = library::resolve(root, &item.path).ok_or("invalid path")?;
+        let dst_path = album_dir.join(src_path.file_name().unwrap());
+        copy_if_changed(&src_path, &dst_path)?;
+        write_file(
+            &sidecar_path_with_suffix(&dst_path, "json"),
+            &serde_json::to_string_pretty(&takeout_sidecar(item, &dst_path, student_name)).unwrap(),
+        )?;
+    }
+    Ok(())
+}
+
+// Items without a date get no creationTime/photoTakenTime at all; a 0 timestamp would
+// file them under 1970 in the importer.
+fn takeout_sidecar(item: &LibraryItem, dst_path: &Path, student_name: &str) -> serde_json::Value {
+    let mut sidecar = json!({
+        "title": dst_path.file_name().unwrap().to_string_lossy(),
+        "description": item.caption.clone().unwrap_or_default(),
+        "imageViews": "0",
+    });
+    if let Some(taken_at) = item.taken_at {
+        let taken_time = json!({
+            "timestamp": taken_at.as_second().to_string(),
+            "formatted": taken_at.to_zoned(TimeZone::UTC).strftime("%b %-d, %Y, %-I:%M:%S %p UTC").to_string(),
+        });
+        sidecar["creationTime"] = taken_time.clone();
+        sidecar["photoTakenTime"] = taken_time;
+    }
+    sidecar["people"] = json!([{ "name": student_name }]);
+    sidecar["url"] = json!("");
+    sidecar
+}
+
+// photo.jpg -> photo.jpg.json / photo.jpg.xmp, the naming Takeout, immich and PhotoPrism
+// all read, and one that keeps photo.jpg and photo.mp4 from sharing a sidecar.
+fn sidecar_path_with_suffix(media_path: &Path, suffix: &str) -> PathBuf {
+    let mut name = media_path.file_name().unwrap().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    media_path.with_file_name(name)
+}
+
+fn render_xmp(item: &LibraryItem, student_name: &str) -> String {
+    let date_attrs = match item.taken_at {
+        Some(taken_at) => {
+            let date = taken_at.to_zoned(TimeZone::UTC).strftime("%Y-%m-%dT%H:%M:%SZ").to_string();
+            format!(
+                "\n    photoshop:DateCreated=\"{date}\"\n    exif:DateTimeOriginal=\"{date}\"\n    xmp:CreateDate=\"{date}\"",
+                date = date
+            )
+        },
+        None => String::new(),
+    };
+    let description = match &item.caption {
+        Some(caption) => format!(
+            "\n   <dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>",
+            escape_html(caption)
+        ),
+        None => String::new(),
+    };
+    XMP_TEMPLATE
+        .replace("{bom}", "\u{feff}")
+        .replace("{student}", &escape_html(student_name))
+        .replace("{date_attrs}", &date_attrs)
+        .replace("{description}", &description)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use jiff::tz;
+
+    use super::*;
+
+    const CAPTION: &str = "<b>Sand & water</b> \"all morning\"";
+
+    // A library with one captioned photo and one video that only has its file name.
+    fn sample_library() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let month_dir = root.path().join("Ada & Bo").join("2024-05");
+        fs::create_dir_all(&month_dir).unwrap();
+        fs::write(month_dir.join("2024-05-17-093012-a1.jpg"), "photo").unwrap();
+        fs::write(month_dir.join("2024-05-17-093012-a1.json"), serde_json::to_vec(&json!({
+            "object_id": "a1",
+            "created_at": "2024-05-17T09:30:12Z",
+            "note": CAPTION,
+            "action_type": "ac_photo",
+        })).unwrap()).unwrap();
+        fs::write(month_dir.join("2024-05-18-101500-v1.mp4"), "video").unwrap();
+        root
+    }
+
+    fn files_under(dir: &Path) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(path) = pending.pop() {
+            for entry in fs::read_dir(&path).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                }
+                else {
+                    files.push(path.strip_prefix(dir).unwrap().to_string_lossy().into_owned());
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    fn item(path: &str, taken_at: Option<&str>) -> LibraryItem {
+        LibraryItem {
+            path: path.into(),
+            kind: MediaKind::Photo,
+            taken_at: taken_at.map(|ts| ts.parse().unwrap()),
+            caption: None,
+        }
+    }
+
+    #[test]
+    fn local_days_span_utc_month_folders() {
+        let months = [
+            LibraryMonth {
+                month: "2024-05".into(),
+                items: vec![item("Ada/2024-05/b.jpg", Some("2024-05-31T18:00:00Z"))],
+            },
+            LibraryMonth {
+                month: "2024-06".into(),
+                items: vec![
+                    item("Ada/2024-06/c.jpg", Some("2024-06-01T20:00:00Z")),
+                    item("Ada/2024-06/a.jpg", Some("2024-06-01T03:00:00Z")),
+                    item("Ada/2024-06/undated.jpg", None),
+                ],
+            },
+        ];
+        let pacific = TimeZone::fixed(tz::offset(-7));
+        let days = Vec::from_iter(group_by_day(&months, &pacific).into_iter().map(|day| {
+            (day.date, Vec::from_iter(day.items.iter().map(|item| item.path.as_str())))
+        }));
+        assert_eq!(days, [
+            ("2024-05-31".to_string(), vec!["Ada/2024-05/b.jpg", "Ada/2024-06/a.jpg"]),
+            ("2024-06-00".to_string(), vec!["Ada/2024-06/undated.jpg"]),
+            ("2024-06-01".to_string(), vec!["Ada/2024-06/c.jpg"]),
+        ]);
+
+        let days = group_by_day(&months, &TimeZone::UTC);
+        assert_eq!(Vec::from_iter(days.iter().map(|day| day.date.as_str())), ["2024-05-31", "2024-06-00", "2024-06-01"]);
+        assert_eq!(days[2].items.len(), 2);
+    }
+
+    #[test]
+    fn zip_holds_every_file_under_the_album_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = dir.path().join("Ada album");
+        fs::create_dir_all(album.join("days")).unwrap();
+        fs::write(album.join("index.html"), "<h1>Ada</h1>").unwrap();
+        fs::write(album.join("days").join("2024-05-31.html"), "<p>May 31</p>").unwrap();
+        let zip_path = dir.path().join("Ada album.zip");
+
+        zip_directory(&album, &zip_path).unwrap();
+
+        let mut archive = zip::ZipArchive::new(fs::File::open(&zip_path).unwrap()).unwrap();
+        let mut names = Vec::from_iter(archive.file_names().map(String::from));
+        names.sort();
+        assert_eq!(names, ["Ada album/days/2024-05-31.html", "Ada album/index.html"]);
+        let mut contents = String::new();
+        archive.by_name("Ada album/days/2024-05-31.html").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "<p>May 31</p>");
+    }
+
+    #[test]
+    fn takeout_export_writes_album_metadata_and_json_sidecars() {
+        let root = sample_library();
+        let dest = tempfile::tempdir().unwrap();
+
+        let summary = export_for_importer(root.path(), ImporterTarget::Takeout, dest.path()).unwrap();
+        assert_eq!((summary.albums, summary.items), (2, 2));
+        let mut expected = Vec::new();
+        for album in ["Ada & Bo", "Ada & Bo 2024-05"] {
+            for name in [
+                "2024-05-17-093012-a1.jpg", "2024-05-17-093012-a1.jpg.json",
+                "2024-05-18-101500-v1.mp4", "2024-05-18-101500-v1.mp4.json", "metadata.json",
+            ] {
+                expected.push(format!("Takeout/Google Photos/{}/{}", album, name));
+            }
+        }
+        expected.sort();
+        assert_eq!(files_under(dest.path()), expected);
+
+        let album_dir = dest.path().join("Takeout/Google Photos/Ada & Bo 2024-05");
+        let metadata: serde_json::Value = serde_json::from_slice(&fs::read(album_dir.join("metadata.json")).unwrap()).unwrap();
+        assert_eq!(metadata["title"], "Ada & Bo 2024-05");
+
+        let sidecar: serde_json::Value =
+            serde_json::from_slice(&fs::read(album_dir.join("2024-05-17-093012-a1.jpg.json")).unwrap()).unwrap();
+        assert_eq!(sidecar["title"], "2024-05-17-093012-a1.jpg");
+        assert_eq!(sidecar["description"], CAPTION);
+        assert_eq!(sidecar["photoTakenTime"], json!({ "timestamp": "1715938212", "formatted": "May 17, 2024, 9:30:12 AM UTC" }));
+        assert_eq!(sidecar["people"], json!([{ "name": "Ada & Bo" }]));
+
+        let sidecar: serde_json::Value =
+            serde_json::from_slice(&fs::read(album_dir.join("2024-05-18-101500-v1.mp4.json")).unwrap()).unwrap();
+        assert_eq!(sidecar["description"], "");
+        assert_eq!(sidecar["creationTime"]["timestamp"], "1716027300");
+        assert_eq!(sidecar["creationTime"]["formatted"], "May 18, 2024, 10:15:00 AM UTC");
+    }
+
+    #[test]
+    fn undated_takeout_sidecars_leave_out_the_taken_time() {
+        let sidecar = takeout_sidecar(&item("Ada/2024-05/photo.jpg", None), Path::new("album/photo.jpg"), "Ada");
+        assert_eq!(sidecar["title"], "photo.jpg");
+        assert_eq!(sidecar.get("photoTakenTime"), None);
+        assert_eq!(sidecar.get("creationTime"), None);
+        assert_eq!(sidecar["people"], json!([{ "name": "Ada" }]));
+    }
+
+    #[test]
+    fn immich_and_photoprism_exports_write_escaped_xmp_sidecars() {
+        let root = sample_library();
+        for (target, photo_xmp, video_xmp) in [
+            (ImporterTarget::Immich, "2024-05-17-093012-a1.jpg.xmp", "2024-05-18-101500-v1.mp4.xmp"),
+            (ImporterTarget::PhotoPrism, "2024-05-17-093012-a1.jpg.xmp", "2024-05-18-101500-v1.mp4.xmp"),
+        ] {
+            let dest = tempfile::tempdir().unwrap();
+            let summary = export_for_importer(root.path(), target, dest.path()).unwrap();
+            assert_eq!((summary.albums, summary.items), (1, 2));
+
+            let month_dir = "Ada & Bo/2024-05/";
+            let mut expected = Vec::from_iter(
+                ["2024-05-17-093012-a1.jpg", photo_xmp, "2024-05-18-101500-v1.mp4", video_xmp]
+                    .map(|name| format!("{}{}", month_dir, name))
+            );
+            expected.sort();
+            assert_eq!(files_under(dest.path()), expected, "{:?}", target);
+
+            let xmp = fs::read_to_string(dest.path().join(month_dir).join(photo_xmp)).unwrap();
+            assert!(xmp.starts_with("<?xpacket begin=\"\u{feff}\""), "{}", xmp);
+            assert!(xmp.contains("exif:DateTimeOriginal=\"2024-05-17T09:30:12Z\""), "{}", xmp);
+            assert!(xmp.contains("photoshop:DateCreated=\"2024-05-17T09:30:12Z\""), "{}", xmp);
+            assert!(xmp.contains(
+                "<rdf:li xml:lang=\"x-default\">&lt;b&gt;Sand &amp; water&lt;/b&gt; &quot;all morning&quot;</rdf:li>"
+            ), "{}", xmp);
+            assert!(xmp.contains("<rdf:li>Ada &amp; Bo</rdf:li>"), "{}", xmp);
+
+            let xmp = fs::read_to_string(dest.path().join(month_dir).join(video_xmp)).unwrap();
+            assert!(xmp.contains("xmp:CreateDate=\"2024-05-18T10:15:00Z\""), "{}", xmp);
+            assert!(!xmp.contains("dc:description"), "{}", xmp);
+        }
+    }
+}
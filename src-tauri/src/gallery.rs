@@ -0,0 +1,271 @@
+use std::{fs::File, io::{BufWriter, Read, Seek, SeekFrom}, path::{Path, PathBuf}};
+
+use image::{imageops::FilterType, ImageFormat};
+use percent_encoding::percent_decode_str;
+use tauri::http::{header, Request, Response, StatusCode};
+use tempfile::NamedTempFile;
+use tracing::{debug, warn};
+
+use crate::library::{self, MediaKind, CACHE_DIR};
+
+const THUMBNAIL_SIZE: u32 = 360;
+
+// Most bytes sent for one Range request.
+const MAX_RANGE_LEN: u64 = 4 * 1024 * 1024;
+
+// Serves full-size media for the library:// protocol, honoring Range requests
+// so videos can seek.
+pub fn handle_media_request(root: &Path, request: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+    let Some(path) = request_path(root, request) else {
+        return error_response(StatusCode::BAD_REQUEST);
+    };
+    let range_opt = request.headers().get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_range);
+
+    let opened = File::open(&path).and_then(|file| {
+        let total_len = file.metadata()?.len();
+        Ok((file, total_len))
+    });
+    let (mut file, total_len) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            warn!("could not serve {:?}: {}", path, e);
+            return error_response(StatusCode::NOT_FOUND);
+        }
+    };
+    let Some((start, end)) = resolve_range(range_opt, total_len) else {
+        return Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", total_len))
+            .body(Vec::new())
+            .unwrap();
+    };
+    match read_span(&mut file, start, end) {
+        Ok(bytes) => {
+            let builder = Response::builder()
+                .header(header::CONTENT_TYPE, content_type(&path))
+                .header(header::ACCEPT_RANGES, "bytes");
+            let builder = if range_opt.is_some() || end - start < total_len {
+                builder.status(StatusCode::PARTIAL_CONTENT).header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end - 1, total_len),
+                )
+            }
+            else {
+                builder.status(StatusCode::OK)
+            };
+            builder.body(bytes).unwrap()
+        },
+        Err(e) => {
+            warn!("could not serve {:?}: {}", path, e);
+            error_response(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+// Serves cached thumbnails for the thumb:// protocol, generating them on first use.
+pub fn handle_thumbnail_request(root: &Path, request: &Request<Vec<u8>>) -> Response<Vec<u8>> {
+    let Some(rel_path) = request_rel_path(request) else {
+        return error_response(StatusCode::BAD_REQUEST);
+    };
+    match thumbnail(root, &rel_path).and_then(|path| std::fs::read(path).map_err(|e| e.to_string())) {
+        Ok(bytes) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "image/jpeg")
+            .body(bytes)
+            .unwrap(),
+        Err(msg) => {
+            warn!("could not make thumbnail for {}: {}", rel_path, msg);
+            error_response(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+pub fn thumbnail_cache_path(root: &Path, rel_path: &str) -> Option<PathBuf> {
+    library::resolve(&root.join(CACHE_DIR).join("thumbnails"), rel_path)
+        .map(|path| path.with_extension("jpg"))
+}
+
+// Returns the path of the cached thumbnail for a library photo, regenerating it
+// if the photo is newer than the cached copy.
+pub fn thumbnail(root: &Path, rel_path: &str) -> Result<PathBuf, String> {
+    let src_path = library::resolve(root, rel_path).ok_or("invalid path")?;
+    if library::media_kind(&src_path) != Some(MediaKind::Photo) {
+        return Err("not a photo".into());
+    }
+    let thumb_path = thumbnail_cache_path(root, rel_path).ok_or("invalid path")?;
+
+    let src_modified = std::fs::metadata(&src_path).and_then(|m| m.modified()).map_err(|e| e.to_string())?;
+    if let Ok(thumb_modified) = std::fs::metadata(&thumb_path).and_then(|m| m.modified()) {
+        if thumb_modified >= src_modified {
+            return Ok(thumb_path);
+        }
+    }
+
+    debug!("generating thumbnail {:?}", thumb_path);
+    let thumb_dir = thumb_path.parent().unwrap();
+    std::fs::create_dir_all(thumb_dir).map_err(|e| e.to_string())?;
+    let image = image::open(&src_path).map_err(|e| e.to_string())?;
+    // Written beside the cache entry and renamed into place, so a request racing this one
+    // or a crash halfway through never finds a truncated thumbnail.
+    let mut partial = NamedTempFile::new_in(thumb_dir).map_err(|e| e.to_string())?;
+    image.resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle)
+        .to_rgb8()
+        .write_to(&mut BufWriter::new(partial.as_file_mut()), ImageFormat::Jpeg)
+        .map_err(|e| e.to_string())?;
+    partial.persist(&thumb_path).map_err(|e| e.to_string())?;
+    Ok(thumb_path)
+}
+
+fn request_rel_path(request: &Request<Vec<u8>>) -> Option<String> {
+    percent_decode_str(request.uri().path()).decode_utf8().ok().map(|path| path.into_owned())
+}
+
+fn request_path(root: &Path, request: &Request<Vec<u8>>) -> Option<PathBuf> {
+    library::resolve(root, &request_rel_path(request)?)
+}
+
+// Parses a single "bytes=start-end" range; multi-range, suffix and malformed requests
+// are treated as if they had no Range.
+fn parse_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let spec = value.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let start = start.parse().ok()?;
+    let end_opt = if end.is_empty() { None } else { Some(end.parse().ok()?) };
+    match end_opt {
+        Some(end) if end < start => None,
+        _ => Some((start, end_opt)),
+    }
+}
+
+// The span [start, end) of a file of total_len bytes to send, or None if the range
+// starts past the end of the file. Spans are cut to MAX_RANGE_LEN so a video player
+// asking for "bytes=0-" doesn't pull the whole file into memory; it asks for the rest
+// as it plays. Requests without a Range get the same cap, answered as a 206 when the
+// file is bigger than that.
+fn resolve_range(range_opt: Option<(u64, Option<u64>)>, total_len: u64) -> Option<(u64, u64)> {
+    match range_opt {
+        None => Some((0, total_len.min(MAX_RANGE_LEN))),
+        Some((start, _)) if start >= total_len => None,
+        Some((start, end_opt)) => {
+            let end = end_opt.map_or(total_len, |end| end.saturating_add(1).min(total_len));
+            Some((start, end.min(start + MAX_RANGE_LEN)))
+        },
+    }
+}
+
+fn read_span(file: &mut File, start: u64, end: u64) -> std::io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut bytes = Vec::with_capacity((end - start) as usize);
+    file.take(end - start).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_ascii_lowercase()).as_deref() {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("heic") => "image/heic",
+        Some("mp4") | Some("m4v") => "video/mp4",
+        Some("mov") => "video/quicktime",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+pub fn not_found_response() -> Response<Vec<u8>> {
+    error_response(StatusCode::NOT_FOUND)
+}
+
+fn error_response(status: StatusCode) -> Response<Vec<u8>> {
+    Response::builder().status(status).body(Vec::new()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_parse_single_spans_only() {
+        assert_eq!(parse_range("bytes=0-"), Some((0, None)));
+        assert_eq!(parse_range("bytes=100-199"), Some((100, Some(199))));
+        assert_eq!(parse_range("bytes=5-5"), Some((5, Some(5))));
+        assert_eq!(parse_range("bytes=0-1,5-9"), None);
+        assert_eq!(parse_range("bytes=-500"), None);
+        assert_eq!(parse_range("bytes=9-3"), None);
+        assert_eq!(parse_range("items=0-10"), None);
+        assert_eq!(parse_range("bytes=a-b"), None);
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_file_and_the_chunk_limit() {
+        assert_eq!(resolve_range(None, 1000), Some((0, 1000)));
+        assert_eq!(resolve_range(Some((0, None)), 1000), Some((0, 1000)));
+        assert_eq!(resolve_range(Some((100, Some(199))), 1000), Some((100, 200)));
+        assert_eq!(resolve_range(Some((900, Some(5000))), 1000), Some((900, 1000)));
+        assert_eq!(resolve_range(Some((999, None)), 1000), Some((999, 1000)));
+        assert_eq!(resolve_range(Some((1000, None)), 1000), None);
+        assert_eq!(resolve_range(Some((0, None)), 0), None);
+        assert_eq!(resolve_range(Some((u64::MAX - 1, Some(u64::MAX))), 1000), None);
+
+        let video_len = 3 * MAX_RANGE_LEN;
+        assert_eq!(resolve_range(None, video_len), Some((0, MAX_RANGE_LEN)));
+        assert_eq!(resolve_range(Some((0, None)), video_len), Some((0, MAX_RANGE_LEN)));
+        assert_eq!(
+            resolve_range(Some((MAX_RANGE_LEN, Some(video_len - 1))), video_len),
+            Some((MAX_RANGE_LEN, 2 * MAX_RANGE_LEN))
+        );
+    }
+
+    #[test]
+    fn spans_are_read_from_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clip.mp4");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let mut file = File::open(&path).unwrap();
+        assert_eq!(read_span(&mut file, 2, 5).unwrap(), b"234");
+        assert_eq!(read_span(&mut file, 0, 10).unwrap(), b"0123456789");
+    }
+
+    #[test]
+    fn unranged_requests_for_big_files_get_the_first_chunk() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("small.mp4"), b"0123456789").unwrap();
+        std::fs::write(root.path().join("big.mp4"), vec![7; MAX_RANGE_LEN as usize + 10]).unwrap();
+
+        let request = Request::builder().uri("library://localhost/small.mp4").body(Vec::new()).unwrap();
+        let response = handle_media_request(root.path(), &request);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), b"0123456789");
+
+        let request = Request::builder().uri("library://localhost/big.mp4").body(Vec::new()).unwrap();
+        let response = handle_media_request(root.path(), &request);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.body().len() as u64, MAX_RANGE_LEN);
+        assert_eq!(
+            response.headers()[header::CONTENT_RANGE],
+            format!("bytes 0-{}/{}", MAX_RANGE_LEN - 1, MAX_RANGE_LEN + 10)
+        );
+    }
+
+    #[test]
+    fn thumbnails_are_renamed_into_the_cache() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("Ada")).unwrap();
+        image::RgbImage::new(800, 600).save(root.path().join("Ada/photo.png")).unwrap();
+
+        let thumb_path = thumbnail(root.path(), "Ada/photo.png").unwrap();
+        assert_eq!(thumb_path, thumbnail_cache_path(root.path(), "Ada/photo.png").unwrap());
+        assert_eq!(image::open(&thumb_path).unwrap().width(), THUMBNAIL_SIZE);
+        let cached = Vec::from_iter(
+            std::fs::read_dir(thumb_path.parent().unwrap()).unwrap().map(|entry| entry.unwrap().file_name())
+        );
+        assert_eq!(cached, ["photo.jpg"]);
+    }
+}
@@ -0,0 +1,268 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::brightwheel::{Activity, Media};
+use crate::library::format_filename;
+use crate::records::RecordKind;
+
+// What a handler saves for one activity. The sync engine does the saving, so handlers
+// only decide what an activity is worth keeping.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandlerOutput {
+    // Downloaded into the student's month folder, with the activity's sidecar next to it.
+    Media { filename: String, url: String },
+    // Written into the student's month folder.
+    Text { filename: String, contents: String },
+    // Looked up and saved by the sync engine; see RecordKind.
+    Record(RecordKind),
+}
+
+// One kind of thing an activity can carry. Every enabled handler that accepts an
+// activity runs on it, so an activity with both a photo and a video saves both.
+pub trait ActivityHandler: Send + Sync {
+    // Stable id, used in settings.
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    fn accepts(&self, activity: &Activity) -> bool;
+
+    fn outputs(&self, activity: &Activity) -> Vec<HandlerOutput>;
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HandlerInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub enabled: bool,
+}
+
+pub struct PhotoHandler;
+
+impl ActivityHandler for PhotoHandler {
+    fn name(&self) -> &'static str {
+        "photo"
+    }
+
+    fn description(&self) -> &'static str {
+        "Photos"
+    }
+
+    fn accepts(&self, activity: &Activity) -> bool {
+        !activity.photos().is_empty()
+    }
+
+    // A photo is named after the activity when it's the only one, or when it's the single
+    // media object, which was the only photo saved before albums; names saved then still
+    // match. Other album photos get their own media id, or failing that a hash of their
+    // URL, so names don't shift when photos are reordered or added.
+    fn outputs(&self, activity: &Activity) -> Vec<HandlerOutput> {
+        let photos = activity.photos();
+        let cover_url_opt = match activity.media.as_slice() {
+            [media] => Some(&media.image_url),
+            _ => None,
+        };
+        Vec::from_iter(photos.iter().map(|media| {
+            let name = if photos.len() == 1 || cover_url_opt == Some(&media.image_url) {
+                activity.object_id.clone()
+            }
+            else {
+                format!("{}-{}", activity.object_id, media_key(media))
+            };
+            HandlerOutput::Media {
+                filename: format_filename(&activity.created_at, &name, "jpg"),
+                url: media.image_url.clone(),
+            }
+        }))
+    }
+}
+
+// The media id, or the first 8 hex digits of a SHA-256 of the image URL without its query,
+// which brightwheel re-signs on every request.
+fn media_key(media: &Media) -> String {
+    if let Some(media_id) = &media.object_id {
+        return media_id.clone();
+    }
+    let url = media.image_url.split(['?', '#']).next().unwrap_or_default();
+    String::from_iter(format!("{:x}", Sha256::digest(url.as_bytes())).chars().take(8))
+}
+
+pub struct VideoHandler;
+
+impl ActivityHandler for VideoHandler {
+    fn name(&self) -> &'static str {
+        "video"
+    }
+
+    fn description(&self) -> &'static str {
+        "Videos"
+    }
+
+    fn accepts(&self, activity: &Activity) -> bool {
+        activity.video_info.is_some()
+    }
+
+    fn outputs(&self, activity: &Activity) -> Vec<HandlerOutput> {
+        Vec::from_iter(activity.video_info.iter().map(|video_info| HandlerOutput::Media {
+            filename: format_filename(&activity.created_at, &activity.object_id, "mp4"),
+            url: video_info.downloadable_url.clone(),
+        }))
+    }
+}
+
+// Notes posted on their own; notes on photos and videos already go in their sidecars.
+pub struct NoteHandler;
+
+impl ActivityHandler for NoteHandler {
+    fn name(&self) -> &'static str {
+        "note"
+    }
+
+    fn description(&self) -> &'static str {
+        "Notes from teachers"
+    }
+
+    fn accepts(&self, activity: &Activity) -> bool {
+        activity.action_type.as_deref() == Some("ac_note")
+            && activity.note.as_deref().is_some_and(|note| !note.trim().is_empty())
+    }
+
+    fn outputs(&self, activity: &Activity) -> Vec<HandlerOutput> {
+        Vec::from_iter(activity.note.iter().map(|note| HandlerOutput::Text {
+            filename: format_filename(&activity.created_at, &activity.object_id, "txt"),
+            contents: format!("{}\n", note.trim()),
+        }))
+    }
+}
+
+// Care activities, whose details are in the day's daily report.
+pub struct DailyReportHandler {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub action_types: &'static [&'static str],
+}
+
+impl ActivityHandler for DailyReportHandler {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.description
+    }
+
+    fn accepts(&self, activity: &Activity) -> bool {
+        activity.action_type.as_deref().is_some_and(|action_type| self.action_types.contains(&action_type))
+    }
+
+    fn outputs(&self, _activity: &Activity) -> Vec<HandlerOutput> {
+        vec![HandlerOutput::Record(RecordKind::DailyReport)]
+    }
+}
+
+pub struct ObservationHandler;
+
+impl ActivityHandler for ObservationHandler {
+    fn name(&self) -> &'static str {
+        "observation"
+    }
+
+    fn description(&self) -> &'static str {
+        "Learning observations, milestones and assessments"
+    }
+
+    fn accepts(&self, activity: &Activity) -> bool {
+        matches!(
+            activity.action_type.as_deref(),
+            Some("ac_observation" | "ac_learning" | "ac_assessment" | "ac_milestone")
+        )
+    }
+
+    fn outputs(&self, _activity: &Activity) -> Vec<HandlerOutput> {
+        vec![HandlerOutput::Record(RecordKind::Observation)]
+    }
+}
+
+// Every handler, in the order they run and are listed in settings.
+pub fn all() -> Vec<Box<dyn ActivityHandler>> {
+    vec![
+        Box::new(PhotoHandler),
+        Box::new(VideoHandler),
+        Box::new(NoteHandler),
+        Box::new(DailyReportHandler { name: "nap", description: "Daily reports for naps", action_types: &["ac_nap"] }),
+        Box::new(DailyReportHandler {
+            name: "meal", description: "Daily reports for meals and bottles", action_types: &["ac_food", "ac_bottle"],
+        }),
+        Box::new(DailyReportHandler {
+            name: "diaper", description: "Daily reports for diapers and potty", action_types: &["ac_diaper", "ac_potty"],
+        }),
+        Box::new(DailyReportHandler {
+            name: "wellness", description: "Daily reports for moods and health checks",
+            action_types: &["ac_mood", "ac_health_check"],
+        }),
+        Box::new(ObservationHandler),
+    ]
+}
+
+// The handlers not switched off in settings.
+pub fn enabled(disabled: &[String]) -> Vec<Box<dyn ActivityHandler>> {
+    Vec::from_iter(all().into_iter().filter(|handler| !disabled.iter().any(|name| name == handler.name())))
+}
+
+pub fn describe(disabled: &[String]) -> Vec<HandlerInfo> {
+    Vec::from_iter(all().iter().map(|handler| HandlerInfo {
+        name: handler.name(),
+        description: handler.description(),
+        enabled: !disabled.iter().any(|name| name == handler.name()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::brightwheel::Aliased;
+
+    fn photo_names(media: serde_json::Value) -> Vec<(String, String)> {
+        let activity = Activity::from_json(json!({
+            "object_id": "a1", "created_at": "2024-05-17T09:30:12Z", "action_type": "ac_photo", "media": media,
+        })).unwrap();
+        let mut names = Vec::from_iter(PhotoHandler.outputs(&activity).into_iter().map(|output| match output {
+            HandlerOutput::Media { filename, url } => (url, filename),
+            _ => unreachable!(),
+        }));
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn album_photo_names_survive_reordering_and_additions() {
+        let a = json!({ "image_url": "https://cdn.example.com/a.jpg?sig=1" });
+        let b = json!({ "object_id": "m2", "image_url": "https://cdn.example.com/b.jpg" });
+        let c = json!({ "image_url": "https://cdn.example.com/c.jpg" });
+        let d = json!({ "image_url": "https://cdn.example.com/d.jpg" });
+
+        let names = photo_names(json!([a, b, c]));
+        assert_eq!(names[1].1, "2024-05-17-093012-a1-m2.jpg");
+        assert!(names[0].1.starts_with("2024-05-17-093012-a1-") && names[0].1.len() == "2024-05-17-093012-a1-".len() + 12);
+        assert_ne!(names[0].1, names[2].1);
+
+        let reordered = photo_names(json!([d, c, a, b]));
+        for name in &names {
+            assert!(reordered.contains(name), "{:?} was renamed", name);
+        }
+
+        // The signature in the URL doesn't change the name.
+        let mut resigned_a = a.clone();
+        resigned_a["image_url"] = json!("https://cdn.example.com/a.jpg?sig=2");
+        assert_eq!(photo_names(json!([c, b, resigned_a]))[0].1, names[0].1);
+    }
+
+    #[test]
+    fn single_photos_keep_the_activity_name() {
+        let expected = "2024-05-17-093012-a1.jpg";
+        assert_eq!(photo_names(json!({ "image_url": "https://cdn.example.com/a.jpg" }))[0].1, expected);
+        assert_eq!(photo_names(json!([{ "object_id": "m1", "image_url": "https://cdn.example.com/a.jpg" }]))[0].1, expected);
+    }
+}
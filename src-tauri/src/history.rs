@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+const HISTORY_PATH: &str = "sync_history.json";
+const WATERMARKS_PATH: &str = "sync_watermarks.json";
+
+// Oldest runs are dropped once the history grows past this.
+const MAX_RUNS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SyncRun {
+    pub started_at: Timestamp,
+    pub finished_at: Option<Timestamp>,
+    pub account: Option<String>,
+    pub students: Vec<String>,
+    pub new_items: usize,
+    pub skipped_items: usize,
+    pub failed_items: usize,
+    pub bytes_transferred: u64,
+    #[serde(default)]
+    pub uploaded_items: usize,
+    #[serde(default)]
+    pub bytes_uploaded: u64,
+    // Daily reports and observations saved or updated.
+    #[serde(default)]
+    pub new_records: usize,
+    pub errors: Vec<String>,
+}
+
+impl SyncRun {
+    pub fn start() -> SyncRun {
+        SyncRun {
+            started_at: Timestamp::now(),
+            finished_at: None,
+            account: None,
+            students: Vec::new(),
+            new_items: 0,
+            skipped_items: 0,
+            failed_items: 0,
+            bytes_transferred: 0,
+            uploaded_items: 0,
+            bytes_uploaded: 0,
+            new_records: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.finished_at = Some(Timestamp::now());
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = format!("{} new, {} already downloaded", self.new_items, self.skipped_items);
+        if self.failed_items > 0 {
+            summary.push_str(&format!(", {} failed", self.failed_items));
+        }
+        if self.uploaded_items > 0 {
+            summary.push_str(&format!(", {} uploaded", self.uploaded_items));
+        }
+        if self.new_records > 0 {
+            summary.push_str(&format!(", {} reports and observations", self.new_records));
+        }
+        summary
+    }
+}
+
+pub fn load_history() -> Vec<SyncRun> {
+    if let Ok(file) = std::fs::File::open(HISTORY_PATH).map(std::io::BufReader::new) {
+        match serde_json::from_reader(file) {
+            Ok(runs) => runs,
+            Err(e) => {
+                warn!("Could not parse {}: {}; starting a new history", HISTORY_PATH, e);
+                Vec::new()
+            }
+        }
+    }
+    else {
+        Vec::new()
+    }
+}
+
+pub fn append_run(run: &SyncRun) {
+    let mut runs = load_history();
+    runs.push(run.clone());
+    if runs.len() > MAX_RUNS {
+        runs.drain(..runs.len() - MAX_RUNS);
+    }
+
+    let mut writer = std::fs::File::create(HISTORY_PATH)
+        .map(std::io::BufWriter::new)
+        .unwrap();
+    serde_json::to_writer_pretty(&mut writer, &runs).unwrap();
+}
+
+// Newest activity synced for each student, keyed by the student's object_id.
+pub fn load_watermarks() -> BTreeMap<String, Timestamp> {
+    if let Ok(file) = std::fs::File::open(WATERMARKS_PATH).map(std::io::BufReader::new) {
+        match serde_json::from_reader(file) {
+            Ok(watermarks) => watermarks,
+            Err(e) => {
+                warn!("Could not parse {}: {}; syncing everything", WATERMARKS_PATH, e);
+                BTreeMap::new()
+            }
+        }
+    }
+    else {
+        BTreeMap::new()
+    }
+}
+
+pub fn save_watermarks(watermarks: &BTreeMap<String, Timestamp>) {
+    let mut writer = std::fs::File::create(WATERMARKS_PATH)
+        .map(std::io::BufWriter::new)
+        .unwrap();
+    serde_json::to_writer_pretty(&mut writer, watermarks).unwrap();
+}
@@ -1,414 +1,507 @@
 
+mod auth;
 pub mod brightwheel;
-
-use std::{fs, ops::Deref, path::{Path, PathBuf}, sync::{Arc, Mutex}};
-
-use jiff::{civil::Time, Timestamp};
-use reqwest_cookie_store::CookieStoreMutex;
+mod catalog;
+mod export;
+mod gallery;
+mod handlers;
+mod history;
+mod library;
+mod logging;
+mod records;
+mod scheduler;
+mod settings;
+mod storage;
+mod sync;
+mod tray;
+mod webdav;
+
+use std::{collections::{BTreeMap, HashSet}, fs, ops::Deref, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+
+use jiff::{tz::TimeZone, Timestamp};
 use serde::Serialize;
-use serde_json::{Map, Value};
-use tauri::{Builder, Manager, State};
-
-use crate::brightwheel::{BrightwheelClient, Student};
+use serde_json::Value;
+use tauri::{async_runtime, AppHandle, Builder, Manager, State, WindowEvent};
+use tauri_plugin_notification::NotificationExt;
+use tracing::{info, info_span, warn};
+use zeroize::Zeroizing;
+
+use crate::auth::{AppState, LoggedInState, MfaMethod, MfaStatus, StartState, COOKIES_PATH};
+use crate::brightwheel::{BlockingBrightwheelClient, UserProfile};
+use crate::export::{ExportSummary, ImporterExportSummary, ImporterTarget};
+use crate::handlers::HandlerInfo;
+use crate::history::SyncRun;
+use crate::library::LibraryStudent;
+use crate::logging::{redact_json, LogHandle};
+use crate::scheduler::SyncStatus;
+use crate::settings::{Schedule, Settings, SettingsView};
+use crate::sync::{DryRunReport, SyncContext, SyncScope};
+use crate::storage::LibraryLocation;
+use crate::webdav::{WebDavClient, WebDavSettings};
 
 fn to_json_debug<S: Serialize>(x: &S) -> String {
     serde_json::to_string_pretty(x).unwrap()
 }
 
+fn to_redacted_json_debug(x: &Value) -> String {
+    to_json_debug(&redact_json(x))
+}
+
 struct OuterAppState {
   state_opt: Option<AppState>,
 }
 
-enum AppState {
-    Start(StartState),
-    NeedsMfa(NeedsMfaState),
-    LoggedIn(LoggedInState),
-    Error(String),
+#[tauri::command]
+async fn init_view(app: AppHandle) -> LoginResult {
+    let state_mutex = app.state::<async_runtime::Mutex<OuterAppState>>();
+    let outer_state = state_mutex.lock().await;
+    LoginResult::from_state(&outer_state.state_opt)
 }
 
-struct StartState {
-    bw_client: BrightwheelClient
+#[derive(Serialize)]
+struct LoginResult {
+    message: Option<String>,
+    tab_name: String,
+    // Set while waiting for a verification code.
+    mfa: Option<MfaStatus>,
 }
 
-fn complete_login(mut bw_client: BrightwheelClient, email: &str, password: &str, mfa_code_opt: Option<&str>) -> AppState {
-    let response = bw_client.post_sessions(email, password, mfa_code_opt);
-    let response_json = response.json::<serde_json::Value>().unwrap();
-    println!("/sessions response_json: {}\n", serde_json::to_string(&response_json).unwrap());
-    match response_json {
-        serde_json::Value::Object(response_obj) => {
-            write_cookies(&bw_client.cookie_store_arc_mutex);
-
-            AppState::LoggedIn(LoggedInState { bw_client })
-        },
-        _ => {
-            AppState::Error("received non-object response from brightwheel login endpoint".into())
+impl LoginResult {
+    fn from_state(state_opt: &Option<AppState>) -> LoginResult {
+        if let Some(state) = state_opt {
+            LoginResult {
+                message: state.message().map(String::from),
+                tab_name: state.tab_name().into(),
+                mfa: state.mfa_status(),
+            }
+        }
+        else {
+            LoginResult {
+                message: Some("outer state is empty for some reason?".into()),
+                tab_name: "login".into(),
+                mfa: None,
+            }
         }
     }
 }
 
-fn write_cookies(cookie_store_arc_mutex: &Arc<CookieStoreMutex>) {
-    let mut writer = std::fs::File::create("cookies.json")
-      .map(std::io::BufWriter::new)
-      .unwrap();
-    cookie_store_arc_mutex.lock().unwrap().save_json(&mut writer);
-}
-
-impl StartState {
-    fn login(self, email: &str, password: &str) -> AppState {
-        let bw_client = self.bw_client;
-
-        let response = bw_client.post_sessions_start(email, password);
-        let response_json = response.json::<serde_json::Value>().unwrap();
-        println!("/sessions/start response_json: {}\n", serde_json::to_string(&response_json).unwrap());
-
-        match response_json {
-            serde_json::Value::Object(response_obj) => {
-                if let Some(mfa_required_val) = response_obj.get("2fa_required") {
-                    if let Some(mfa_required) = mfa_required_val.as_bool() {
-                        if mfa_required {
-                            AppState::NeedsMfa(NeedsMfaState { bw_client })
-                        }
-                        else {
-                            complete_login(bw_client, email, password, None)
-                        }
-                    }
-                    else {
-                        AppState::Error("2fa_required is not a bool???".into())
-                    }
-                }
-                else {
-                    // TODO: this might actually be a login failure
-                    AppState::LoggedIn(LoggedInState { bw_client })
-                }
-            }
-            _ => {
-                AppState::Error("received non-object response from brightwheel login endpoint".into())
-            }
+// Logging in again from an error or the MFA step starts the flow over.
+#[tauri::command]
+async fn login(app: AppHandle, email: String, password: String) -> LoginResult {
+    let (email, password) = (Zeroizing::new(email), Zeroizing::new(password));
+    let state_mutex = app.state::<async_runtime::Mutex<OuterAppState>>();
+    let mut outer_state = state_mutex.lock().await;
+
+    if let Some(state) = outer_state.state_opt.take() {
+        let state = state.login(&email, &password).await;
+        if let Some(wipe_at) = state.pending_wipe_at() {
+            spawn_pending_login_expiry(app.clone(), wipe_at);
         }
-        
+        outer_state.state_opt = Some(state);
     }
+    LoginResult::from_state(&outer_state.state_opt)
 }
 
-struct NeedsMfaState {
-    bw_client: BrightwheelClient
+// Wipes the credentials held for the MFA step if it's still waiting at wipe_at. A
+// later login has a later deadline, so an older timer finding it does nothing.
+fn spawn_pending_login_expiry(app: AppHandle, wipe_at: Timestamp) {
+    async_runtime::spawn(async move {
+        tokio::time::sleep(Duration::try_from(wipe_at.duration_since(Timestamp::now())).unwrap_or_default()).await;
+        let state_mutex = app.state::<async_runtime::Mutex<OuterAppState>>();
+        let mut outer_state = state_mutex.lock().await;
+        outer_state.state_opt = outer_state.state_opt.take().map(|state| state.expire_pending(Timestamp::now()));
+    });
 }
 
-impl NeedsMfaState {
-    fn complete_login(self, email: &str, password: &str, mfa_code: &str) -> AppState {
-        let bw_client = self.bw_client;
-        complete_login(bw_client, email, password, Some(mfa_code))
-    }
-}
-struct LoggedInState {
-    bw_client: BrightwheelClient
-}
+#[tauri::command]
+async fn login_mfa(app: AppHandle, mfa_code: String) -> LoginResult {
+    info!("login_mfa");
+    let state_mutex = app.state::<async_runtime::Mutex<OuterAppState>>();
+    let mut outer_state = state_mutex.lock().await;
 
-#[derive(Serialize)]
-struct InitViewResult {
-    tab_name: String,
+    if let Some(state) = outer_state.state_opt.take() {
+        outer_state.state_opt = Some(state.login_mfa(mfa_code.trim()).await);
+    }
+    LoginResult::from_state(&outer_state.state_opt)
 }
 
+// Asks brightwheel for a new verification code, by method if given.
 #[tauri::command]
-fn init_view(state_mutex: State<'_, Mutex<OuterAppState>>) -> InitViewResult {
-    let outer_state = state_mutex.lock().unwrap();
-    let tab_name = if let Some(state) = &outer_state.state_opt {
-        match state {
-            AppState::Start(_) => "login",
-            AppState::NeedsMfa(_) => "mfa",
-            AppState::LoggedIn(_) => "loggedin",
-            AppState::Error(_) => "login",
-        }
+async fn resend_mfa_code(app: AppHandle, method: Option<MfaMethod>) -> LoginResult {
+    info!("resend_mfa_code");
+    let state_mutex = app.state::<async_runtime::Mutex<OuterAppState>>();
+    let mut outer_state = state_mutex.lock().await;
+
+    if let Some(state) = outer_state.state_opt.take() {
+        outer_state.state_opt = Some(state.resend_mfa_code(method).await);
     }
-    else {
-        "login"
-    };
-    InitViewResult { tab_name: tab_name.into() }
+    LoginResult::from_state(&outer_state.state_opt)
 }
 
-#[derive(Serialize)]
-struct LoginResult {
-    message: Option<String>,
-    tab_name: String,
+// Backs out of the MFA step or an error to the login form.
+#[tauri::command]
+async fn restart_login(app: AppHandle) -> LoginResult {
+    let state_mutex = app.state::<async_runtime::Mutex<OuterAppState>>();
+    let mut outer_state = state_mutex.lock().await;
+
+    outer_state.state_opt = outer_state.state_opt.take().map(AppState::restart);
+    LoginResult::from_state(&outer_state.state_opt)
 }
 
-// Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
+// The signed-in account's profile, which is also recorded in the library's catalog.
 #[tauri::command]
-fn login(state_mutex: State<'_, Mutex<OuterAppState>>, email: &str, password: &str) -> LoginResult {
-    let mut outer_state = state_mutex.lock().unwrap();
-
-    outer_state.state_opt = Some(if let Some(state) = outer_state.state_opt.take() {
-        match state {
-            AppState::Start(start_state) => {
-                start_state.login(email, password)
-            },
-            _ => AppState::Error("wrong state for login".into())
+async fn get_profile(app: AppHandle) -> Result<UserProfile, String> {
+    let bw_client = match &app.state::<async_runtime::Mutex<OuterAppState>>().lock().await.state_opt {
+        Some(AppState::LoggedIn(logged_in_state)) => logged_in_state.bw_client.clone(),
+        _ => return Err("not logged in".into()),
+    };
+    let profile = bw_client.get_profile().await?;
+
+    let settings = app.state::<Mutex<Settings>>().lock().unwrap().clone();
+    let catalog_profile = profile.clone();
+    async_runtime::spawn_blocking(move || {
+        if let Err(msg) = settings.library.open()
+            .and_then(|storage| catalog::update_account(storage.as_ref(), &catalog_profile, None, BTreeMap::new())) {
+            warn!("could not update the library catalog: {}", msg);
         }
-    }
-    else {
-        AppState::Error("outer state is empty for some reason?".into())
     });
-
-    if let Some(state) = &outer_state.state_opt {
-        match state {
-            AppState::Error(msg) => LoginResult {
-                message: Some(msg.clone()),
-                tab_name: "login".into(),
-            },
-            AppState::NeedsMfa(_) => LoginResult {
-                message: None,
-                tab_name: "mfa".into(),
-            },
-            AppState::LoggedIn(_) => LoginResult {
-                message: None,
-                tab_name: "loggedin".into(),
-            },
-            _ => LoginResult {
-                message: None,
-                tab_name: "login".into(),
-            }
-        }
-    }
-    else {
-        LoginResult {
-            message: Some("outer state is empty for some reason?".into()),
-            tab_name: "login".into(),
-        }
-    }
+    Ok(profile)
 }
 
-#[derive(Serialize)]
-struct LoginMfaResult {
+#[derive(Serialize, Default)]
+struct SyncResult {
     message: Option<String>,
-    tab_name: String,
+    run: Option<SyncRun>,
+    report: Option<DryRunReport>,
 }
 
-// Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
+// The sync engine is blocking, so it runs on a blocking thread and drives the async
+// client through BlockingBrightwheelClient.
 #[tauri::command]
-fn login_mfa(state_mutex: State<'_, Mutex<OuterAppState>>, email: &str, password: &str, mfa_code: &str) -> LoginMfaResult {
-    println!("login_mfa({}, ***, {})", email, mfa_code);
-    let mut outer_state = state_mutex.lock().unwrap();
-
-    outer_state.state_opt = Some(if let Some(state) = outer_state.state_opt.take() {
-        match state {
-            AppState::NeedsMfa(needs_mfa_state) => {
-                needs_mfa_state.complete_login(email, password, mfa_code)
-            },
-            _ => AppState::Error("wrong state for login_mfa".into())
+async fn sync(app: AppHandle, dry_run: Option<bool>, scope: Option<SyncScope>) -> SyncResult {
+    let scope = scope.unwrap_or_default();
+    async_runtime::spawn_blocking(move || {
+        if dry_run.unwrap_or(false) {
+            let settings = app.state::<Mutex<Settings>>().lock().unwrap().clone();
+            run_sync(&app.state::<async_runtime::Mutex<OuterAppState>>(), &settings, true, scope)
         }
-    }
-    else {
-        AppState::Error("outer state is empty for some reason?".into())
-    });
+        else {
+            sync_and_report(&app, scope)
+        }
+    }).await.unwrap_or_else(|e| SyncResult {
+        message: Some(format!("sync failed: {}", e)),
+        ..Default::default()
+    })
+}
 
-    if let Some(state) = &outer_state.state_opt {
-        match state {
-            AppState::Error(msg) => LoginMfaResult {
-                message: Some(msg.clone()),
-                tab_name: "mfa".into(),
-            },
-            AppState::LoggedIn(_) => LoginMfaResult {
-                message: None,
-                tab_name: "loggedin".into(),
-            },
-            _ => LoginMfaResult {
-                message: None,
-                tab_name: "mfa".into(),
-            }
+// Runs a sync and publishes the outcome to the sync status, the tray, and (if anything
+// new arrived) a desktop notification. Used by the Sync button, the tray, and the scheduler.
+// Blocks, so it must not be called from an async task.
+fn sync_and_report(app: &AppHandle, scope: SyncScope) -> SyncResult {
+    {
+        let status_mutex = app.state::<Mutex<SyncStatus>>();
+        let mut status = status_mutex.lock().unwrap();
+        if status.running {
+            return SyncResult {
+                message: Some("a sync is already running".into()),
+                ..Default::default()
+            };
         }
+        status.running = true;
+    }
+    tray::set_status(app, "Syncing...");
+
+    let settings = app.state::<Mutex<Settings>>().lock().unwrap().clone();
+    let result = run_sync(&app.state::<async_runtime::Mutex<OuterAppState>>(), &settings, false, scope);
+
+    let message = if let Some(msg) = &result.message {
+        msg.clone()
+    }
+    else if let Some(run) = &result.run {
+        run.summary()
     }
     else {
-        LoginMfaResult {
-            message: Some("outer state is empty for some reason?".into()),
-            tab_name: "mfa".into(),
+        "nothing synced".into()
+    };
+    let now = Timestamp::now();
+    {
+        let status_mutex = app.state::<Mutex<SyncStatus>>();
+        let mut status = status_mutex.lock().unwrap();
+        status.running = false;
+        status.last_sync = Some(now);
+        status.last_message = Some(message.clone());
+    }
+    tray::set_status(app, &format!(
+        "Last sync: {} ({})", now.to_zoned(TimeZone::system()).strftime("%F %H:%M"), message
+    ));
+
+    let new_items = result.run.as_ref().map_or(0, |run| run.new_items);
+    if new_items > 0 {
+        if let Err(e) = app.notification()
+            .builder()
+            .title("shinydisc")
+            .body(format!("{} new photos and videos from brightwheel", new_items))
+            .show() {
+            warn!("could not show notification: {}", e);
         }
     }
-}
 
-#[derive(Serialize)]
-struct SyncResult {
-    user_id: Option<String>,
+    result
 }
 
-// Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
-#[tauri::command]
-fn sync(state_mutex: State<'_, Mutex<OuterAppState>>) -> SyncResult {
-    if let AppState::LoggedIn(logged_in_state) = state_mutex.lock().unwrap().state_opt.as_ref().unwrap() {
-        let bw_client = &logged_in_state.bw_client;
-        
-        let user_id = bw_client.get_user_id();
-        println!("got user_id: {}", user_id);
-
-        let students = bw_client.get_students(&user_id);
-        for student in &students {
-            sync_student(bw_client, student)
+// A dry run walks every activity page but leaves the library, WebDAV and the sync
+// history alone, and returns a DryRunReport instead of a SyncRun.
+fn run_sync(
+    state_mutex: &async_runtime::Mutex<OuterAppState>, settings: &Settings, dry_run: bool, scope: SyncScope,
+) -> SyncResult {
+    let bw_client_opt = match &state_mutex.blocking_lock().state_opt {
+        Some(AppState::LoggedIn(logged_in_state)) => Some(logged_in_state.bw_client.clone()),
+        _ => None,
+    };
+    if let Some(bw_client) = bw_client_opt {
+        let api = BlockingBrightwheelClient::new(bw_client, async_runtime::handle().inner().clone());
+        let storage = match settings.library.open() {
+            Ok(storage) => storage,
+            Err(msg) => {
+                return SyncResult {
+                    message: Some(format!("could not open library: {}", msg)),
+                    ..Default::default()
+                };
+            }
+        };
+        let mut ctx = SyncContext {
+            api: &api,
+            storage: storage.as_ref(),
+            run: SyncRun::start(),
+            webdav_opt: None,
+            report_opt: dry_run.then(DryRunReport::default),
+            scope,
+            watermarks: history::load_watermarks(),
+            page_size: settings.page_size.unwrap_or(brightwheel::DEFAULT_PAGE_SIZE),
+            fetched_records: HashSet::new(),
+            handlers: handlers::enabled(&settings.disabled_handlers),
+            saved_items: BTreeMap::new(),
+        };
+        let _span = info_span!("sync", started_at = %ctx.run.started_at).entered();
+
+        if settings.webdav.enabled && !dry_run {
+            match WebDavClient::new(&settings.webdav) {
+                Ok(webdav) => ctx.webdav_opt = Some(webdav),
+                Err(msg) => ctx.run.errors.push(msg),
+            }
+        }
+
+        sync::sync_account(&mut ctx);
+
+        if let Some(mut report) = ctx.report_opt {
+            report.errors = ctx.run.errors;
+            return SyncResult {
+                report: Some(report),
+                ..Default::default()
+            };
         }
 
+        history::save_watermarks(&ctx.watermarks);
+        let mut run = ctx.run;
+        run.finish();
+        history::append_run(&run);
+
         SyncResult {
-            user_id: None,
+            run: Some(run),
+            ..Default::default()
         }
     }
     else {
         SyncResult {
-            user_id: None,
+            message: Some("not logged in".into()),
+            ..Default::default()
         }
     }
 }
 
-fn sync_student(bw_client: &BrightwheelClient, student: &Student) {
-    println!("sync_student: {} {}", student.first_name, student.last_name);
-
-    let student_path = PathBuf::from(format!("{} {}", student.first_name, student.last_name));
-    if !student_path.exists() {
-        std::fs::create_dir(&student_path).unwrap();
-    }
-
-    let page_size: usize = 1000;
-    let mut page: usize = 0;
+#[tauri::command]
+fn get_settings(settings_mutex: State<'_, Mutex<Settings>>) -> SettingsView {
+    settings_mutex.lock().unwrap().view()
+}
 
-    while download_activities(bw_client, student, page_size, page, &student_path) {
-        page += 1;
-    }
+#[tauri::command]
+fn set_schedule(settings_mutex: State<'_, Mutex<Settings>>, schedule: Schedule) -> SettingsView {
+    let mut settings = settings_mutex.lock().unwrap();
+    settings.schedule = schedule;
+    settings.save();
+    settings.view()
 }
 
-fn download_activities(bw_client: &BrightwheelClient, student: &Student, page_size: usize, page: usize, path: &PathBuf) -> bool {
-    println!("download_activities: {} {}, page {}", student.first_name, student.last_name, page);
-
-    let response_json = bw_client.get_students_activities(
-        &student.object_id, page_size, page
-    ).json::<Value>().unwrap();
-    let response_obj = response_json.as_object().unwrap();
-    println!("response keys: {:?}", Vec::from_iter(response_obj.keys().into_iter()));
-
-    let page = response_obj.get("page").unwrap().as_u64().unwrap() as usize;
-    let page_size = response_obj.get("page_size").unwrap().as_u64().unwrap() as usize;
-    println!("page, page_size: {}, {}", page, page_size);
-
-    let activities = response_obj.get("activities").unwrap().as_array().unwrap();
-    println!("# activities: {}", activities.len());
-    for (i, activity_val) in activities.iter().enumerate() {
-        let activity = activity_val.as_object().unwrap();
-        println!("page {}, item {}", page, i);
-        println!("activity keys: {:?}", Vec::from_iter(activity.keys().into_iter()));
-        if activity.get("media").unwrap().is_object() {
-            println!("found media");
-            download_photo(bw_client, student, path, activity);
-        }
-        else if activity.get("video_info").unwrap().is_object() {
-            println!("found video_info");
-            download_video(bw_client, student, path, activity);
-        }
-        // println!("activity keys: {:?}", Vec::from_iter(activity.keys().into_iter()));
-        // println!("activity: {:?}", activity);
+#[tauri::command]
+fn get_activity_handlers(settings_mutex: State<'_, Mutex<Settings>>) -> Vec<HandlerInfo> {
+    handlers::describe(&settings_mutex.lock().unwrap().disabled_handlers)
+}
 
-        // if(i > 10) {
-        //     break;
-        // }
+#[tauri::command]
+fn set_activity_handler_enabled(
+    settings_mutex: State<'_, Mutex<Settings>>, name: String, enabled: bool
+) -> Vec<HandlerInfo> {
+    let mut settings = settings_mutex.lock().unwrap();
+    settings.disabled_handlers.retain(|disabled| *disabled != name);
+    if !enabled {
+        settings.disabled_handlers.push(name);
     }
-
-    activities.len() == page_size
+    settings.save();
+    handlers::describe(&settings.disabled_handlers)
 }
 
-fn download_photo(bw_client: &BrightwheelClient, student: &Student, path: &PathBuf, activity: &Map<String, Value>) {
-    let timestamp = get_created_at(activity);
-    let object_id = get_object_id(activity);
-    let month_path = create_month_path(path, &timestamp);
-    let photo_info = activity.get("media").unwrap().as_object().unwrap();
-    // println!("{}\n", to_json_debug(photo_info));
-
-    let src_url = reqwest::Url::parse(photo_info.get("image_url").unwrap().as_str().unwrap()).unwrap();
-    let filename = format_filename(&timestamp, &object_id, "jpg");
-    let dst_path = month_path.join(filename);
-
-    println!("{:?}", dst_path);
-    if dst_path.exists() {
-        println!("...already exists; skipping");
-    }
-    else {
-        println!("...downloading...");
-        bw_client.download_file(&src_url, &dst_path);
-        println!("...done.");
-    }
+#[tauri::command]
+fn set_webdav_settings(settings_mutex: State<'_, Mutex<Settings>>, webdav: WebDavSettings) -> SettingsView {
+    let mut settings = settings_mutex.lock().unwrap();
+    settings.set_webdav(webdav);
+    settings.save();
+    settings.view()
 }
 
-fn download_video(bw_client: &BrightwheelClient, student: &Student, path: &PathBuf, activity: &Map<String, Value>) {
-    let timestamp = get_created_at(activity);
-    let object_id = get_object_id(activity);
-    let month_path = create_month_path(path, &timestamp);
-    let video_info = activity.get("video_info").unwrap().as_object().unwrap();
-    println!("{}\n", to_json_debug(video_info));
+#[tauri::command]
+fn set_library_location(
+    settings_mutex: State<'_, Mutex<Settings>>, library: LibraryLocation
+) -> Result<SettingsView, String> {
+    let library = settings_mutex.lock().unwrap().with_saved_secret(library);
+    library.open()?;
+    let mut settings = settings_mutex.lock().unwrap();
+    settings.library = library;
+    settings.save();
+    Ok(settings.view())
+}
 
-    let src_url = reqwest::Url::parse(video_info.get("downloadable_url").unwrap().as_str().unwrap()).unwrap();
-    let filename = format_filename(&timestamp, &object_id, "mp4");
-    let dst_path = month_path.join(filename);
+#[tauri::command]
+fn get_sync_history() -> Vec<SyncRun> {
+    let mut runs = history::load_history();
+    runs.reverse();
+    runs
+}
 
-    println!("{:?}", dst_path);
-    if dst_path.exists() {
-        println!("...already exists; skipping");
-    }
-    else {
-        println!("...downloading...");
-        bw_client.download_file(&src_url, &dst_path);
-        println!("...done.");
-    }
+#[tauri::command]
+fn get_sync_status(status_mutex: State<'_, Mutex<SyncStatus>>) -> SyncStatus {
+    status_mutex.lock().unwrap().clone()
 }
 
+#[tauri::command]
+fn set_debug_logging(
+    settings_mutex: State<'_, Mutex<Settings>>, log_handle: State<'_, LogHandle>, enabled: bool
+) -> SettingsView {
+    let mut settings = settings_mutex.lock().unwrap();
+    settings.debug_logging = enabled;
+    settings.save();
+    log_handle.set_debug(enabled);
+    info!("debug logging {}", if enabled { "enabled" } else { "disabled" });
+    settings.view()
+}
 
-fn format_filename(timestamp: &Timestamp, object_id: &str, extension: &str) -> String {
-    format!("{}-{}.{}", timestamp.strftime("%F-%H%M%S").to_string(), object_id, extension)
+// The gallery and exports read the library straight from disk, so they need a local library.
+fn local_library_root(app: &AppHandle) -> Result<PathBuf, String> {
+    app.state::<Mutex<Settings>>().lock().unwrap().library.local_root()
+        .ok_or_else(|| "the gallery and exports need a local library folder".into())
 }
 
-fn get_object_id(obj: &Map<String, Value>) -> String {
-    obj.get("object_id").unwrap().as_str().unwrap().into()
+#[tauri::command]
+fn list_library(app: AppHandle) -> Result<Vec<LibraryStudent>, String> {
+    Ok(library::scan_library(&local_library_root(&app)?))
 }
 
-fn get_created_at(obj: &Map<String, Value>) -> Timestamp {
-    obj.get("created_at").unwrap().as_str().unwrap().parse().unwrap()
+#[tauri::command]
+async fn export_album(app: AppHandle, student_name: String, dest_dir: String, zip: bool) -> Result<ExportSummary, String> {
+    let root = local_library_root(&app)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        export::export_album(&root, &student_name, Path::new(&dest_dir), zip)
+    }).await.map_err(|e| e.to_string())?
 }
 
-fn get_month_path(path: &PathBuf, ts: &Timestamp) -> PathBuf {
-    let month_str = ts.strftime("%Y-%m").to_string();
-    path.join(month_str)
+#[tauri::command]
+async fn export_for_importer(app: AppHandle, target: ImporterTarget, dest_dir: String) -> Result<ImporterExportSummary, String> {
+    let root = local_library_root(&app)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        export::export_for_importer(&root, target, Path::new(&dest_dir))
+    }).await.map_err(|e| e.to_string())?
 }
 
-fn create_month_path(path: &PathBuf, ts: &Timestamp) -> PathBuf {
-    let month_path = get_month_path(path, ts);
-    if !month_path.exists() {
-        std::fs::create_dir(&month_path).unwrap();
+fn load_start_state() -> AppState {
+    if let Ok(file) = std::fs::File::open(COOKIES_PATH)
+        .map(std::io::BufReader::new) {
+        info!("Opened cookies.json");
+
+        AppState::LoggedIn(LoggedInState {
+            bw_client: Arc::new(brightwheel::BrightwheelClient::new(
+                reqwest_cookie_store::CookieStore::load_json(file).unwrap()
+            ))
+        })
+    }
+    else
+    {
+        info!("No cookies.json; using default cookie store");
+        AppState::Start(StartState {
+            bw_client: brightwheel::BrightwheelClient::new(reqwest_cookie_store::CookieStore::default())
+        })
     }
-    month_path
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let start_state = {
-        if let Ok(file) = std::fs::File::open("cookies.json")
-            .map(std::io::BufReader::new) {
-            println!("Opened cookies.json");
-
-            AppState::LoggedIn(LoggedInState {
-                bw_client: brightwheel::BrightwheelClient::new(
-                    reqwest_cookie_store::CookieStore::load_json(file).unwrap()
-                )
-            })
-        }
-        else
-        {
-            println!("No cookies.json; using default cookie store");
-            AppState::Start(StartState {
-                bw_client: brightwheel::BrightwheelClient::new(reqwest_cookie_store::CookieStore::default())
-            })
-        }
-    };
-
     Builder::default()
         .setup(|app| {
-            app.manage(Mutex::new(OuterAppState {
-                state_opt: Some(start_state)
+            let settings = Settings::load();
+            let log_dir = app.path().app_log_dir()?;
+            std::fs::create_dir_all(&log_dir)?;
+            app.manage(logging::init(&log_dir, settings.debug_logging));
+            info!("logging to {:?}", log_dir);
+
+            app.manage(async_runtime::Mutex::new(OuterAppState {
+                state_opt: Some(load_start_state())
+            }));
+            app.manage(Mutex::new(settings));
+            app.manage(Mutex::new(SyncStatus {
+                last_sync: history::load_history().last().and_then(|run| run.finished_at),
+                ..Default::default()
             }));
+            tray::create(app)?;
+            scheduler::spawn(app.handle().clone());
             Ok(())            
         })
+        .on_window_event(|window, event| {
+            // Closing the window minimizes to the tray so scheduled syncs keep running;
+            // the tray's Quit item exits the app.
+            if let WindowEvent::CloseRequested { api, .. } = event {
+                if let Err(e) = window.hide() {
+                    warn!("could not hide main window: {}", e);
+                }
+                api.prevent_close();
+            }
+        })
+        // File reads and thumbnailing run on the runtime's blocking pool, which bounds how
+        // many run at once however many images the gallery asks for.
+        .register_asynchronous_uri_scheme_protocol("library", |ctx, request, responder| {
+            let root_result = local_library_root(ctx.app_handle());
+            tauri::async_runtime::spawn_blocking(move || {
+                responder.respond(match root_result {
+                    Ok(root) => gallery::handle_media_request(&root, &request),
+                    Err(_) => gallery::not_found_response(),
+                });
+            });
+        })
+        .register_asynchronous_uri_scheme_protocol("thumb", |ctx, request, responder| {
+            let root_result = local_library_root(ctx.app_handle());
+            tauri::async_runtime::spawn_blocking(move || {
+                responder.respond(match root_result {
+                    Ok(root) => gallery::handle_thumbnail_request(&root, &request),
+                    Err(_) => gallery::not_found_response(),
+                });
+            });
+        })
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![init_view, login, login_mfa, sync])
+        .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_notification::init())
+        .invoke_handler(tauri::generate_handler![
+            init_view, login, login_mfa, resend_mfa_code, restart_login, get_profile, sync, get_settings, set_schedule, get_sync_status,
+            get_sync_history, set_debug_logging, get_activity_handlers, set_activity_handler_enabled, set_webdav_settings, set_library_location,
+            list_library, export_album,
+            export_for_importer
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,241 @@
+use std::path::{Component, Path, PathBuf};
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::brightwheel::{Activity, Room, Student};
+
+// Directory under the library root holding generated files (thumbnails etc.);
+// skipped when scanning for students.
+pub const CACHE_DIR: &str = ".shinydisc";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaSidecar {
+    pub object_id: String,
+    pub created_at: Timestamp,
+    pub note: Option<String>,
+    pub action_type: Option<String>,
+}
+
+// student.json in each student folder: the student as of the last sync, plus every room
+// they have been seen in, so media can be matched to the room it was taken in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StudentProfile {
+    #[serde(flatten)]
+    pub student: Student,
+    pub updated_at: Timestamp,
+    #[serde(default)]
+    pub room_history: Vec<RoomStint>,
+    // Oldest first; the last one is the current photo.
+    #[serde(default)]
+    pub profile_photos: Vec<ProfilePhoto>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RoomStint {
+    pub room: Room,
+    pub first_seen: Timestamp,
+    pub last_seen: Timestamp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProfilePhoto {
+    // Relative to the student folder, e.g. profile/2024-05-17.jpg.
+    pub path: String,
+    // The URL it was last seen at; signed URLs change even when the photo doesn't.
+    pub url: String,
+    pub sha256: String,
+    pub saved_at: Timestamp,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    Photo,
+    Video,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LibraryItem {
+    // Path relative to the library root, always with forward slashes.
+    pub path: String,
+    pub kind: MediaKind,
+    pub taken_at: Option<Timestamp>,
+    pub caption: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LibraryMonth {
+    pub month: String,
+    pub items: Vec<LibraryItem>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LibraryStudent {
+    pub name: String,
+    pub months: Vec<LibraryMonth>,
+}
+
+impl MediaSidecar {
+    pub fn from_activity(activity: &Activity) -> MediaSidecar {
+        MediaSidecar {
+            object_id: activity.object_id.clone(),
+            created_at: activity.created_at,
+            note: activity.note.clone().filter(|note| !note.trim().is_empty()),
+            action_type: activity.action_type.clone(),
+        }
+    }
+
+    pub fn path_for(media_path: &Path) -> PathBuf {
+        media_path.with_extension("json")
+    }
+
+    // Same as path_for, for '/'-separated library paths.
+    pub fn rel_path_for(media_path: &str) -> String {
+        match media_path.rsplit_once('.') {
+            Some((stem, _)) if !stem.ends_with('/') => format!("{}.json", stem),
+            _ => format!("{}.json", media_path),
+        }
+    }
+
+    pub fn load(media_path: &Path) -> Option<MediaSidecar> {
+        let file = std::fs::File::open(Self::path_for(media_path)).ok()?;
+        serde_json::from_reader(std::io::BufReader::new(file)).ok()
+    }
+
+    pub fn to_json_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).unwrap()
+    }
+}
+
+impl StudentProfile {
+    pub fn rel_path_for(student_path: &str) -> String {
+        format!("{}/student.json", student_path)
+    }
+
+    // The profile for student as seen at now, carrying over the histories from prev_opt.
+    pub fn updated(prev_opt: Option<StudentProfile>, student: &Student, now: Timestamp) -> StudentProfile {
+        let (mut room_history, profile_photos) = prev_opt
+            .map(|prev| (prev.room_history, prev.profile_photos))
+            .unwrap_or_default();
+        for room in &student.rooms {
+            match room_history.iter_mut().rfind(|stint| stint.room.object_id == room.object_id) {
+                Some(stint) => {
+                    stint.room = room.clone();
+                    stint.last_seen = now;
+                },
+                None => room_history.push(RoomStint { room: room.clone(), first_seen: now, last_seen: now }),
+            }
+        }
+        StudentProfile { student: student.clone(), updated_at: now, room_history, profile_photos }
+    }
+
+    pub fn to_json_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec_pretty(self).unwrap()
+    }
+}
+
+pub fn media_kind(path: &Path) -> Option<MediaKind> {
+    match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" | "png" | "gif" | "heic" | "webp" => Some(MediaKind::Photo),
+        "mp4" | "mov" | "m4v" | "webm" => Some(MediaKind::Video),
+        _ => None,
+    }
+}
+
+pub fn format_filename(timestamp: &Timestamp, object_id: &str, extension: &str) -> String {
+    format!("{}-{}.{}", timestamp.strftime("%F-%H%M%S").to_string(), object_id, extension)
+}
+
+// Synced filenames look like 2024-05-17-093012-<object_id>.jpg (see format_filename).
+pub fn parse_taken_at(path: &Path) -> Option<Timestamp> {
+    let stem = path.file_stem()?.to_str()?;
+    let prefix = stem.get(..17)?;
+    let datetime = jiff::civil::DateTime::strptime("%F-%H%M%S", prefix).ok()?;
+    datetime.to_zoned(jiff::tz::TimeZone::UTC).ok().map(|zoned| zoned.timestamp())
+}
+
+fn is_month_dir_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() == 7 && bytes[4] == b'-'
+        && bytes[..4].iter().chain(&bytes[5..]).all(u8::is_ascii_digit)
+}
+
+fn sorted_dir_entries(path: &Path) -> Vec<PathBuf> {
+    let mut entries = match std::fs::read_dir(path) {
+        Ok(read_dir) => Vec::from_iter(read_dir.filter_map(|entry| entry.ok().map(|entry| entry.path()))),
+        Err(e) => {
+            warn!("could not read {:?}: {}", path, e);
+            Vec::new()
+        }
+    };
+    entries.sort();
+    entries
+}
+
+fn relative_path_string(root: &Path, path: &Path) -> String {
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    Vec::from_iter(rel.components().map(|c| c.as_os_str().to_string_lossy().into_owned())).join("/")
+}
+
+pub fn scan_month(root: &Path, month_path: &Path) -> Vec<LibraryItem> {
+    Vec::from_iter(
+        sorted_dir_entries(month_path).into_iter().filter_map(|path| {
+            let kind = media_kind(&path)?;
+            let sidecar_opt = MediaSidecar::load(&path);
+            Some(LibraryItem {
+                path: relative_path_string(root, &path),
+                kind,
+                taken_at: sidecar_opt.as_ref().map(|sidecar| sidecar.created_at).or_else(|| parse_taken_at(&path)),
+                caption: sidecar_opt.and_then(|sidecar| sidecar.note),
+            })
+        })
+    )
+}
+
+pub fn scan_student(root: &Path, student_path: &Path) -> Option<LibraryStudent> {
+    let months = Vec::from_iter(
+        sorted_dir_entries(student_path).into_iter().rev().filter_map(|path| {
+            let month = path.file_name()?.to_str()?.to_string();
+            if !path.is_dir() || !is_month_dir_name(&month) {
+                return None;
+            }
+            Some(LibraryMonth { items: scan_month(root, &path), month })
+        })
+    );
+    if months.is_empty() {
+        None
+    }
+    else {
+        Some(LibraryStudent {
+            name: student_path.file_name()?.to_string_lossy().into_owned(),
+            months,
+        })
+    }
+}
+
+// A student folder is any top-level directory that contains YYYY-MM month folders.
+pub fn scan_library(root: &Path) -> Vec<LibraryStudent> {
+    Vec::from_iter(
+        sorted_dir_entries(root).into_iter().filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            if !path.is_dir() || name.starts_with('.') {
+                return None;
+            }
+            scan_student(root, &path)
+        })
+    )
+}
+
+// Resolves a library-relative path from the webview, rejecting anything that
+// could escape the library root.
+pub fn resolve(root: &Path, rel_path: &str) -> Option<PathBuf> {
+    let rel = Path::new(rel_path.trim_start_matches('/'));
+    if rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        Some(root.join(rel))
+    }
+    else {
+        None
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::Path;
+
+use serde_json::Value;
+use tracing_appender::{non_blocking::WorkerGuard, rolling::{RollingFileAppender, Rotation}};
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry};
+
+const DEFAULT_FILTER: &str = "warn,shinydisc_lib=info";
+const DEBUG_FILTER: &str = "info,shinydisc_lib=debug";
+
+// Number of daily log files kept in the log directory.
+const MAX_LOG_FILES: usize = 14;
+
+// JSON keys whose values are never written to the log.
+const SENSITIVE_KEYS: &[&str] = &[
+    "password", "2fa_code", "token", "cookie", "set-cookie", "authorization",
+    "email", "phone", "phone_number", "address",
+];
+
+const REDACTED: &str = "[redacted]";
+
+pub struct LogHandle {
+    filter_handle: reload::Handle<EnvFilter, Registry>,
+    _guard: WorkerGuard,
+}
+
+pub fn init(log_dir: &Path, debug: bool) -> LogHandle {
+    let file_appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix("shinydisc")
+        .filename_suffix("log")
+        .max_log_files(MAX_LOG_FILES)
+        .build(log_dir)
+        .unwrap();
+    let (file_writer, guard) = tracing_appender::non_blocking(file_appender);
+
+    let (filter, filter_handle) = reload::Layer::new(make_filter(debug));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(fmt::layer().with_writer(file_writer).with_ansi(false))
+        .init();
+
+    LogHandle { filter_handle, _guard: guard }
+}
+
+impl LogHandle {
+    pub fn set_debug(&self, debug: bool) {
+        if let Err(e) = self.filter_handle.reload(make_filter(debug)) {
+            tracing::warn!("could not change log level: {}", e);
+        }
+    }
+}
+
+fn make_filter(debug: bool) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(if debug { DEBUG_FILTER } else { DEFAULT_FILTER })
+    })
+}
+
+// Strips the query string and fragment, which carry the signatures of signed media URLs.
+pub fn redact_url(url: &reqwest::Url) -> String {
+    let mut redacted = url.clone();
+    if redacted.query().is_some() {
+        redacted.set_query(Some(REDACTED));
+    }
+    redacted.set_fragment(None);
+    redacted.to_string()
+}
+
+// reqwest puts the whole request URL in its error messages, signature included. The URL
+// is kept, redacted, so errors can be logged and stored in the sync history.
+pub fn redact_error(e: reqwest::Error) -> String {
+    match e.url().map(redact_url) {
+        Some(url) => format!("{} for url ({})", e.without_url(), url),
+        None => e.to_string(),
+    }
+}
+
+// Returns a copy of a JSON value that is safe to log: sensitive keys are blanked out
+// and URLs have their query strings removed.
+pub fn redact_json(value: &Value) -> Value {
+    match value {
+        Value::Object(obj) => Value::Object(
+            obj.iter().map(|(key, val)| {
+                if SENSITIVE_KEYS.contains(&key.to_ascii_lowercase().as_str()) {
+                    (key.clone(), Value::String(REDACTED.into()))
+                }
+                else {
+                    (key.clone(), redact_json(val))
+                }
+            }).collect()
+        ),
+        Value::Array(arr) => Value::Array(arr.iter().map(redact_json).collect()),
+        Value::String(s) => {
+            match reqwest::Url::parse(s) {
+                Ok(url) if url.has_host() => Value::String(redact_url(&url)),
+                _ => value.clone(),
+            }
+        },
+        _ => value.clone(),
+    }
+}
@@ -0,0 +1,110 @@
+use jiff::{tz::TimeZone, Timestamp};
+use serde_json::Value;
+
+use crate::export::escape_html;
+
+const RECORD_CSS: &str = r#"
+body { font-family: Helvetica, Arial, sans-serif; margin: 2em; color: #0f0f0f; max-width: 50em; }
+h1 { font-weight: 500; }
+dl { margin: 0; }
+dt { font-weight: 600; margin-top: 0.6em; }
+dd { margin-left: 1.5em; white-space: pre-wrap; }
+ul { margin: 0.2em 0; padding-left: 1.2em; }
+@media print { body { margin: 0; } }
+"#;
+
+// Records kept alongside a student's media: the JSON brightwheel returns for them, plus
+// a printable HTML page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordKind {
+    // One per student per day, covering naps, meals, diapers and so on.
+    DailyReport,
+    // A learning observation, milestone or assessment.
+    Observation,
+}
+
+impl RecordKind {
+    pub fn title(&self) -> &'static str {
+        match self {
+            RecordKind::DailyReport => "Daily Report",
+            RecordKind::Observation => "Observation",
+        }
+    }
+
+    // Folder under the student's folder; records go in YYYY-MM folders inside it.
+    pub fn dir_name(&self) -> &'static str {
+        match self {
+            RecordKind::DailyReport => "daily-reports",
+            RecordKind::Observation => "observations",
+        }
+    }
+}
+
+// Lays out whatever fields the record has; the shape differs between schools and
+// record types, so nothing beyond JSON structure is assumed.
+pub fn render_html(title: &str, record: &Value) -> String {
+    let mut body = String::new();
+    render_value(record, &mut body);
+    format!(
+        "<!doctype html>\n<html lang=\"en\">\n<head>\n<meta charset=\"UTF-8\">\n\
+         <title>{title}</title>\n<style>{css}</style>\n</head>\n<body>\n<h1>{title}</h1>\n{body}</body>\n</html>\n",
+        title = escape_html(title), css = RECORD_CSS, body = body
+    )
+}
+
+fn render_value(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(obj) => {
+            out.push_str("<dl>\n");
+            for (key, item) in obj.iter().filter(|(key, item)| !is_hidden(key, item)) {
+                out.push_str(&format!("<dt>{}</dt>\n<dd>", escape_html(&humanize_key(key))));
+                render_value(item, out);
+                out.push_str("</dd>\n");
+            }
+            out.push_str("</dl>\n");
+        },
+        Value::Array(items) => {
+            out.push_str("<ul>\n");
+            for item in items {
+                out.push_str("<li>");
+                render_value(item, out);
+                out.push_str("</li>\n");
+            }
+            out.push_str("</ul>\n");
+        },
+        Value::String(s) => out.push_str(&escape_html(&display_string(s))),
+        Value::Bool(b) => out.push_str(if *b { "Yes" } else { "No" }),
+        Value::Number(n) => out.push_str(&n.to_string()),
+        Value::Null => {},
+    }
+}
+
+// Ids, links and empty fields mean nothing on paper.
+fn is_hidden(key: &str, item: &Value) -> bool {
+    key == "object_id" || key.ends_with("_id") || key.ends_with("_url")
+        || match item {
+            Value::Null => true,
+            Value::String(s) => s.is_empty(),
+            Value::Array(items) => items.is_empty(),
+            Value::Object(obj) => obj.is_empty(),
+            _ => false,
+        }
+}
+
+// nap_start_time -> Nap start time
+fn humanize_key(key: &str) -> String {
+    let words = key.replace('_', " ");
+    let mut chars = words.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => words,
+    }
+}
+
+// Timestamps are shown in local time; other strings as they are.
+fn display_string(s: &str) -> String {
+    match s.parse::<Timestamp>() {
+        Ok(ts) => ts.to_zoned(TimeZone::system()).strftime("%F %-I:%M %p").to_string(),
+        Err(_) => s.to_string(),
+    }
+}
@@ -0,0 +1,42 @@
+use std::{sync::Mutex, thread, time::Duration};
+
+use jiff::Timestamp;
+use serde::Serialize;
+use tauri::{AppHandle, Manager};
+use tracing::info;
+
+use crate::settings::Settings;
+use crate::sync::SyncScope;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SyncStatus {
+    pub running: bool,
+    pub last_sync: Option<Timestamp>,
+    pub last_message: Option<String>,
+    pub next_sync: Option<Timestamp>,
+}
+
+pub fn spawn(app: AppHandle) {
+    thread::spawn(move || {
+        loop {
+            let schedule = app.state::<Mutex<Settings>>().lock().unwrap().schedule.clone();
+            let now = Timestamp::now();
+
+            let due = {
+                let status_mutex = app.state::<Mutex<SyncStatus>>();
+                let mut status = status_mutex.lock().unwrap();
+                status.next_sync = schedule.next_run(status.last_sync, now);
+                !status.running && status.next_sync.is_some_and(|next_sync| next_sync <= now)
+            };
+
+            if due {
+                info!("scheduler: starting scheduled sync");
+                crate::sync_and_report(&app, SyncScope::Everything);
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
@@ -0,0 +1,194 @@
+use jiff::{civil::Time, tz::TimeZone, Span, Timestamp, ToSpan};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::storage::LibraryLocation;
+use crate::webdav::WebDavSettings;
+
+const SETTINGS_PATH: &str = "settings.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Settings {
+    #[serde(default)]
+    pub schedule: Schedule,
+    #[serde(default)]
+    pub debug_logging: bool,
+    #[serde(default)]
+    pub webdav: WebDavSettings,
+    #[serde(default)]
+    pub library: LibraryLocation,
+    // Activities requested per page; brightwheel::DEFAULT_PAGE_SIZE if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_size: Option<usize>,
+    // Names of activity handlers switched off; every handler runs by default.
+    #[serde(default)]
+    pub disabled_handlers: Vec<String>,
+}
+
+// Settings as sent to the webview. The WebDAV password and S3 secret key are blanked;
+// the flags say whether one is saved, and saving a blank one keeps it.
+#[derive(Serialize, Debug)]
+pub struct SettingsView {
+    #[serde(flatten)]
+    settings: Settings,
+    webdav_password_set: bool,
+    s3_secret_key_set: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Schedule {
+    #[default]
+    Manual,
+    EveryHours { hours: u32 },
+    DailyAt { time: Time },
+}
+
+impl Settings {
+    pub fn load() -> Settings {
+        if let Ok(file) = std::fs::File::open(SETTINGS_PATH).map(std::io::BufReader::new) {
+            match serde_json::from_reader(file) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    warn!("Could not parse {}: {}; using defaults", SETTINGS_PATH, e);
+                    Settings::default()
+                }
+            }
+        }
+        else {
+            Settings::default()
+        }
+    }
+
+    // The file holds the WebDAV password and S3 secret key, so only the user can read it.
+    pub fn save(&self) {
+        let file = std::fs::File::create(SETTINGS_PATH).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Err(e) = file.set_permissions(std::fs::Permissions::from_mode(0o600)) {
+                warn!("could not restrict access to {}: {}", SETTINGS_PATH, e);
+            }
+        }
+        let mut writer = std::io::BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, self).unwrap();
+    }
+
+    pub fn view(&self) -> SettingsView {
+        let mut settings = self.clone();
+        let webdav_password_set = !std::mem::take(&mut settings.webdav.password).is_empty();
+        let s3_secret_key_set = match &mut settings.library {
+            LibraryLocation::S3 { secret_key, .. } => !std::mem::take(secret_key).is_empty(),
+            LibraryLocation::Local { .. } => false,
+        };
+        SettingsView { settings, webdav_password_set, s3_secret_key_set }
+    }
+
+    // Saves webdav, keeping the current password if webdav's is blank.
+    pub fn set_webdav(&mut self, mut webdav: WebDavSettings) {
+        if webdav.password.is_empty() {
+            webdav.password = std::mem::take(&mut self.webdav.password);
+        }
+        self.webdav = webdav;
+    }
+
+    // library with a blank S3 secret key filled in from the current location, if that is
+    // S3 with the same access key.
+    pub fn with_saved_secret(&self, mut library: LibraryLocation) -> LibraryLocation {
+        if let (
+            LibraryLocation::S3 { access_key, secret_key, .. },
+            LibraryLocation::S3 { access_key: saved_access_key, secret_key: saved_secret_key, .. },
+        ) = (&mut library, &self.library) {
+            if secret_key.is_empty() && access_key == saved_access_key {
+                secret_key.clone_from(saved_secret_key);
+            }
+        }
+        library
+    }
+}
+
+impl Schedule {
+    // Returns when the next scheduled sync is due, or None for manual syncing.
+    // A schedule that has never run is due immediately.
+    pub fn next_run(&self, last_run_opt: Option<Timestamp>, now: Timestamp) -> Option<Timestamp> {
+        match self {
+            Schedule::Manual => None,
+            Schedule::EveryHours { hours } => {
+                if let Some(last_run) = last_run_opt {
+                    let span = Span::new().hours(i64::from((*hours).max(1)));
+                    Some(last_run.checked_add(span).unwrap())
+                }
+                else {
+                    Some(now)
+                }
+            },
+            Schedule::DailyAt { time } => {
+                let base = last_run_opt.unwrap_or(now).to_zoned(TimeZone::system());
+                let candidate = base.date().to_datetime(*time).to_zoned(TimeZone::system()).unwrap();
+                if candidate.timestamp() > base.timestamp() {
+                    Some(candidate.timestamp())
+                }
+                else {
+                    Some(candidate.checked_add(1.day()).unwrap().timestamp())
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s3(access_key: &str, secret_key: &str) -> LibraryLocation {
+        LibraryLocation::S3 {
+            endpoint: "http://localhost:9000".into(),
+            bucket: "photos".into(),
+            region: "us-east-1".into(),
+            access_key: access_key.into(),
+            secret_key: secret_key.into(),
+            prefix: String::new(),
+            path_style: true,
+        }
+    }
+
+    fn webdav(password: &str) -> WebDavSettings {
+        WebDavSettings {
+            enabled: true, url: "https://cloud.example.com/dav/".into(), username: "me".into(), password: password.into(),
+        }
+    }
+
+    #[test]
+    fn view_leaves_out_secrets() {
+        let settings = Settings { webdav: webdav("hunter2"), library: s3("AKIA", "wJalr"), ..Default::default() };
+        let json = serde_json::to_value(settings.view()).unwrap();
+        assert!(!json.to_string().contains("hunter2"), "{}", json);
+        assert!(!json.to_string().contains("wJalr"), "{}", json);
+        assert_eq!(json["webdav"]["username"], "me");
+        assert_eq!(json["library"]["access_key"], "AKIA");
+        assert_eq!((&json["webdav_password_set"], &json["s3_secret_key_set"]), (&true.into(), &true.into()));
+
+        let json = serde_json::to_value(Settings::default().view()).unwrap();
+        assert_eq!((&json["webdav_password_set"], &json["s3_secret_key_set"]), (&false.into(), &false.into()));
+    }
+
+    #[test]
+    fn blank_secrets_keep_the_saved_ones() {
+        let mut settings = Settings { webdav: webdav("hunter2"), library: s3("AKIA", "wJalr"), ..Default::default() };
+
+        settings.set_webdav(webdav(""));
+        assert_eq!(settings.webdav.password, "hunter2");
+        settings.set_webdav(webdav("correct horse"));
+        assert_eq!(settings.webdav.password, "correct horse");
+
+        assert!(matches!(
+            settings.with_saved_secret(s3("AKIA", "")), LibraryLocation::S3 { secret_key, .. } if secret_key == "wJalr"
+        ));
+        assert!(matches!(
+            settings.with_saved_secret(s3("AKIA", "new")), LibraryLocation::S3 { secret_key, .. } if secret_key == "new"
+        ));
+        assert!(matches!(
+            settings.with_saved_secret(s3("OTHER", "")), LibraryLocation::S3 { secret_key, .. } if secret_key.is_empty()
+        ));
+    }
+}
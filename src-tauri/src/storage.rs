@@ -0,0 +1,482 @@
+#[cfg(test)]
+use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{blocking::{Body, Client}, StatusCode, Url};
+use rusty_s3::{actions::ListObjectsV2, Bucket, Credentials, S3Action, UrlStyle};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::logging::redact_error;
+
+// How long presigned S3 request URLs stay valid.
+const SIGNATURE_TTL: Duration = Duration::from_secs(3600);
+
+// Where the library lives. Paths handed to a LibraryStorage are relative to the
+// library root and always use '/' separators, e.g. "Ada Lovelace/2024-05/photo.jpg".
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LibraryLocation {
+    Local {
+        path: String,
+    },
+    S3 {
+        // e.g. https://s3.us-west-2.amazonaws.com, http://localhost:9000 for MinIO
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+        // Optional key prefix, so the library can share a bucket.
+        #[serde(default)]
+        prefix: String,
+        // MinIO and most self-hosted servers need path-style URLs.
+        #[serde(default)]
+        path_style: bool,
+    },
+}
+
+impl Default for LibraryLocation {
+    fn default() -> Self {
+        LibraryLocation::Local { path: ".".into() }
+    }
+}
+
+impl LibraryLocation {
+    pub fn open(&self) -> Result<Box<dyn LibraryStorage>, String> {
+        match self {
+            LibraryLocation::Local { path } => Ok(Box::new(LocalStorage::new(PathBuf::from(path)))),
+            LibraryLocation::S3 { endpoint, bucket, region, access_key, secret_key, prefix, path_style } => {
+                Ok(Box::new(S3Storage::new(
+                    endpoint, bucket, region, access_key, secret_key, prefix, *path_style
+                )?))
+            },
+        }
+    }
+
+    // The library directory on this machine, if the library is local.
+    pub fn local_root(&self) -> Option<PathBuf> {
+        match self {
+            LibraryLocation::Local { path } => Some(PathBuf::from(path)),
+            LibraryLocation::S3 { .. } => None,
+        }
+    }
+}
+
+// Where a library lives. Shared with the sync's download workers, hence Sync.
+pub trait LibraryStorage: Sync {
+    fn exists(&self, path: &str) -> Result<bool, String>;
+
+    fn create_dir(&self, path: &str) -> Result<(), String>;
+
+    // Writes everything from reader to path, returning the number of bytes written.
+    // The file only appears at path once it is complete, so a failed or interrupted
+    // write never leaves a partial file behind.
+    fn write_from(&self, path: &str, reader: &mut dyn Read) -> Result<u64, String>;
+
+    fn read(&self, path: &str) -> Result<Vec<u8>, String>;
+
+    // A reader over the file at path and its length, for passing a file on without
+    // holding all of it in memory.
+    fn open(&self, path: &str) -> Result<(Box<dyn Read + Send>, u64), String>;
+
+    // Names of the files and directories directly inside dir ("" for the root), sorted.
+    fn list(&self, dir: &str) -> Result<Vec<String>, String>;
+
+    // Moves a file within the library, replacing anything already at to.
+    #[allow(dead_code)]
+    fn rename(&self, from: &str, to: &str) -> Result<(), String>;
+}
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> LocalStorage {
+        LocalStorage { root }
+    }
+
+    fn full_path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+}
+
+impl LibraryStorage for LocalStorage {
+    fn exists(&self, path: &str) -> Result<bool, String> {
+        Ok(self.full_path(path).exists())
+    }
+
+    fn create_dir(&self, path: &str) -> Result<(), String> {
+        let full_path = self.full_path(path);
+        if !full_path.exists() {
+            std::fs::create_dir_all(&full_path).map_err(|e| format!("{:?}: {}", full_path, e))?;
+        }
+        Ok(())
+    }
+
+    // Writes to a .partial file next to the destination and renames it into place.
+    fn write_from(&self, path: &str, reader: &mut dyn Read) -> Result<u64, String> {
+        let full_path = self.full_path(path);
+        let partial_path = self.full_path(&format!("{}.partial", path));
+        let result = std::fs::File::create(&partial_path).and_then(|mut file| {
+            let len = std::io::copy(reader, &mut file)?;
+            file.sync_all()?;
+            Ok(len)
+        }).and_then(|len| {
+            std::fs::rename(&partial_path, &full_path)?;
+            Ok(len)
+        });
+        result.map_err(|e| {
+            let _ = std::fs::remove_file(&partial_path);
+            format!("{:?}: {}", full_path, e)
+        })
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+        let full_path = self.full_path(path);
+        std::fs::read(&full_path).map_err(|e| format!("{:?}: {}", full_path, e))
+    }
+
+    fn open(&self, path: &str) -> Result<(Box<dyn Read + Send>, u64), String> {
+        let full_path = self.full_path(path);
+        let file = std::fs::File::open(&full_path).map_err(|e| format!("{:?}: {}", full_path, e))?;
+        let len = file.metadata().map_err(|e| format!("{:?}: {}", full_path, e))?.len();
+        Ok((Box::new(file), len))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, String> {
+        let full_path = self.full_path(dir);
+        let read_dir = match std::fs::read_dir(&full_path) {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("{:?}: {}", full_path, e)),
+        };
+        let mut names = Vec::from_iter(
+            read_dir.filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        );
+        names.sort();
+        Ok(names)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), String> {
+        std::fs::rename(self.full_path(from), self.full_path(to))
+            .map_err(|e| format!("rename {} to {}: {}", from, to, e))
+    }
+}
+
+pub struct S3Storage {
+    client: Client,
+    bucket: Bucket,
+    credentials: Credentials,
+    prefix: String,
+    // Object names under each directory prefix, listed once per directory.
+    listings: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl S3Storage {
+    pub fn new(
+        endpoint: &str, bucket: &str, region: &str, access_key: &str, secret_key: &str,
+        prefix: &str, path_style: bool,
+    ) -> Result<S3Storage, String> {
+        let endpoint_url = Url::parse(endpoint).map_err(|e| format!("bad S3 endpoint: {}", e))?;
+        let url_style = if path_style { UrlStyle::Path } else { UrlStyle::VirtualHost };
+        let bucket = Bucket::new(endpoint_url, url_style, bucket.to_string(), region.to_string())
+            .map_err(|e| format!("bad S3 bucket: {:?}", e))?;
+        let prefix = prefix.trim_matches('/');
+        Ok(S3Storage {
+            client: Client::builder().timeout(Duration::from_secs(300)).build().map_err(|e| e.to_string())?,
+            bucket,
+            credentials: Credentials::new(access_key, secret_key),
+            prefix: if prefix.is_empty() { String::new() } else { format!("{}/", prefix) },
+            listings: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn key(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path)
+    }
+
+    fn list_dir(&self, dir: &str) -> Result<HashSet<String>, String> {
+        if let Some(names) = self.listings.lock().unwrap().get(dir) {
+            return Ok(names.clone());
+        }
+
+        let dir_prefix = if dir.is_empty() { self.prefix.clone() } else { format!("{}/", self.key(dir)) };
+        let mut names = HashSet::new();
+        let mut continuation_token_opt: Option<String> = None;
+        loop {
+            let mut action = self.bucket.list_objects_v2(Some(&self.credentials));
+            action.with_prefix(dir_prefix.as_str());
+            action.with_delimiter("/");
+            if let Some(token) = &continuation_token_opt {
+                action.with_continuation_token(token.as_str());
+            }
+            let url = action.sign(SIGNATURE_TTL);
+            let response = self.client.get(url).send().map_err(redact_error)?;
+            if !response.status().is_success() {
+                return Err(format!("S3 list {}: HTTP {}", dir_prefix, response.status()));
+            }
+            let body = response.text().map_err(redact_error)?;
+            let parsed = ListObjectsV2::parse_response(&body).map_err(|e| format!("S3 list {}: {}", dir_prefix, e))?;
+
+            names.extend(parsed.contents.iter().map(|object| object.key[dir_prefix.len()..].to_string()));
+            names.extend(parsed.common_prefixes.iter().map(|common| {
+                common.prefix[dir_prefix.len()..].trim_end_matches('/').to_string()
+            }));
+
+            continuation_token_opt = parsed.next_continuation_token;
+            if continuation_token_opt.is_none() {
+                break;
+            }
+        }
+        debug!("s3: {} entries under {}", names.len(), dir_prefix);
+
+        self.listings.lock().unwrap().insert(dir.to_string(), names.clone());
+        Ok(names)
+    }
+
+    fn remember(&self, path: &str) {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if let Some(names) = self.listings.lock().unwrap().get_mut(dir) {
+            names.insert(name.to_string());
+        }
+    }
+}
+
+impl LibraryStorage for S3Storage {
+    // Uses a cached listing of the parent prefix rather than a HEAD per object.
+    fn exists(&self, path: &str) -> Result<bool, String> {
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        Ok(self.list_dir(dir)?.contains(name))
+    }
+
+    // S3 has no directories; keys are created with their full prefix.
+    fn create_dir(&self, _path: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // PUT needs a length up front, so the data is spooled to a temporary file first.
+    fn write_from(&self, path: &str, reader: &mut dyn Read) -> Result<u64, String> {
+        let mut spool = tempfile::tempfile().map_err(|e| e.to_string())?;
+        let len = std::io::copy(reader, &mut spool).map_err(|e| e.to_string())?;
+        spool.flush().map_err(|e| e.to_string())?;
+        spool.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+
+        let key = self.key(path);
+        let url = self.bucket.put_object(Some(&self.credentials), &key).sign(SIGNATURE_TTL);
+        let response = self.client.put(url)
+            .body(Body::sized(spool, len))
+            .send()
+            .map_err(redact_error)?;
+        if !response.status().is_success() {
+            return Err(format!("S3 put {}: HTTP {}", key, response.status()));
+        }
+        self.remember(path);
+        Ok(len)
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+        let key = self.key(path);
+        let url = self.bucket.get_object(Some(&self.credentials), &key).sign(SIGNATURE_TTL);
+        let response = self.client.get(url).send().map_err(redact_error)?;
+        match response.status() {
+            StatusCode::OK => Ok(response.bytes().map_err(redact_error)?.to_vec()),
+            status => Err(format!("S3 get {}: HTTP {}", key, status)),
+        }
+    }
+
+    fn open(&self, path: &str) -> Result<(Box<dyn Read + Send>, u64), String> {
+        let key = self.key(path);
+        let url = self.bucket.get_object(Some(&self.credentials), &key).sign(SIGNATURE_TTL);
+        let response = self.client.get(url).send().map_err(redact_error)?;
+        if response.status() != StatusCode::OK {
+            return Err(format!("S3 get {}: HTTP {}", key, response.status()));
+        }
+        let len = response.content_length().ok_or_else(|| format!("S3 get {}: no content length", key))?;
+        Ok((Box::new(response), len))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, String> {
+        let mut names = Vec::from_iter(self.list_dir(dir)?);
+        names.sort();
+        Ok(names)
+    }
+
+    // S3 has no rename; the object is copied server-side and the original deleted.
+    fn rename(&self, from: &str, to: &str) -> Result<(), String> {
+        let from_key = self.key(from);
+        let to_key = self.key(to);
+        let copy_source = format!(
+            "/{}/{}", self.bucket.name(), utf8_percent_encode(&from_key, NON_ALPHANUMERIC)
+        );
+        let mut action = self.bucket.put_object(Some(&self.credentials), &to_key);
+        action.headers_mut().insert("x-amz-copy-source", copy_source.clone());
+        let url = action.sign(SIGNATURE_TTL);
+        let response = self.client.put(url)
+            .header("x-amz-copy-source", copy_source)
+            .send()
+            .map_err(redact_error)?;
+        if !response.status().is_success() {
+            return Err(format!("S3 copy {} to {}: HTTP {}", from_key, to_key, response.status()));
+        }
+        self.remember(to);
+
+        let url = self.bucket.delete_object(Some(&self.credentials), &from_key).sign(SIGNATURE_TTL);
+        let response = self.client.delete(url).send().map_err(redact_error)?;
+        if !response.status().is_success() {
+            return Err(format!("S3 delete {}: HTTP {}", from_key, response.status()));
+        }
+        let (from_dir, from_name) = from.rsplit_once('/').unwrap_or(("", from));
+        if let Some(names) = self.listings.lock().unwrap().get_mut(from_dir) {
+            names.remove(from_name);
+        }
+        Ok(())
+    }
+}
+
+// Keeps a whole library in memory, for tests of the sync engine.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+    dirs: Mutex<BTreeSet<String>>,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    pub fn file_paths(&self) -> Vec<String> {
+        Vec::from_iter(self.files.lock().unwrap().keys().cloned())
+    }
+}
+
+#[cfg(test)]
+impl LibraryStorage for MemoryStorage {
+    fn exists(&self, path: &str) -> Result<bool, String> {
+        Ok(self.files.lock().unwrap().contains_key(path) || self.dirs.lock().unwrap().contains(path))
+    }
+
+    fn create_dir(&self, path: &str) -> Result<(), String> {
+        let mut dirs = self.dirs.lock().unwrap();
+        let mut prefix = String::new();
+        for segment in path.split('/') {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(segment);
+            dirs.insert(prefix.clone());
+        }
+        Ok(())
+    }
+
+    fn write_from(&self, path: &str, reader: &mut dyn Read) -> Result<u64, String> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(|e| format!("{}: {}", path, e))?;
+        let len = bytes.len() as u64;
+        self.files.lock().unwrap().insert(path.to_string(), bytes);
+        Ok(len)
+    }
+
+    fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+        self.files.lock().unwrap().get(path).cloned().ok_or_else(|| format!("{}: not found", path))
+    }
+
+    fn open(&self, path: &str) -> Result<(Box<dyn Read + Send>, u64), String> {
+        let bytes = self.read(path)?;
+        let len = bytes.len() as u64;
+        Ok((Box::new(std::io::Cursor::new(bytes)), len))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, String> {
+        let dir_prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+        let files = self.files.lock().unwrap();
+        let dirs = self.dirs.lock().unwrap();
+        let names = BTreeSet::from_iter(
+            files.keys().chain(dirs.iter())
+                .filter_map(|path| path.strip_prefix(&dir_prefix))
+                .map(|rest| rest.split('/').next().unwrap().to_string())
+                .filter(|name| !name.is_empty())
+        );
+        Ok(Vec::from_iter(names))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), String> {
+        let mut files = self.files.lock().unwrap();
+        let bytes = files.remove(from).ok_or_else(|| format!("{}: not found", from))?;
+        files.insert(to.to_string(), bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("connection reset"))
+        }
+    }
+
+    #[test]
+    fn local_write_is_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().to_path_buf());
+        storage.create_dir("Ada Lovelace/2024-05").unwrap();
+
+        assert!(storage.write_from("Ada Lovelace/2024-05/a.jpg", &mut FailingReader).is_err());
+        assert_eq!(storage.list("Ada Lovelace/2024-05").unwrap(), Vec::<String>::new());
+
+        assert_eq!(storage.write_from("Ada Lovelace/2024-05/a.jpg", &mut &b"jpeg"[..]).unwrap(), 4);
+        assert_eq!(storage.list("Ada Lovelace/2024-05").unwrap(), vec!["a.jpg"]);
+        assert_eq!(storage.read("Ada Lovelace/2024-05/a.jpg").unwrap(), b"jpeg");
+
+        let (mut reader, len) = storage.open("Ada Lovelace/2024-05/a.jpg").unwrap();
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        assert_eq!((contents.as_slice(), len), (&b"jpeg"[..], 4));
+    }
+
+    #[test]
+    fn local_list_and_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().to_path_buf());
+        storage.create_dir("b").unwrap();
+        storage.write_from("a.txt", &mut &b"x"[..]).unwrap();
+        assert_eq!(storage.list("").unwrap(), vec!["a.txt", "b"]);
+        assert_eq!(storage.list("missing").unwrap(), Vec::<String>::new());
+
+        storage.rename("a.txt", "b/c.txt").unwrap();
+        assert!(!storage.exists("a.txt").unwrap());
+        assert_eq!(storage.read("b/c.txt").unwrap(), b"x");
+        assert!(storage.rename("a.txt", "b/d.txt").is_err());
+    }
+
+    #[test]
+    fn memory_storage_behaves_like_a_directory_tree() {
+        let storage = MemoryStorage::default();
+        storage.create_dir("Ada Lovelace/2024-05").unwrap();
+        storage.write_from("Ada Lovelace/2024-05/a.jpg", &mut &b"jpeg"[..]).unwrap();
+
+        assert!(storage.exists("Ada Lovelace").unwrap());
+        assert!(storage.exists("Ada Lovelace/2024-05/a.jpg").unwrap());
+        assert_eq!(storage.list("").unwrap(), vec!["Ada Lovelace"]);
+        assert_eq!(storage.list("Ada Lovelace").unwrap(), vec!["2024-05"]);
+
+        assert!(storage.write_from("Ada Lovelace/2024-05/b.jpg", &mut FailingReader).is_err());
+        assert!(!storage.exists("Ada Lovelace/2024-05/b.jpg").unwrap());
+
+        storage.rename("Ada Lovelace/2024-05/a.jpg", "Ada Lovelace/2024-05/c.jpg").unwrap();
+        assert_eq!(storage.list("Ada Lovelace/2024-05").unwrap(), vec!["c.jpg"]);
+        assert!(storage.rename("Ada Lovelace/2024-05/a.jpg", "Ada Lovelace/2024-05/d.jpg").is_err());
+    }
+}
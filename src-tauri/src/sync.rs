@@ -0,0 +1,1412 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet}, io::Read,
+    sync::{mpsc::{channel, sync_channel, Receiver, SyncSender}, Mutex}, thread,
+};
+
+use jiff::{tz::TimeZone, Timestamp, ToSpan};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, info_span, warn, Span};
+
+use crate::brightwheel::{
+    Activity, Announcement, Attachment, BrightwheelApi, DateRange, MessageThread, Sender, Student,
+};
+use crate::handlers::{ActivityHandler, HandlerOutput};
+use crate::catalog::{self, CatalogItem};
+use crate::history::SyncRun;
+use crate::library::{format_filename, MediaSidecar, ProfilePhoto, StudentProfile};
+use crate::logging::redact_url;
+use crate::records::{self, RecordKind};
+use crate::storage::LibraryStorage;
+use crate::webdav::WebDavClient;
+
+// Media downloads in flight at once for a student. Only downloads run side by side;
+// the activity feed, records, messages and WebDAV uploads go one request at a time on
+// the sync thread.
+const DOWNLOAD_WORKERS: usize = 4;
+
+// State shared by everything that runs during one sync.
+pub struct SyncContext<'a, A: BrightwheelApi, S: LibraryStorage + ?Sized> {
+    pub api: &'a A,
+    pub storage: &'a S,
+    pub run: SyncRun,
+    pub webdav_opt: Option<WebDavClient>,
+    // Set for a dry run: nothing is written, and what would happen is recorded here instead.
+    pub report_opt: Option<DryRunReport>,
+    pub scope: SyncScope,
+    // Newest created_at synced for each student (by object_id). Read for
+    // SyncScope::SinceLastSync and advanced after each student that synced cleanly.
+    pub watermarks: BTreeMap<String, Timestamp>,
+    pub page_size: usize,
+    // Daily reports and observations already looked up this sync (saved or not), by path,
+    // so a day with many naps and meals only asks for its report once.
+    pub fetched_records: HashSet<String>,
+    // The enabled activity handlers; see handlers::enabled.
+    pub handlers: Vec<Box<dyn ActivityHandler>>,
+    // Files saved so far, recorded in the library's catalog at the end of the sync.
+    pub saved_items: BTreeMap<String, CatalogItem>,
+}
+
+// Which activities a sync looks at.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SyncScope {
+    #[default]
+    Everything,
+    LastDays {
+        days: i64,
+    },
+    ThisMonth,
+    // Everything newer than the student's watermark; the whole feed if there is none.
+    SinceLastSync,
+    Between {
+        since: Option<Timestamp>,
+        until: Option<Timestamp>,
+    },
+}
+
+impl SyncScope {
+    pub fn range(&self, watermark_opt: Option<Timestamp>, now: Timestamp) -> DateRange {
+        match self {
+            SyncScope::Everything => DateRange::default(),
+            SyncScope::LastDays { days } => DateRange {
+                since: now.to_zoned(TimeZone::system()).checked_sub(days.days()).ok().map(|zoned| zoned.timestamp()),
+                until: None,
+            },
+            SyncScope::ThisMonth => {
+                let zoned = now.to_zoned(TimeZone::system());
+                DateRange {
+                    since: zoned.first_of_month().and_then(|first| first.start_of_day()).ok().map(|start| start.timestamp()),
+                    until: None,
+                }
+            },
+            SyncScope::SinceLastSync => DateRange { since: watermark_opt, until: None },
+            SyncScope::Between { since, until } => DateRange { since: *since, until: *until },
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PlannedFile {
+    pub path: String,
+    // From Content-Length, if the server sent one.
+    pub bytes: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DryRunConflict {
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct DryRunReport {
+    pub new_files: Vec<PlannedFile>,
+    pub existing_files: Vec<String>,
+    pub conflicts: Vec<DryRunConflict>,
+    pub estimated_bytes: u64,
+    // New files whose size could not be found out.
+    pub unknown_sizes: usize,
+    // Activities with no photo or video, by action_type.
+    pub non_media_activities: BTreeMap<String, usize>,
+    pub errors: Vec<String>,
+    #[serde(skip)]
+    planned_paths: HashSet<String>,
+    #[serde(skip)]
+    listings: HashMap<String, Vec<String>>,
+}
+
+// Syncs every student on the account into ctx.storage, recording the outcome in ctx.run.
+pub fn sync_account<A: BrightwheelApi, S: LibraryStorage + ?Sized>(ctx: &mut SyncContext<A, S>) {
+    let profile = match ctx.api.profile() {
+        Ok(profile) => profile,
+        Err(msg) => {
+            warn!("could not load the account profile: {}", msg);
+            ctx.run.errors.push(format!("could not load the account profile: {}", msg));
+            return;
+        }
+    };
+    debug!("got user_id: {}", profile.object_id);
+    ctx.run.account = Some(profile.object_id.clone());
+
+    match ctx.api.students(&profile.object_id) {
+        Ok(students) => {
+            for student_result in students {
+                match student_result {
+                    Ok(student) => {
+                        ctx.run.students.push(format!("{} {}", student.first_name, student.last_name));
+                        sync_student(ctx, &student)
+                    },
+                    Err(msg) => {
+                        warn!("skipping a student: {}", msg);
+                        ctx.run.errors.push(format!("skipped {}", msg));
+                    }
+                }
+            }
+        },
+        Err(msg) => {
+            warn!("could not list students: {}", msg);
+            ctx.run.errors.push(format!("could not list students: {}", msg));
+        }
+    }
+    sync_messages(ctx, &profile.object_id);
+
+    if ctx.report_opt.is_none() {
+        if let Err(msg) = catalog::update_account(
+            ctx.storage, &profile, Some(ctx.run.started_at), std::mem::take(&mut ctx.saved_items),
+        ) {
+            warn!("could not update the library catalog: {}", msg);
+            ctx.run.errors.push(msg);
+        }
+    }
+}
+
+fn sync_student<A: BrightwheelApi, S: LibraryStorage + ?Sized>(ctx: &mut SyncContext<A, S>, student: &Student) {
+    let _span = info_span!("student", id = %student.object_id).entered();
+    info!("sync_student: {} {}", student.first_name, student.last_name);
+
+    let student_path = format!("{} {}", student.first_name, student.last_name);
+    if ctx.report_opt.is_none() {
+        if let Err(msg) = ctx.storage.create_dir(&student_path) {
+            warn!("could not create student folder: {}", msg);
+            ctx.run.errors.push(msg);
+            return;
+        }
+        write_student_profile(ctx, &student_path, student);
+    }
+
+    let range = ctx.scope.range(ctx.watermarks.get(&student.object_id).copied(), Timestamp::now());
+    debug!("range: {:?}", range);
+    let failed_before = ctx.run.failed_items;
+    let mut newest_opt: Option<Timestamp> = None;
+
+    // The range is sent to the server and also applied here, in case the server ignores
+    // it; the feed is newest first, so the first activity before range.since ends the walk.
+    let api = ctx.api;
+    let storage = ctx.storage;
+    let (job_sender, job_receiver) = sync_channel::<(reqwest::Url, String)>(DOWNLOAD_WORKERS);
+    let (result_sender, result_receiver) = channel();
+    let job_receiver = Mutex::new(job_receiver);
+    thread::scope(|scope| {
+        for _ in 0..DOWNLOAD_WORKERS {
+            let (job_receiver, result_sender, span) = (&job_receiver, result_sender.clone(), Span::current());
+            scope.spawn(move || {
+                let _span = span.entered();
+                loop {
+                    // The lock is let go before downloading, so the other workers can take jobs.
+                    let job_result = job_receiver.lock().unwrap().recv();
+                    // Ends once the sync thread drops its sender.
+                    let Ok((src_url, dst_path)) = job_result else {
+                        break;
+                    };
+                    let outcome = download_to_library(api, storage, &src_url, &dst_path);
+                    if result_sender.send((dst_path, outcome)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let mut downloads = Downloads {
+            student_id: student.object_id.clone(), job_sender, result_receiver, pending: HashMap::new(),
+        };
+
+        for (i, activity_result) in api.activities(&student.object_id, range, ctx.page_size).enumerate() {
+            finish_downloads(ctx, &mut downloads, false);
+            let activity = match activity_result {
+                Ok(activity) => activity,
+                Err(msg) => {
+                    record_download(&mut ctx.run, Err(msg));
+                    continue;
+                }
+            };
+            debug!("item {}: {} ({:?})", i, activity.object_id, activity.action_type);
+            if range.until.is_some_and(|until| activity.created_at > until) {
+                continue;
+            }
+            if range.since.is_some_and(|since| activity.created_at < since) {
+                debug!("reached {}, before the start of the range", activity.created_at);
+                break;
+            }
+            newest_opt = Some(newest_opt.map_or(activity.created_at, |newest| newest.max(activity.created_at)));
+
+            sync_activity(ctx, &mut downloads, student, &student_path, &activity);
+        }
+        finish_downloads(ctx, &mut downloads, true);
+    });
+
+    // Only move the watermark past activities that all made it into the library.
+    if let Some(newest) = newest_opt {
+        if ctx.run.failed_items == failed_before {
+            let watermark = ctx.watermarks.entry(student.object_id.clone()).or_insert(newest);
+            *watermark = newest.max(*watermark);
+        }
+    }
+}
+
+fn sync_activity<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    ctx: &mut SyncContext<A, S>, downloads: &mut Downloads, student: &Student, path: &str, activity: &Activity,
+) {
+    let outputs = Vec::from_iter(
+        ctx.handlers.iter()
+            .filter(|handler| handler.accepts(activity))
+            .inspect(|handler| debug!("{} handles {}", handler.name(), activity.object_id))
+            .flat_map(|handler| handler.outputs(activity))
+    );
+
+    // A dry run only plans downloads; everything else is counted by action_type.
+    if let Some(report) = ctx.report_opt.as_mut() {
+        if !outputs.iter().any(|output| matches!(output, HandlerOutput::Media { .. })) {
+            let action_type = activity.action_type.as_deref().unwrap_or("unknown");
+            *report.non_media_activities.entry(action_type.into()).or_default() += 1;
+        }
+    }
+
+    let month_path = get_month_path(path, &activity.created_at);
+    for output in outputs {
+        match output {
+            HandlerOutput::Media { filename, url } => {
+                let result = reqwest::Url::parse(&url)
+                    .map_err(|e| format!("bad url for {}: {}", activity.object_id, e))
+                    .and_then(|src_url| save_media(ctx, downloads, activity, &month_path, &filename, src_url));
+                if let Err(msg) = result {
+                    record_download(&mut ctx.run, Err(msg));
+                }
+            },
+            HandlerOutput::Text { .. } | HandlerOutput::Record(_) if ctx.report_opt.is_some() => {},
+            HandlerOutput::Text { filename, contents } => {
+                let dst_path = format!("{}/{}", month_path, filename);
+                let result = ctx.storage.exists(&dst_path).and_then(|exists| {
+                    if exists {
+                        return Ok(false);
+                    }
+                    ctx.storage.create_dir(&month_path)?;
+                    ctx.storage.write_from(&dst_path, &mut contents.as_bytes()).map(|_| true)
+                });
+                match result {
+                    Ok(true) => {
+                        ctx.run.new_records += 1;
+                        note_saved(ctx, &dst_path, Some(&student.object_id), &activity.object_id);
+                    },
+                    Ok(false) => {},
+                    Err(msg) => {
+                        warn!("could not write {}: {}", dst_path, msg);
+                        ctx.run.errors.push(format!("{}: {}", dst_path, msg));
+                    }
+                }
+            },
+            HandlerOutput::Record(kind) => {
+                match save_record(ctx, student, path, activity, kind) {
+                    Ok(true) => ctx.run.new_records += 1,
+                    Ok(false) => {},
+                    Err(msg) => {
+                        warn!("could not save {} for {}: {}", kind.title(), activity.object_id, msg);
+                        ctx.run.errors.push(format!("{} for {}: {}", kind.title(), activity.object_id, msg));
+                    }
+                }
+            },
+        }
+    }
+}
+
+// Saves the record behind a daily-report or observation activity as JSON plus a printable
+// HTML page, returning whether anything was written. Today's report can still grow, so it
+// is fetched again on each sync and rewritten if it changed; anything else is skipped once
+// saved.
+fn save_record<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    ctx: &mut SyncContext<A, S>, student: &Student, path: &str, activity: &Activity, kind: RecordKind,
+) -> Result<bool, String> {
+    let local_date = activity.created_at.to_zoned(TimeZone::system()).date();
+    let (month_path, stem, title) = match kind {
+        RecordKind::DailyReport => (
+            format!("{}/{}/{}", path, kind.dir_name(), local_date.strftime("%Y-%m")),
+            local_date.to_string(),
+            format!("{} \u{2013} {} \u{2013} {}", path, kind.title(), local_date),
+        ),
+        RecordKind::Observation => (
+            get_month_path(&format!("{}/{}", path, kind.dir_name()), &activity.created_at),
+            format!("{}-{}", activity.created_at.strftime("%F-%H%M%S"), activity.object_id),
+            format!("{} \u{2013} {} \u{2013} {}", path, kind.title(), local_date),
+        ),
+    };
+    let json_path = format!("{}/{}.json", month_path, stem);
+    if !ctx.fetched_records.insert(json_path.clone()) {
+        return Ok(false);
+    }
+    let exists = ctx.storage.exists(&json_path)?;
+    let still_open = kind == RecordKind::DailyReport && local_date >= Timestamp::now().to_zoned(TimeZone::system()).date();
+    if exists && !still_open {
+        debug!("{} already exists; skipping", json_path);
+        return Ok(false);
+    }
+
+    let record_opt = match kind {
+        RecordKind::DailyReport => ctx.api.daily_report(&student.object_id, local_date)?,
+        RecordKind::Observation => ctx.api.observation(&student.object_id, &activity.object_id)?,
+    };
+    let Some(record) = record_opt else {
+        debug!("no {} for {}", kind.title(), json_path);
+        return Ok(false);
+    };
+    let json = serde_json::to_vec_pretty(&record).unwrap();
+    if exists && ctx.storage.read(&json_path)? == json {
+        return Ok(false);
+    }
+
+    info!("saving {}", json_path);
+    ctx.storage.create_dir(&month_path)?;
+    ctx.storage.write_from(&json_path, &mut json.as_slice())?;
+    note_saved(ctx, &json_path, Some(&student.object_id), &activity.object_id);
+    let html_path = format!("{}/{}.html", month_path, stem);
+    let html = records::render_html(&title, &record);
+    ctx.storage.write_from(&html_path, &mut html.as_bytes())?;
+    note_saved(ctx, &html_path, Some(&student.object_id), &activity.object_id);
+    Ok(true)
+}
+
+// Writes the sidecar and hands the file to the download workers, or in a dry run records
+// what that would do.
+fn save_media<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    ctx: &mut SyncContext<A, S>, downloads: &mut Downloads, activity: &Activity, month_path: &str, filename: &str,
+    src_url: reqwest::Url,
+) -> Result<(), String> {
+    if ctx.report_opt.is_some() {
+        plan_fetch(ctx, &activity.object_id, month_path, filename, &src_url)?;
+        record_download(&mut ctx.run, Ok(None));
+        return Ok(());
+    }
+
+    ctx.storage.create_dir(month_path)?;
+    let dst_path = format!("{}/{}", month_path, filename);
+    write_sidecar(ctx, activity, &dst_path);
+    // Another activity's file by the same name is already on its way.
+    if downloads.pending.insert(dst_path.clone(), activity.object_id.clone()).is_some() {
+        debug!("{} already being downloaded; skipping", dst_path);
+        record_download(&mut ctx.run, Ok(None));
+        return Ok(());
+    }
+    // Blocks while every worker is busy and the queue is full.
+    downloads.job_sender.send((src_url, dst_path)).map_err(|e| format!("download workers stopped: {}", e))
+}
+
+// Media downloads handed to the workers of one student's sync, and their outcomes coming
+// back, by destination path.
+struct Downloads {
+    student_id: String,
+    job_sender: SyncSender<(reqwest::Url, String)>,
+    result_receiver: Receiver<(String, Result<Option<u64>, String>)>,
+    // The activity each pending download is for, by destination path.
+    pending: HashMap<String, String>,
+}
+
+// Mirrors finished downloads to WebDAV and records them in the run; with wait, keeps at
+// it until none are pending.
+fn finish_downloads<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    ctx: &mut SyncContext<A, S>, downloads: &mut Downloads, wait: bool,
+) {
+    loop {
+        let result = if wait && !downloads.pending.is_empty() {
+            downloads.result_receiver.recv().ok()
+        }
+        else {
+            downloads.result_receiver.try_recv().ok()
+        };
+        let Some((dst_path, outcome)) = result else {
+            break;
+        };
+        let activity_id = downloads.pending.remove(&dst_path).unwrap_or_default();
+        if outcome.is_ok() {
+            mirror_to_webdav(ctx, &dst_path);
+        }
+        if let Ok(Some(_)) = outcome {
+            note_saved(ctx, &dst_path, Some(&downloads.student_id), &activity_id);
+        }
+        record_download(&mut ctx.run, outcome);
+    }
+}
+
+// Dry-run counterpart of fetch_to_library. A conflict is a target path claimed by two
+// activities, or an existing file for the same activity under a different name (which
+// a real sync would download again next to it).
+fn plan_fetch<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    ctx: &mut SyncContext<A, S>, object_id: &str, month_path: &str, filename: &str, src_url: &reqwest::Url,
+) -> Result<(), String> {
+    let storage = ctx.storage;
+    let api = ctx.api;
+    let report = ctx.report_opt.as_mut().unwrap();
+    let dst_path = format!("{}/{}", month_path, filename);
+
+    if !report.planned_paths.insert(dst_path.clone()) {
+        report.conflicts.push(DryRunConflict {
+            path: dst_path,
+            reason: "more than one activity would be saved here".into(),
+        });
+        return Ok(());
+    }
+
+    if !report.listings.contains_key(month_path) {
+        report.listings.insert(month_path.into(), storage.list(month_path)?);
+    }
+    let listing = &report.listings[month_path];
+    if listing.iter().any(|name| name == filename) {
+        report.existing_files.push(dst_path);
+        return Ok(());
+    }
+
+    let extension = filename.rsplit('.').next().unwrap_or_default();
+    let same_activity_suffix = format!("-{}.{}", object_id, extension);
+    if let Some(other) = listing.iter().find(|name| name.ends_with(&same_activity_suffix)) {
+        report.conflicts.push(DryRunConflict {
+            path: dst_path,
+            reason: format!("already saved as {}", other),
+        });
+        return Ok(());
+    }
+
+    let bytes_opt = match api.content_length(src_url) {
+        Ok(bytes_opt) => bytes_opt,
+        Err(msg) => {
+            warn!("could not get size of {}: {}", redact_url(src_url), msg);
+            None
+        }
+    };
+    match bytes_opt {
+        Some(bytes) => report.estimated_bytes += bytes,
+        None => report.unknown_sizes += 1,
+    }
+    report.new_files.push(PlannedFile { path: dst_path, bytes: bytes_opt });
+    Ok(())
+}
+
+// Downloads src_url to dst_path unless it is already in the library, then mirrors it
+// to WebDAV if that is enabled. Returns the bytes downloaded, or None if it was skipped.
+fn fetch_to_library<A: BrightwheelApi, S: LibraryStorage + ?Sized>(ctx: &mut SyncContext<A, S>, src_url: &reqwest::Url, dst_path: &str) -> Result<Option<u64>, String> {
+    let outcome = download_to_library(ctx.api, ctx.storage, src_url, dst_path)?;
+    mirror_to_webdav(ctx, dst_path);
+    Ok(outcome)
+}
+
+// The download half of fetch_to_library; also what the download workers run.
+fn download_to_library<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    api: &A, storage: &S, src_url: &reqwest::Url, dst_path: &str,
+) -> Result<Option<u64>, String> {
+    if storage.exists(dst_path)? {
+        debug!("{} already exists; skipping", dst_path);
+        return Ok(None);
+    }
+    info!("downloading {} to {}", redact_url(src_url), dst_path);
+    let bytes = api.open_download(src_url)
+        .and_then(|mut reader| storage.write_from(dst_path, &mut reader))
+        .map_err(|e| format!("{}: {}", dst_path, e))?;
+    debug!("...done ({} bytes).", bytes);
+    Ok(Some(bytes))
+}
+
+fn mirror_to_webdav<A: BrightwheelApi, S: LibraryStorage + ?Sized>(ctx: &mut SyncContext<A, S>, dst_path: &str) {
+    if let Some(webdav) = ctx.webdav_opt.as_mut() {
+        let storage = ctx.storage;
+        match webdav.upload_if_missing(dst_path, || storage.open(dst_path)) {
+            Ok(Some(bytes)) => {
+                ctx.run.uploaded_items += 1;
+                ctx.run.bytes_uploaded += bytes;
+            },
+            Ok(None) => {},
+            Err(msg) => {
+                warn!("upload failed: {}", msg);
+                ctx.run.errors.push(msg);
+            }
+        }
+    }
+}
+
+// Records a file this sync saved, for the library's catalog.
+fn note_saved<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    ctx: &mut SyncContext<A, S>, path: &str, student_opt: Option<&str>, source: &str,
+) {
+    ctx.saved_items.insert(path.into(), CatalogItem {
+        account: ctx.run.account.clone().unwrap_or_default(),
+        student: student_opt.map(String::from),
+        source: source.into(),
+        saved_at: Timestamp::now(),
+    });
+}
+
+fn record_download(run: &mut SyncRun, outcome: Result<Option<u64>, String>) {
+    match outcome {
+        Ok(Some(bytes)) => {
+            run.new_items += 1;
+            run.bytes_transferred += bytes;
+        },
+        Ok(None) => {
+            run.skipped_items += 1;
+        },
+        Err(msg) => {
+            warn!("download failed: {}", msg);
+            run.failed_items += 1;
+            run.errors.push(msg);
+        }
+    }
+}
+
+// Keeps the activity's note and metadata next to the media file for the gallery and exports.
+fn write_sidecar<A: BrightwheelApi, S: LibraryStorage + ?Sized>(ctx: &SyncContext<A, S>, activity: &Activity, dst_path: &str) {
+    let sidecar_path = MediaSidecar::rel_path_for(dst_path);
+    let result = ctx.storage.exists(&sidecar_path).and_then(|exists| {
+        if exists {
+            return Ok(0);
+        }
+        let bytes = MediaSidecar::from_activity(activity).to_json_bytes();
+        ctx.storage.write_from(&sidecar_path, &mut bytes.as_slice())
+    });
+    if let Err(msg) = result {
+        warn!("could not write sidecar for {}: {}", dst_path, msg);
+    }
+}
+
+// Top-level folder for message threads and announcements, next to the student folders.
+const MESSAGES_PATH: &str = "messages";
+
+// Archives the account's message threads and announcements under MESSAGES_PATH: a
+// transcript of each, with its attachments next to it.
+fn sync_messages<A: BrightwheelApi, S: LibraryStorage + ?Sized>(ctx: &mut SyncContext<A, S>, user_id: &str) {
+    let _span = info_span!("messages").entered();
+    match ctx.api.message_threads(user_id) {
+        Ok(threads) => {
+            for thread in &threads {
+                sync_thread(ctx, thread);
+            }
+        },
+        Err(msg) => {
+            warn!("could not list message threads: {}", msg);
+            ctx.run.errors.push(format!("could not list message threads: {}", msg));
+        }
+    }
+    match ctx.api.announcements(user_id) {
+        Ok(announcements) => {
+            for announcement in &announcements {
+                sync_announcement(ctx, announcement);
+            }
+        },
+        Err(msg) => {
+            warn!("could not list announcements: {}", msg);
+            ctx.run.errors.push(format!("could not list announcements: {}", msg));
+        }
+    }
+}
+
+// messages/threads/<thread id>/transcript.txt, oldest message first, with the attachments
+// in the same folder.
+fn sync_thread<A: BrightwheelApi, S: LibraryStorage + ?Sized>(ctx: &mut SyncContext<A, S>, thread: &MessageThread) {
+    let mut messages = match ctx.api.messages(&thread.object_id) {
+        Ok(messages) => messages,
+        Err(msg) => {
+            warn!("could not load messages for thread {}: {}", thread.object_id, msg);
+            ctx.run.errors.push(format!("could not load messages for thread {}: {}", thread.object_id, msg));
+            return;
+        }
+    };
+    if messages.is_empty() {
+        return;
+    }
+    messages.sort_by_key(|message| message.created_at);
+
+    let thread_path = format!("{}/threads/{}", MESSAGES_PATH, thread.object_id);
+    let mut transcript = format!("{}\n", thread.title.as_deref().unwrap_or("Messages"));
+    for message in &messages {
+        transcript += &format!("\n{}\n", transcript_heading(message.created_at, message.sender.as_ref()));
+        if let Some(text) = message.text.as_deref().map(str::trim).filter(|text| !text.is_empty()) {
+            transcript += &format!("{}\n", text);
+        }
+        for (i, attachment) in message.attachments.iter().enumerate() {
+            let filename = attachment_filename(message.created_at, &message.object_id, i, attachment);
+            transcript += &format!("[attachment: {}]\n", filename);
+            let outcome = save_attachment(ctx, &thread_path, &filename, &message.object_id, attachment);
+            record_download(&mut ctx.run, outcome);
+        }
+    }
+    write_transcript(ctx, &format!("{}/transcript.txt", thread_path), &transcript);
+}
+
+// messages/announcements/YYYY-MM/<timestamp>-<id>.txt, with the attachments next to it.
+fn sync_announcement<A: BrightwheelApi, S: LibraryStorage + ?Sized>(ctx: &mut SyncContext<A, S>, announcement: &Announcement) {
+    let month_path = get_month_path(&format!("{}/announcements", MESSAGES_PATH), &announcement.created_at);
+    let mut transcript = String::new();
+    if let Some(title) = &announcement.title {
+        transcript += &format!("{}\n\n", title.trim());
+    }
+    transcript += &format!("{}\n", transcript_heading(announcement.created_at, announcement.sender.as_ref()));
+    if let Some(text) = announcement.text.as_deref().map(str::trim).filter(|text| !text.is_empty()) {
+        transcript += &format!("{}\n", text);
+    }
+    for (i, attachment) in announcement.attachments.iter().enumerate() {
+        let filename = attachment_filename(announcement.created_at, &announcement.object_id, i, attachment);
+        transcript += &format!("[attachment: {}]\n", filename);
+        let outcome = save_attachment(ctx, &month_path, &filename, &announcement.object_id, attachment);
+        record_download(&mut ctx.run, outcome);
+    }
+    let transcript_path = format!(
+        "{}/{}", month_path, format_filename(&announcement.created_at, &announcement.object_id, "txt")
+    );
+    write_transcript(ctx, &transcript_path, &transcript);
+}
+
+fn transcript_heading(created_at: Timestamp, sender_opt: Option<&Sender>) -> String {
+    let when = created_at.to_zoned(TimeZone::system()).strftime("%F %H:%M").to_string();
+    match sender_opt.map(Sender::display_name).filter(|name| !name.is_empty()) {
+        Some(name) => format!("{}  {}:", when, name),
+        None => format!("{}:", when),
+    }
+}
+
+// <timestamp>-<id>-<original name>, where id is the attachment's own if it has one.
+fn attachment_filename(created_at: Timestamp, parent_id: &str, index: usize, attachment: &Attachment) -> String {
+    let id = attachment.object_id.clone().unwrap_or_else(|| format!("{}-{}", parent_id, index + 1));
+    let original = attachment.filename.clone()
+        .or_else(|| {
+            reqwest::Url::parse(&attachment.url).ok()?
+                .path_segments()?
+                .next_back()
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "attachment".into());
+    format!("{}-{}-{}", created_at.strftime("%F-%H%M%S"), id, sanitize_filename(&original))
+}
+
+// Keeps a server-supplied name from escaping its folder or tripping up Windows.
+fn sanitize_filename(name: &str) -> String {
+    let cleaned = String::from_iter(name.chars().map(|c| {
+        if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c }
+    }));
+    let trimmed = cleaned.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if trimmed.is_empty() { "attachment".into() } else { trimmed.chars().take(100).collect() }
+}
+
+// Fetches an attachment of the message or announcement source into dir_path like any
+// other media, or plans it in a dry run.
+fn save_attachment<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    ctx: &mut SyncContext<A, S>, dir_path: &str, filename: &str, source: &str, attachment: &Attachment,
+) -> Result<Option<u64>, String> {
+    let src_url = reqwest::Url::parse(&attachment.url)
+        .map_err(|e| format!("bad attachment url for {}: {}", filename, e))?;
+    if ctx.report_opt.is_some() {
+        plan_fetch(ctx, attachment.object_id.as_deref().unwrap_or(filename), dir_path, filename, &src_url)?;
+        return Ok(None);
+    }
+    ctx.storage.create_dir(dir_path)?;
+    let dst_path = format!("{}/{}", dir_path, filename);
+    let outcome = fetch_to_library(ctx, &src_url, &dst_path)?;
+    if outcome.is_some() {
+        note_saved(ctx, &dst_path, None, source);
+    }
+    Ok(outcome)
+}
+
+// Transcripts grow as messages arrive, so unlike media they are rewritten when they change.
+fn write_transcript<A: BrightwheelApi, S: LibraryStorage + ?Sized>(ctx: &mut SyncContext<A, S>, path: &str, transcript: &str) {
+    if ctx.report_opt.is_some() {
+        return;
+    }
+    let result = ctx.storage.exists(path).and_then(|exists| {
+        if exists && ctx.storage.read(path)? == transcript.as_bytes() {
+            return Ok(());
+        }
+        let (dir_path, _) = path.rsplit_once('/').unwrap_or_default();
+        ctx.storage.create_dir(dir_path)?;
+        ctx.storage.write_from(path, &mut transcript.as_bytes()).map(|_| ())
+    });
+    if let Err(msg) = result {
+        warn!("could not write {}: {}", path, msg);
+        ctx.run.errors.push(format!("{}: {}", path, msg));
+    }
+}
+
+// Refreshes student.json in the student's folder, keeping the rooms and profile photos
+// seen on earlier syncs.
+fn write_student_profile<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    ctx: &mut SyncContext<A, S>, student_path: &str, student: &Student,
+) {
+    let profile_path = StudentProfile::rel_path_for(student_path);
+    let prev_result = ctx.storage.exists(&profile_path).and_then(|exists| {
+        if !exists {
+            return Ok(None);
+        }
+        Ok(
+            serde_json::from_slice(&ctx.storage.read(&profile_path)?)
+                .inspect_err(|e| warn!("replacing unreadable {}: {}", profile_path, e))
+                .ok()
+        )
+    });
+    let mut profile = match prev_result {
+        Ok(prev_opt) => StudentProfile::updated(prev_opt, student, ctx.run.started_at),
+        Err(msg) => {
+            warn!("could not read {}: {}", profile_path, msg);
+            return;
+        }
+    };
+
+    if let Some(url) = &student.profile_photo_url {
+        if let Err(msg) = save_profile_photo(ctx, student_path, &mut profile, url) {
+            warn!("could not save profile photo: {}", msg);
+            ctx.run.errors.push(format!("{}: profile photo: {}", student_path, msg));
+        }
+    }
+
+    let bytes = profile.to_json_bytes();
+    if let Err(msg) = ctx.storage.write_from(&profile_path, &mut bytes.as_slice()) {
+        warn!("could not write {}: {}", profile_path, msg);
+    }
+}
+
+// Saves the photo at url as profile/YYYY-MM-DD.jpg unless it's the one saved last time.
+// Versions are told apart by hash only: brightwheel re-signs its URLs, so a new URL just
+// means the photo is fetched again, and it's only kept as a new version if its sha256
+// differs from the last one saved. An unchanged URL isn't fetched at all.
+fn save_profile_photo<A: BrightwheelApi, S: LibraryStorage + ?Sized>(
+    ctx: &mut SyncContext<A, S>, student_path: &str, profile: &mut StudentProfile, url: &str,
+) -> Result<(), String> {
+    if profile.profile_photos.last().is_some_and(|photo| photo.url == url) {
+        return Ok(());
+    }
+    let src_url = reqwest::Url::parse(url).map_err(|e| format!("bad profile photo url: {}", e))?;
+    let mut bytes = Vec::new();
+    ctx.api.open_download(&src_url)?.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+    let sha256 = format!("{:x}", Sha256::digest(&bytes));
+
+    if let Some(photo) = profile.profile_photos.last_mut().filter(|photo| photo.sha256 == sha256) {
+        photo.url = url.into();
+        return Ok(());
+    }
+
+    let date = ctx.run.started_at.strftime("%F").to_string();
+    let mut path = format!("profile/{}.jpg", date);
+    for n in 2.. {
+        if !profile.profile_photos.iter().any(|photo| photo.path == path)
+            && !ctx.storage.exists(&format!("{}/{}", student_path, path))? {
+            break;
+        }
+        path = format!("profile/{}-{}.jpg", date, n);
+    }
+    info!("saving new profile photo to {}/{}", student_path, path);
+    ctx.storage.create_dir(&format!("{}/profile", student_path))?;
+    let dst_path = format!("{}/{}", student_path, path);
+    let written = ctx.storage.write_from(&dst_path, &mut bytes.as_slice())?;
+    let student_id = profile.student.object_id.clone();
+    note_saved(ctx, &dst_path, Some(&student_id), &student_id);
+    ctx.run.new_items += 1;
+    ctx.run.bytes_transferred += written;
+
+    profile.profile_photos.push(ProfilePhoto { path, url: url.into(), sha256, saved_at: ctx.run.started_at });
+    Ok(())
+}
+
+fn get_month_path(path: &str, ts: &Timestamp) -> String {
+    let month_str = ts.strftime("%Y-%m").to_string();
+    format!("{}/{}", path, month_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::brightwheel::{MockApi, Room};
+    use crate::catalog::Catalog;
+    use crate::handlers;
+    use crate::library::RoomStint;
+    use crate::storage::MemoryStorage;
+
+    fn photo(object_id: &str, created_at: &str, url: &str) -> Value {
+        json!({
+            "object_id": object_id,
+            "created_at": created_at,
+            "action_type": "ac_photo",
+            "note": "Painting",
+            "media": { "image_url": url },
+            "video_info": null,
+        })
+    }
+
+    fn video(object_id: &str, created_at: &str, url: &str) -> Value {
+        json!({
+            "object_id": object_id,
+            "created_at": created_at,
+            "action_type": "ac_video",
+            "media": null,
+            "video_info": { "downloadable_url": url },
+        })
+    }
+
+    fn nap(object_id: &str, created_at: &str) -> Value {
+        json!({
+            "object_id": object_id,
+            "created_at": created_at,
+            "action_type": "ac_nap",
+            "media": null,
+            "video_info": null,
+        })
+    }
+
+    fn run_sync(api: &MockApi, storage: &MemoryStorage) -> SyncRun {
+        run_scoped_sync(api, storage, SyncScope::Everything, &mut BTreeMap::new())
+    }
+
+    fn run_scoped_sync(api: &MockApi, storage: &MemoryStorage, scope: SyncScope, watermarks: &mut BTreeMap<String, Timestamp>) -> SyncRun {
+        let mut ctx = SyncContext {
+            api,
+            storage,
+            run: SyncRun::start(),
+            webdav_opt: None,
+            report_opt: None,
+            scope,
+            watermarks: std::mem::take(watermarks),
+            page_size: 1000,
+            fetched_records: HashSet::new(),
+            handlers: handlers::all(),
+            saved_items: BTreeMap::new(),
+        };
+        sync_account(&mut ctx);
+        *watermarks = ctx.watermarks;
+        ctx.run
+    }
+
+    fn dry_run(api: &MockApi, storage: &MemoryStorage) -> DryRunReport {
+        let mut ctx = SyncContext {
+            api,
+            storage,
+            run: SyncRun::start(),
+            webdav_opt: None,
+            report_opt: Some(DryRunReport::default()),
+            scope: SyncScope::Everything,
+            watermarks: BTreeMap::new(),
+            page_size: 1000,
+            fetched_records: HashSet::new(),
+            handlers: handlers::all(),
+            saved_items: BTreeMap::new(),
+        };
+        sync_account(&mut ctx);
+        ctx.report_opt.unwrap()
+    }
+
+    fn sample_api() -> MockApi {
+        MockApi::default()
+            .with_student("s1", "Ada", "Lovelace", vec![
+                video("v1", "2024-06-02T15:04:05Z", "https://cdn.example.com/v1.mp4"),
+                nap("n1", "2024-05-17T13:00:00Z"),
+                photo("p1", "2024-05-17T09:30:12Z", "https://cdn.example.com/p1.jpg"),
+            ])
+            .with_file("https://cdn.example.com/p1.jpg", b"jpeg bytes")
+            .with_file("https://cdn.example.com/v1.mp4", b"mp4")
+    }
+
+    #[test]
+    fn full_sync_writes_media_and_sidecars_by_month() {
+        let api = sample_api();
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(storage.file_paths(), vec![
+            ".shinydisc/catalog.json",
+            "Ada Lovelace/2024-05/2024-05-17-093012-p1.jpg",
+            "Ada Lovelace/2024-05/2024-05-17-093012-p1.json",
+            "Ada Lovelace/2024-06/2024-06-02-150405-v1.json",
+            "Ada Lovelace/2024-06/2024-06-02-150405-v1.mp4",
+            "Ada Lovelace/student.json",
+        ]);
+        assert_eq!(storage.read("Ada Lovelace/2024-05/2024-05-17-093012-p1.jpg").unwrap(), b"jpeg bytes");
+        let sidecar: MediaSidecar = serde_json::from_slice(
+            &storage.read("Ada Lovelace/2024-05/2024-05-17-093012-p1.json").unwrap()
+        ).unwrap();
+        assert_eq!(sidecar.note.as_deref(), Some("Painting"));
+
+        assert_eq!(run.account.as_deref(), Some("guardian-1"));
+        assert_eq!(run.students, vec!["Ada Lovelace"]);
+        assert_eq!(run.new_items, 2);
+        assert_eq!(run.skipped_items, 0);
+        assert_eq!(run.failed_items, 0);
+        assert_eq!(run.bytes_transferred, 13);
+    }
+
+    #[test]
+    fn sync_records_the_account_in_the_catalog() {
+        let api = sample_api();
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        let catalog = Catalog::load(&storage).unwrap();
+        let account = &catalog.accounts["guardian-1"];
+        assert_eq!(account.profile.display_name(), "Grace");
+        assert_eq!(account.last_synced_at, Some(run.started_at));
+
+        assert_eq!(Vec::from_iter(catalog.items.keys().map(String::as_str)), [
+            "Ada Lovelace/2024-05/2024-05-17-093012-p1.jpg",
+            "Ada Lovelace/2024-06/2024-06-02-150405-v1.mp4",
+        ]);
+        let item = &catalog.items["Ada Lovelace/2024-05/2024-05-17-093012-p1.jpg"];
+        assert_eq!((item.account.as_str(), item.student.as_deref(), item.source.as_str()), ("guardian-1", Some("s1"), "p1"));
+
+        // A second sync saves nothing new, and keeps what the first one recorded.
+        run_sync(&api, &storage);
+        assert_eq!(Catalog::load(&storage).unwrap().items, catalog.items);
+    }
+
+    #[test]
+    fn student_profile_keeps_earlier_rooms() {
+        let api = sample_api();
+        let storage = MemoryStorage::default();
+        let first = run_sync(&api, &storage);
+
+        let read_profile = || -> StudentProfile {
+            serde_json::from_slice(&storage.read("Ada Lovelace/student.json").unwrap()).unwrap()
+        };
+        let mut profile = read_profile();
+        assert_eq!(profile.student.rooms[0].name.as_deref(), Some("Toddlers"));
+        assert_eq!(profile.room_history.len(), 1);
+
+        // Last year's room, from an earlier sync.
+        profile.room_history.insert(0, RoomStint {
+            room: Room { object_id: "room-infants".into(), name: Some("Infants".into()) },
+            first_seen: "2023-09-01T00:00:00Z".parse().unwrap(),
+            last_seen: "2024-06-30T00:00:00Z".parse().unwrap(),
+        });
+        storage.write_from("Ada Lovelace/student.json", &mut profile.to_json_bytes().as_slice()).unwrap();
+
+        let second = run_sync(&api, &storage);
+        let profile = read_profile();
+        assert_eq!(Vec::from_iter(profile.room_history.iter().map(|stint| stint.room.object_id.as_str())), [
+            "room-infants", "room-s1",
+        ]);
+        assert_eq!(profile.room_history[1].first_seen, first.started_at);
+        assert_eq!(profile.room_history[1].last_seen, second.started_at);
+    }
+
+    #[test]
+    fn profile_photo_is_saved_again_only_when_it_changes() {
+        let api = sample_api()
+            .with_file("https://cdn.example.com/ada.jpg?sig=1", b"smile")
+            .with_file("https://cdn.example.com/ada.jpg?sig=2", b"smile")
+            .with_file("https://cdn.example.com/ada-new.jpg", b"grin");
+        let storage = MemoryStorage::default();
+        let set_photo = |url: &str| api.profile_photos.lock().unwrap().insert("s1".into(), url.into());
+        let read_profile = || -> StudentProfile {
+            serde_json::from_slice(&storage.read("Ada Lovelace/student.json").unwrap()).unwrap()
+        };
+
+        set_photo("https://cdn.example.com/ada.jpg?sig=1");
+        let run = run_sync(&api, &storage);
+        let date = run.started_at.strftime("%F").to_string();
+        assert_eq!(run.new_items, 3);
+        assert_eq!(storage.read(&format!("Ada Lovelace/profile/{}.jpg", date)).unwrap(), b"smile");
+
+        // The same photo under a freshly signed URL.
+        set_photo("https://cdn.example.com/ada.jpg?sig=2");
+        let run = run_sync(&api, &storage);
+        assert_eq!(run.new_items, 0);
+        let profile = read_profile();
+        assert_eq!(profile.profile_photos.len(), 1);
+        assert_eq!(profile.profile_photos[0].url, "https://cdn.example.com/ada.jpg?sig=2");
+
+        set_photo("https://cdn.example.com/ada-new.jpg");
+        run_sync(&api, &storage);
+        assert_eq!(Vec::from_iter(read_profile().profile_photos.iter().map(|photo| photo.path.clone())), [
+            format!("profile/{}.jpg", date), format!("profile/{}-2.jpg", date),
+        ]);
+        assert_eq!(storage.read(&format!("Ada Lovelace/profile/{}-2.jpg", date)).unwrap(), b"grin");
+
+        // Unchanged URL: not even downloaded.
+        let downloads_before = api.downloads.lock().unwrap().len();
+        run_sync(&api, &storage);
+        assert_eq!(api.downloads.lock().unwrap().len(), downloads_before);
+    }
+
+    #[test]
+    fn messages_and_announcements_are_archived_with_attachments() {
+        let api = MockApi::default()
+            .with_thread(json!({ "object_id": "t1", "title": "Ada's teachers" }), vec![
+                json!({
+                    "object_id": "m2", "created_at": "2024-05-18T08:00:00Z", "body": "Here's this week's menu",
+                    "sender": { "first_name": "Ms.", "last_name": "Frizzle" },
+                    "attachments": [{ "url": "https://cdn.example.com/files/menu%20may.pdf" }],
+                }),
+                json!({
+                    "object_id": "m1", "created_at": "2024-05-17T08:00:00Z", "text": "Is there a menu?",
+                    "sender": { "name": "Grace Hopper" },
+                }),
+            ])
+            .with_announcement(json!({
+                "object_id": "a1", "created_at": "2024-06-01T12:00:00Z", "title": "Picture day",
+                "message": "Smile!",
+                "attachments": [{ "object_id": "f1", "file_url": "https://cdn.example.com/f1", "name": "form?.pdf" }],
+            }))
+            .with_file("https://cdn.example.com/files/menu%20may.pdf", b"%PDF menu")
+            .with_file("https://cdn.example.com/f1", b"%PDF form");
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(run.new_items, 2);
+        assert_eq!(storage.list("messages/threads/t1").unwrap(), vec![
+            "2024-05-18-080000-m2-1-menu may.pdf",
+            "transcript.txt",
+        ]);
+        assert_eq!(storage.list("messages/announcements/2024-06").unwrap(), vec![
+            "2024-06-01-120000-a1.txt",
+            "2024-06-01-120000-f1-form_.pdf",
+        ]);
+
+        let transcript = String::from_utf8(storage.read("messages/threads/t1/transcript.txt").unwrap()).unwrap();
+        assert!(transcript.starts_with("Ada's teachers\n"));
+        let asked = transcript.find("Grace Hopper:\nIs there a menu?").unwrap();
+        let answered = transcript.find("Ms. Frizzle:\nHere's this week's menu\n[attachment: 2024-05-18-080000-m2-1-menu may.pdf]").unwrap();
+        assert!(asked < answered);
+
+        let second = run_sync(&api, &storage);
+        assert_eq!(second.new_items, 0);
+        assert_eq!(second.skipped_items, 2);
+    }
+
+    #[test]
+    fn daily_reports_and_observations_are_saved_as_json_and_html() {
+        let nap_at: Timestamp = "2024-05-17T13:00:00Z".parse().unwrap();
+        let date = nap_at.to_zoned(TimeZone::system()).date();
+        let mut api = MockApi::default()
+            .with_student("s1", "Ada", "Lovelace", vec![
+                json!({ "object_id": "o1", "created_at": "2024-05-17T15:00:00Z", "action_type": "ac_observation" }),
+                json!({ "object_id": "n1", "created_at": nap_at, "action_type": "ac_nap" }),
+                json!({ "object_id": "f1", "created_at": "2024-05-17T12:59:00Z", "action_type": "ac_food" }),
+            ]);
+        api.daily_reports.insert(("s1".into(), date), json!({
+            "object_id": "d1",
+            "naps": [{ "start_time": "2024-05-17T13:00:00Z", "note": "Slept <2h>" }],
+            "meals": [],
+        }));
+        api.observations.insert("o1".into(), json!({ "milestone": "Stacks blocks", "learning_area": "Motor" }));
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(run.new_records, 2);
+        assert_eq!(*api.records_fetched.lock().unwrap(), 2);
+        let report_path = format!("Ada Lovelace/daily-reports/{}/{}", date.strftime("%Y-%m"), date);
+        let report: Value = serde_json::from_slice(&storage.read(&format!("{}.json", report_path)).unwrap()).unwrap();
+        assert_eq!(report["object_id"], "d1");
+        let html = String::from_utf8(storage.read(&format!("{}.html", report_path)).unwrap()).unwrap();
+        assert!(html.contains("<dt>Naps</dt>"));
+        assert!(html.contains("Slept &lt;2h&gt;"));
+        assert!(!html.contains("Meals"));
+        assert!(storage.exists("Ada Lovelace/observations/2024-05/2024-05-17-150000-o1.html").unwrap());
+
+        // Past days are not asked for again.
+        let run = run_sync(&api, &storage);
+        assert_eq!(run.new_records, 0);
+        assert_eq!(*api.records_fetched.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn every_handler_that_accepts_an_activity_runs() {
+        let api = MockApi::default()
+            .with_student("s1", "Ada", "Lovelace", vec![
+                json!({
+                    "object_id": "pv1", "created_at": "2024-05-17T09:30:12Z", "action_type": "ac_video",
+                    "media": { "image_url": "https://cdn.example.com/poster.jpg" },
+                    "video_info": { "downloadable_url": "https://cdn.example.com/pv1.mp4" },
+                }),
+                json!({
+                    "object_id": "note1", "created_at": "2024-05-17T10:00:00Z", "action_type": "ac_note",
+                    "note": "  Ada helped tidy up today.  ",
+                }),
+            ])
+            .with_file("https://cdn.example.com/poster.jpg", b"jpeg")
+            .with_file("https://cdn.example.com/pv1.mp4", b"mp4");
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(run.new_items, 2);
+        assert_eq!(run.new_records, 1);
+        assert_eq!(storage.list("Ada Lovelace/2024-05").unwrap(), vec![
+            "2024-05-17-093012-pv1.jpg",
+            "2024-05-17-093012-pv1.json",
+            "2024-05-17-093012-pv1.mp4",
+            "2024-05-17-100000-note1.txt",
+        ]);
+        assert_eq!(storage.read("Ada Lovelace/2024-05/2024-05-17-100000-note1.txt").unwrap(), b"Ada helped tidy up today.\n");
+    }
+
+    #[test]
+    fn album_photos_each_get_a_file() {
+        let api = MockApi::default()
+            .with_student("s1", "Ada", "Lovelace", vec![
+                json!({
+                    "object_id": "a1", "created_at": "2024-05-17T09:30:12Z", "action_type": "ac_photo",
+                    "media": [
+                        { "image_url": "https://cdn.example.com/a.jpg" },
+                        { "object_id": "m2", "image_url": "https://cdn.example.com/b.jpg" },
+                        { "image_url": "https://cdn.example.com/c.jpg" },
+                    ],
+                }),
+                json!({
+                    "object_id": "a2", "created_at": "2024-05-18T09:00:00Z", "action_type": "ac_photo",
+                    "media": { "image_url": "https://cdn.example.com/d.jpg" },
+                    "media_list": [
+                        { "image_url": "https://cdn.example.com/d.jpg" },
+                        { "image_url": "https://cdn.example.com/e.jpg" },
+                    ],
+                }),
+            ])
+            .with_file("https://cdn.example.com/a.jpg", b"a")
+            .with_file("https://cdn.example.com/b.jpg", b"b")
+            .with_file("https://cdn.example.com/c.jpg", b"c")
+            .with_file("https://cdn.example.com/d.jpg", b"d")
+            .with_file("https://cdn.example.com/e.jpg", b"e");
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(run.new_items, 5);
+        let photos = Vec::from_iter(
+            storage.list("Ada Lovelace/2024-05").unwrap().into_iter().filter(|name| name.ends_with(".jpg"))
+        );
+        // Album photos without a media id are named by a hash of their URL.
+        assert_eq!(photos, vec![
+            "2024-05-17-093012-a1-97e111b6.jpg",
+            "2024-05-17-093012-a1-e32fb90c.jpg",
+            "2024-05-17-093012-a1-m2.jpg",
+            "2024-05-18-090000-a2-90c949cb.jpg",
+            "2024-05-18-090000-a2.jpg",
+        ]);
+        assert_eq!(storage.read("Ada Lovelace/2024-05/2024-05-17-093012-a1-97e111b6.jpg").unwrap(), b"c");
+        assert!(storage.exists("Ada Lovelace/2024-05/2024-05-17-093012-a1-m2.json").unwrap());
+
+        let second = run_sync(&api, &storage);
+        assert_eq!(second.new_items, 0);
+        assert_eq!(second.skipped_items, 5);
+    }
+
+    #[test]
+    fn disabled_handlers_are_skipped() {
+        let api = sample_api();
+        let storage = MemoryStorage::default();
+        let mut ctx = SyncContext {
+            api: &api,
+            storage: &storage,
+            run: SyncRun::start(),
+            webdav_opt: None,
+            report_opt: None,
+            scope: SyncScope::Everything,
+            watermarks: BTreeMap::new(),
+            page_size: 1000,
+            fetched_records: HashSet::new(),
+            handlers: handlers::enabled(&["video".into(), "nap".into()]),
+            saved_items: BTreeMap::new(),
+        };
+        sync_account(&mut ctx);
+
+        assert_eq!(ctx.run.new_items, 1);
+        assert_eq!(*api.records_fetched.lock().unwrap(), 0);
+        assert!(!storage.exists("Ada Lovelace/2024-06/2024-06-02-150405-v1.mp4").unwrap());
+        assert!(handlers::describe(&["video".into()]).iter().any(|info| info.name == "video" && !info.enabled));
+    }
+
+    #[test]
+    fn second_sync_skips_existing_files() {
+        let api = sample_api();
+        let storage = MemoryStorage::default();
+        run_sync(&api, &storage);
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(api.downloads.lock().unwrap().len(), 2);
+        assert_eq!(run.new_items, 0);
+        assert_eq!(run.skipped_items, 2);
+        assert_eq!(storage.file_paths().len(), 6);
+    }
+
+    #[test]
+    fn downloads_overlap_up_to_the_worker_limit() {
+        let mut api = MockApi::default();
+        let mut activities = Vec::new();
+        for n in 0..12 {
+            let url = format!("https://cdn.example.com/p{}.jpg", n);
+            activities.push(photo(&format!("p{}", n), &format!("2024-05-{:02}T09:00:00Z", 20 - n), &url));
+            api = api.with_file(&url, b"jpeg");
+        }
+        api = api.with_student("s1", "Ada", "Lovelace", activities);
+        api.download_delay = Duration::from_millis(20);
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(run.new_items, 12);
+        assert!(run.errors.is_empty());
+        let most_open = api.open_downloads.lock().unwrap().1;
+        assert!(most_open > 1 && most_open <= DOWNLOAD_WORKERS, "{} downloads open at once", most_open);
+    }
+
+    #[test]
+    fn failed_download_is_recorded_and_not_kept() {
+        let api = MockApi::default()
+            .with_student("s1", "Ada", "Lovelace", vec![
+                photo("p1", "2024-05-17T09:30:12Z", "https://cdn.example.com/missing.jpg"),
+                photo("p2", "2024-05-18T09:30:12Z", "https://cdn.example.com/p2.jpg"),
+            ])
+            .with_file("https://cdn.example.com/p2.jpg", b"jpeg");
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(run.new_items, 1);
+        assert_eq!(run.failed_items, 1);
+        assert_eq!(run.errors.len(), 1);
+        assert!(!storage.exists("Ada Lovelace/2024-05/2024-05-17-093012-p1.jpg").unwrap());
+        assert!(storage.exists("Ada Lovelace/2024-05/2024-05-18-093012-p2.jpg").unwrap());
+
+        // The next sync retries it.
+        let api = api.with_file("https://cdn.example.com/missing.jpg", b"jpeg");
+        let run = run_sync(&api, &storage);
+        assert_eq!(run.new_items, 1);
+        assert_eq!(run.skipped_items, 1);
+    }
+
+    #[test]
+    fn sync_walks_every_page() {
+        let mut activities = Vec::new();
+        let mut api = MockApi::default();
+        for i in 0..1001 {
+            let url = format!("https://cdn.example.com/p{}.jpg", i);
+            activities.push(photo(&format!("p{}", i), "2024-05-17T09:30:12Z", &url));
+            api = api.with_file(&url, b"x");
+        }
+        let api = api.with_student("s1", "Ada", "Lovelace", activities);
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(run.new_items, 1001);
+        assert!(storage.exists("Ada Lovelace/2024-05/2024-05-17-093012-p1000.jpg").unwrap());
+    }
+
+    #[test]
+    fn unreadable_students_are_skipped_and_reported() {
+        let mut api = sample_api();
+        api.student_errors.push("student s9: missing field `last_name`".into());
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(run.students, ["Ada Lovelace"]);
+        assert_eq!(run.new_items, 2);
+        assert_eq!(run.errors, ["skipped student s9: missing field `last_name`"]);
+    }
+
+    #[test]
+    fn each_student_gets_a_folder() {
+        let api = sample_api()
+            .with_student("s2", "Grace", "Hopper", vec![
+                photo("p9", "2023-12-31T23:59:59Z", "https://cdn.example.com/p1.jpg"),
+            ]);
+        let storage = MemoryStorage::default();
+        let run = run_sync(&api, &storage);
+
+        assert_eq!(run.students, vec!["Ada Lovelace", "Grace Hopper"]);
+        assert_eq!(storage.list("").unwrap(), vec![".shinydisc", "Ada Lovelace", "Grace Hopper"]);
+        assert_eq!(storage.list("Grace Hopper/2023-12").unwrap(), vec![
+            "2023-12-31-235959-p9.jpg",
+            "2023-12-31-235959-p9.json",
+        ]);
+    }
+
+    #[test]
+    fn dry_run_reports_without_writing() {
+        let api = sample_api();
+        let storage = MemoryStorage::default();
+        let report = dry_run(&api, &storage);
+
+        assert!(storage.file_paths().is_empty());
+        assert!(storage.list("").unwrap().is_empty());
+        assert!(api.downloads.lock().unwrap().is_empty());
+        assert_eq!(
+            Vec::from_iter(report.new_files.iter().map(|file| file.path.as_str())),
+            vec!["Ada Lovelace/2024-06/2024-06-02-150405-v1.mp4", "Ada Lovelace/2024-05/2024-05-17-093012-p1.jpg"]
+        );
+        assert_eq!(report.estimated_bytes, 13);
+        assert_eq!(report.unknown_sizes, 0);
+        assert_eq!(report.non_media_activities.get("ac_nap"), Some(&1));
+        assert!(report.existing_files.is_empty());
+        assert!(report.conflicts.is_empty());
+    }
+
+    #[test]
+    fn dry_run_finds_existing_files_and_conflicts() {
+        let api = sample_api();
+        let storage = MemoryStorage::default();
+        run_sync(&api, &storage);
+
+        // The same photo under an older timestamp, and a second activity with the same id.
+        storage.rename(
+            "Ada Lovelace/2024-05/2024-05-17-093012-p1.jpg",
+            "Ada Lovelace/2024-05/2024-05-17-093011-p1.jpg",
+        ).unwrap();
+        let api = api.with_student("s2", "Ada", "Lovelace", vec![
+            video("v1", "2024-06-02T15:04:05Z", "https://cdn.example.com/v1.mp4"),
+        ]);
+        let report = dry_run(&api, &storage);
+
+        assert!(report.new_files.is_empty());
+        assert_eq!(report.existing_files, vec!["Ada Lovelace/2024-06/2024-06-02-150405-v1.mp4"]);
+        assert_eq!(
+            Vec::from_iter(report.conflicts.iter().map(|conflict| conflict.path.as_str())),
+            vec!["Ada Lovelace/2024-05/2024-05-17-093012-p1.jpg", "Ada Lovelace/2024-06/2024-06-02-150405-v1.mp4"]
+        );
+    }
+
+    #[test]
+    fn since_stops_at_older_activities() {
+        let mut activities = Vec::new();
+        let mut api = MockApi::default();
+        // Newest first: 1500 activities, one a day back from 2024-06-30.
+        for i in 0..1500 {
+            let created_at: Timestamp = "2024-06-30T12:00:00Z".parse().unwrap();
+            let created_at = created_at.checked_sub((i * 24).hours()).unwrap();
+            let url = format!("https://cdn.example.com/p{}.jpg", i);
+            activities.push(photo(&format!("p{}", i), &created_at.to_string(), &url));
+            api = api.with_file(&url, b"x");
+        }
+        let api = api.with_student("s1", "Ada", "Lovelace", activities);
+        let storage = MemoryStorage::default();
+        let scope = SyncScope::Between {
+            since: Some("2024-06-01T00:00:00Z".parse().unwrap()),
+            until: Some("2024-06-20T00:00:00Z".parse().unwrap()),
+        };
+        let run = run_scoped_sync(&api, &storage, scope, &mut BTreeMap::new());
+
+        // 2024-06-01 through 2024-06-19.
+        assert_eq!(run.new_items, 19);
+        assert_eq!(storage.list("Ada Lovelace").unwrap(), vec!["2024-06", "student.json"]);
+        assert_eq!(api.page_requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn since_last_sync_uses_the_watermark() {
+        let api = sample_api();
+        let storage = MemoryStorage::default();
+        let mut watermarks = BTreeMap::new();
+        run_scoped_sync(&api, &storage, SyncScope::SinceLastSync, &mut watermarks);
+        assert_eq!(watermarks.get("s1").map(Timestamp::to_string).as_deref(), Some("2024-06-02T15:04:05Z"));
+
+        let mut api = api.with_file("https://cdn.example.com/p2.jpg", b"new");
+        api.activities.get_mut("s1").unwrap().insert(
+            0, photo("p2", "2024-06-03T08:00:00Z", "https://cdn.example.com/p2.jpg")
+        );
+        let run = run_scoped_sync(&api, &storage, SyncScope::SinceLastSync, &mut watermarks);
+
+        // p2 is new and v1 sits on the watermark itself; p1 and the nap are never looked at.
+        assert_eq!(run.new_items, 1);
+        assert_eq!(run.skipped_items, 1);
+        assert_eq!(watermarks.get("s1").map(Timestamp::to_string).as_deref(), Some("2024-06-03T08:00:00Z"));
+    }
+
+    #[test]
+    fn failures_hold_the_watermark_back() {
+        let api = MockApi::default()
+            .with_student("s1", "Ada", "Lovelace", vec![
+                photo("p1", "2024-05-17T09:30:12Z", "https://cdn.example.com/missing.jpg"),
+            ]);
+        let mut watermarks = BTreeMap::new();
+        run_scoped_sync(&api, &MemoryStorage::default(), SyncScope::SinceLastSync, &mut watermarks);
+        assert!(watermarks.is_empty());
+    }
+}
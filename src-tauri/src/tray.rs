@@ -0,0 +1,69 @@
+use tauri::{
+    menu::{Menu, MenuItem, PredefinedMenuItem},
+    tray::TrayIconBuilder,
+    App, AppHandle, Manager, Wry,
+};
+
+use tracing::warn;
+
+use crate::sync::SyncScope;
+
+const TRAY_ID: &str = "main";
+
+struct TrayState {
+    status_item: MenuItem<Wry>,
+}
+
+pub fn create(app: &App) -> tauri::Result<()> {
+    let status_item = MenuItem::with_id(app, "status", "Last sync: never", false, None::<&str>)?;
+    let sync_item = MenuItem::with_id(app, "sync_now", "Sync now", true, None::<&str>)?;
+    let show_item = MenuItem::with_id(app, "show", "Show shinydisc", true, None::<&str>)?;
+    let quit_item = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
+    let separator = PredefinedMenuItem::separator(app)?;
+    let menu = Menu::with_items(app, &[&status_item, &separator, &sync_item, &show_item, &quit_item])?;
+
+    TrayIconBuilder::with_id(TRAY_ID)
+        .icon(app.default_window_icon().unwrap().clone())
+        .tooltip("shinydisc")
+        .menu(&menu)
+        .on_menu_event(|app, event| {
+            match event.id.as_ref() {
+                "sync_now" => {
+                    let app = app.clone();
+                    std::thread::spawn(move || {
+                        crate::sync_and_report(&app, SyncScope::Everything);
+                    });
+                },
+                "show" => show_main_window(app),
+                "quit" => app.exit(0),
+                _ => {}
+            }
+        })
+        .build(app)?;
+
+    app.manage(TrayState { status_item });
+    Ok(())
+}
+
+// The tray and window are cosmetic here; a platform that refuses an update shouldn't take
+// a sync or the app down with it, so failures are only logged.
+pub fn set_status(app: &AppHandle, text: &str) {
+    if let Some(tray_state) = app.try_state::<TrayState>() {
+        if let Err(e) = tray_state.status_item.set_text(text) {
+            warn!("could not update tray status: {}", e);
+        }
+    }
+    if let Some(tray) = app.tray_by_id(TRAY_ID) {
+        if let Err(e) = tray.set_tooltip(Some(format!("shinydisc\n{}", text))) {
+            warn!("could not update tray tooltip: {}", e);
+        }
+    }
+}
+
+pub fn show_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        if let Err(e) = window.show().and_then(|_| window.unminimize()).and_then(|_| window.set_focus()) {
+            warn!("could not show main window: {}", e);
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::{collections::{HashMap, HashSet}, io::Read, time::Duration};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::{blocking::{Body, Client}, Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+// Escaped in each path segment of a remote path.
+const SEGMENT_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'/').add(b'<').add(b'>').add(b'?')
+    .add(b'[').add(b']').add(b'^').add(b'`').add(b'{').add(b'|').add(b'}');
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/></d:prop></d:propfind>"#;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WebDavSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    // Base collection URL, e.g. https://cloud.example.com/remote.php/dav/files/me/brightwheel/
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+// Mirrors files from the local library to a WebDAV collection using the same
+// relative layout (Student Name/YYYY-MM/file).
+pub struct WebDavClient {
+    client: Client,
+    base_url: Url,
+    username: String,
+    password: String,
+    // Remote directory listings, fetched once per directory per sync.
+    listings: HashMap<String, HashSet<String>>,
+}
+
+impl WebDavClient {
+    pub fn new(settings: &WebDavSettings) -> Result<WebDavClient, String> {
+        let mut base_url = Url::parse(&settings.url).map_err(|e| format!("bad WebDAV URL: {}", e))?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        let client = Client::builder()
+            .timeout(Duration::from_secs(300))
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(WebDavClient {
+            client,
+            base_url,
+            username: settings.username.clone(),
+            password: settings.password.clone(),
+            listings: HashMap::new(),
+        })
+    }
+
+    // Streams the file from open_contents (a reader and its length) to rel_path unless a
+    // file with that name is already there. Returns the number of bytes uploaded, or None
+    // if it was skipped.
+    pub fn upload_if_missing<F>(&mut self, rel_path: &str, open_contents: F) -> Result<Option<u64>, String>
+    where F: FnOnce() -> Result<(Box<dyn Read + Send>, u64), String> {
+        let (rel_dir, file_name) = rel_path.rsplit_once('/').unwrap_or(("", rel_path));
+        if self.list_dir(rel_dir)?.contains(file_name) {
+            debug!("webdav: {} already present", rel_path);
+            return Ok(None);
+        }
+
+        self.ensure_dir(rel_dir)?;
+        let (reader, len) = open_contents()?;
+        info!("webdav: uploading {}", rel_path);
+        let response = self.request(Method::PUT, rel_path)?
+            .body(Body::sized(reader, len))
+            .send()
+            .map_err(|e| e.to_string())?;
+        check_status(&response, rel_path)?;
+
+        self.listings.entry(rel_dir.to_string()).or_default().insert(file_name.to_string());
+        Ok(Some(len))
+    }
+
+    // Names of the entries in a remote directory; a missing directory is empty.
+    fn list_dir(&mut self, rel_dir: &str) -> Result<&HashSet<String>, String> {
+        if !self.listings.contains_key(rel_dir) {
+            let dir_url = self.url(&dir_path(rel_dir))?;
+            let response = self.request(Method::from_bytes(b"PROPFIND").unwrap(), &dir_path(rel_dir))?
+                .header("Depth", "1")
+                .header(reqwest::header::CONTENT_TYPE, "application/xml")
+                .body(PROPFIND_BODY)
+                .send()
+                .map_err(|e| e.to_string())?;
+            let names = if response.status() == StatusCode::NOT_FOUND {
+                HashSet::new()
+            }
+            else {
+                check_status(&response, rel_dir)?;
+                let body = response.text().map_err(|e| e.to_string())?;
+                entry_names(&body, &dir_url)
+            };
+            self.listings.insert(rel_dir.to_string(), names);
+        }
+        Ok(&self.listings[rel_dir])
+    }
+
+    // Creates each missing component of rel_dir with MKCOL.
+    fn ensure_dir(&mut self, rel_dir: &str) -> Result<(), String> {
+        let mut prefix = String::new();
+        for segment in rel_dir.split('/').filter(|segment| !segment.is_empty()) {
+            let parent = prefix.clone();
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(segment);
+
+            if self.list_dir(&parent)?.contains(segment) {
+                continue;
+            }
+            debug!("webdav: creating {}", prefix);
+            let response = self.request(Method::from_bytes(b"MKCOL").unwrap(), &dir_path(&prefix))?
+                .send()
+                .map_err(|e| e.to_string())?;
+            // 405 means the collection already exists.
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                check_status(&response, &prefix)?;
+            }
+            self.listings.entry(parent).or_default().insert(segment.to_string());
+            self.listings.entry(prefix.clone()).or_default();
+        }
+        Ok(())
+    }
+
+    fn url(&self, rel_path: &str) -> Result<Url, String> {
+        self.base_url.join(&encode_path(rel_path)).map_err(|e| e.to_string())
+    }
+
+    fn request(&self, method: Method, rel_path: &str) -> Result<reqwest::blocking::RequestBuilder, String> {
+        Ok(self.client.request(method, self.url(rel_path)?).basic_auth(&self.username, Some(&self.password)))
+    }
+}
+
+fn dir_path(rel_dir: &str) -> String {
+    if rel_dir.is_empty() { String::new() } else { format!("{}/", rel_dir) }
+}
+
+fn encode_path(rel_path: &str) -> String {
+    Vec::from_iter(
+        rel_path.split('/').map(|segment| utf8_percent_encode(segment, SEGMENT_ENCODE_SET).to_string())
+    ).join("/")
+}
+
+fn check_status(response: &reqwest::blocking::Response, what: &str) -> Result<(), String> {
+    if response.status().is_success() {
+        Ok(())
+    }
+    else {
+        Err(format!("webdav {}: HTTP {}", what, response.status()))
+    }
+}
+
+// Names of the entries in a PROPFIND listing of dir_url. The listing includes the
+// directory itself, which is left out.
+fn entry_names(body: &str, dir_url: &Url) -> HashSet<String> {
+    let dir_path = dir_url.path().trim_end_matches('/');
+    HashSet::from_iter(extract_hrefs(body).iter().filter_map(|href| {
+        // Servers send hrefs as absolute paths or full URLs.
+        let url = dir_url.join(href).ok()?;
+        if url.path().trim_end_matches('/') == dir_path {
+            return None;
+        }
+        last_segment(url.path())
+    }))
+}
+
+// Pulls the text of every <href> element (any namespace prefix) out of a multistatus body.
+fn extract_hrefs(body: &str) -> Vec<String> {
+    let mut hrefs = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else { break };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        // Closing tags, self-closing tags, declarations and comments carry no text.
+        if tag.starts_with(['/', '?', '!']) || tag.ends_with('/') {
+            continue;
+        }
+        let name = tag.split_whitespace().next().unwrap_or(tag);
+        let local_name = name.rsplit(':').next().unwrap_or(name);
+        if local_name.eq_ignore_ascii_case("href") {
+            let text_end = rest.find('<').unwrap_or(rest.len());
+            let href = rest[..text_end].trim();
+            if !href.is_empty() {
+                hrefs.push(href.replace("&amp;", "&"));
+            }
+        }
+    }
+    hrefs
+}
+
+fn last_segment(href: &str) -> Option<String> {
+    let segment = href.trim_end_matches('/').rsplit('/').next()?;
+    let name = percent_decode_str(segment).decode_utf8().ok()?;
+    if name.is_empty() { None } else { Some(name.into_owned()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Cursor, Write},
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+
+    const LISTING: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:">
+ <d:response>
+  <d:href>/dav/Ada%20Lovelace/</d:href>
+  <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+ </d:response>
+ <d:response>
+  <d:href>/dav/Ada%20Lovelace/2024-05/</d:href>
+  <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+ </d:response>
+ <d:response>
+  <D:href xmlns:D="DAV:">https://cloud.example.com/dav/Ada%20Lovelace/notes%20%26%20more.txt</D:href>
+  <d:propstat><d:prop><d:resourcetype/></d:prop></d:propstat>
+ </d:response>
+ <d:response>
+  <href>/dav/Ada%20Lovelace/photo.jpg</href>
+ </d:response>
+</d:multistatus>"#;
+
+    #[test]
+    fn propfind_listing_names_the_entries_but_not_the_directory() {
+        assert_eq!(extract_hrefs(LISTING), [
+            "/dav/Ada%20Lovelace/",
+            "/dav/Ada%20Lovelace/2024-05/",
+            "https://cloud.example.com/dav/Ada%20Lovelace/notes%20%26%20more.txt",
+            "/dav/Ada%20Lovelace/photo.jpg",
+        ]);
+
+        let dir_url = Url::parse("https://cloud.example.com/dav/Ada%20Lovelace/").unwrap();
+        let mut names = Vec::from_iter(entry_names(LISTING, &dir_url));
+        names.sort();
+        assert_eq!(names, ["2024-05", "notes & more.txt", "photo.jpg"]);
+
+        let empty = r#"<d:multistatus xmlns:d="DAV:"><d:response><d:href>/dav/</d:href></d:response></d:multistatus>"#;
+        assert!(entry_names(empty, &Url::parse("https://cloud.example.com/dav/").unwrap()).is_empty());
+    }
+
+    // A request the test server received: method, path and body.
+    type Received = (String, String, Vec<u8>);
+
+    // Serves WebDAV requests from a canned response function on a local port, recording
+    // every request it gets.
+    fn serve(respond: fn(&str, &str) -> (u16, &'static str)) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/dav", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let server_received = Arc::clone(&received);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let received = Arc::clone(&server_received);
+                thread::spawn(move || handle_connection(stream.unwrap(), respond, &received));
+            }
+        });
+        (url, received)
+    }
+
+    fn handle_connection(stream: TcpStream, respond: fn(&str, &str) -> (u16, &'static str), received: &Mutex<Vec<Received>>) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = request_line.split_whitespace();
+            let (method, path) = (parts.next().unwrap().to_string(), parts.next().unwrap().to_string());
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let (status, response_body) = respond(&method, &path);
+            received.lock().unwrap().push((method, path, body));
+            write!(
+                writer, "HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n{}", status, response_body.len(), response_body
+            ).unwrap();
+        }
+    }
+
+    fn contents(bytes: &'static [u8]) -> Result<(Box<dyn Read + Send>, u64), String> {
+        Ok((Box::new(Cursor::new(bytes)), bytes.len() as u64))
+    }
+
+    #[test]
+    fn uploads_create_missing_collections_and_skip_existing_files() {
+        let (url, received) = serve(|method, path| match (method, path) {
+            ("PROPFIND", "/dav/") => (207, r#"<d:multistatus xmlns:d="DAV:">
+                <d:response><d:href>/dav/</d:href></d:response>
+                <d:response><d:href>/dav/old.jpg</d:href></d:response>
+            </d:multistatus>"#),
+            ("PROPFIND", _) => (404, ""),
+            ("MKCOL", _) | ("PUT", _) => (201, ""),
+            _ => (500, ""),
+        });
+        let mut client = WebDavClient::new(&WebDavSettings {
+            enabled: true, url, username: "me".into(), password: "secret".into(),
+        }).unwrap();
+
+        assert_eq!(client.upload_if_missing("Ada Lovelace/2024-05/photo.jpg", || contents(b"jpeg!")).unwrap(), Some(5));
+        assert_eq!(client.upload_if_missing("Ada Lovelace/2024-05/photo.jpg", || contents(b"jpeg!")).unwrap(), None);
+        assert_eq!(client.upload_if_missing("old.jpg", || contents(b"old")).unwrap(), None);
+
+        let received = received.lock().unwrap();
+        let requests = Vec::from_iter(received.iter().map(|(method, path, _)| (method.as_str(), path.as_str())));
+        assert_eq!(requests, [
+            ("PROPFIND", "/dav/Ada%20Lovelace/2024-05/"),
+            ("PROPFIND", "/dav/"),
+            ("MKCOL", "/dav/Ada%20Lovelace/"),
+            ("MKCOL", "/dav/Ada%20Lovelace/2024-05/"),
+            ("PUT", "/dav/Ada%20Lovelace/2024-05/photo.jpg"),
+        ]);
+        assert_eq!(received[4].2, b"jpeg!");
+    }
+
+    #[test]
+    fn failed_uploads_report_the_status() {
+        let (url, _received) = serve(|method, _path| match method {
+            "PROPFIND" => (404, ""),
+            "MKCOL" => (405, ""),
+            _ => (507, ""),
+        });
+        let mut client = WebDavClient::new(&WebDavSettings {
+            enabled: true, url, username: "me".into(), password: "secret".into(),
+        }).unwrap();
+        let error = client.upload_if_missing("Ada/photo.jpg", || contents(b"jpeg!")).unwrap_err();
+        assert_eq!(error, "webdav Ada/photo.jpg: HTTP 507 Insufficient Storage");
+    }
+}
@@ -1,13 +1,26 @@
-const { invoke } = window.__TAURI__.core;
+const { invoke, convertFileSrc } = window.__TAURI__.core;
 
 let emailInput;
 let pwInput;
 let loginMsgEl;
 let mfaInput;
 let mfaMsgEl;
+let mfaInfoEl;
+let mfaMethodSelect;
+let syncStatusEl;
+let profileEl;
+let scheduleKindSelect;
+let scheduleHoursInput;
+let scheduleTimeInput;
+let historyTbody;
+let debugLoggingInput;
+let galleryStudentSelect;
+let galleryEl;
+let lightboxEl;
+let libraryStudents = [];
 
 function setTab(targetTabName) {
-  for(let tabName of ["login", "mfa", "loggedin"]) {
+  for(let tabName of ["login", "mfa", "loggedin", "gallery"]) {
     let tabEl = document.querySelector("#" + tabName + "-tab");
     if(tabName == targetTabName) {
       tabEl.classList.add("tab-visible");
@@ -20,32 +33,472 @@ function setTab(targetTabName) {
   }
 }
 
-async function init_view() {
-  let result = await invoke("init_view");
+const MFA_METHOD_NAMES = { email: "email", sms: "text message" };
+
+// Shows a login-flow result on whichever tab it points at.
+function showLoginResult(result) {
+  loginMsgEl.textContent = "";
+  mfaMsgEl.textContent = "";
+  if(result.message) {
+    (result.tab_name == "mfa" ? mfaMsgEl : loginMsgEl).textContent = result.message;
+  }
+  if(result.mfa) {
+    renderMfaStatus(result.mfa);
+  }
   setTab(result.tab_name);
+  if(result.tab_name == "loggedin") {
+    load_profile();
+  }
+}
+
+async function load_profile() {
+  try {
+    let profile = await invoke("get_profile");
+    let name = [profile.first_name, profile.last_name].filter(Boolean).join(" ");
+    let lines = ["Signed in as " + name + (profile.email ? " (" + profile.email + ")" : "")];
+    let students = profile.guardian_relationships
+      .filter((rel) => rel.student && rel.student.first_name)
+      .map((rel) => rel.student.first_name + (rel.relationship ? " (" + rel.relationship + ")" : ""));
+    if(students.length) {
+      lines.push("Students: " + students.join(", "));
+    }
+    let schools = profile.schools.map((school) => school.name).filter(Boolean);
+    if(schools.length) {
+      lines.push("Schools: " + schools.join(", "));
+    }
+    profileEl.textContent = lines.join(" \u00b7 ");
+  }
+  catch(e) {
+    console.log("get_profile failed:", e);
+    profileEl.textContent = "";
+  }
+}
+
+function renderMfaStatus(mfa) {
+  let expiresAt = new Date(mfa.expires_at).toLocaleTimeString([], { hour: "numeric", minute: "2-digit" });
+  mfaInfoEl.textContent = "We sent a code by " + MFA_METHOD_NAMES[mfa.method] + ". It expires at " + expiresAt + ".";
+  mfaMethodSelect.replaceChildren(...mfa.methods.map((method) => {
+    let option = document.createElement("option");
+    option.value = method;
+    option.textContent = "By " + MFA_METHOD_NAMES[method];
+    option.selected = method == mfa.method;
+    return option;
+  }));
+  mfaMethodSelect.hidden = mfa.methods.length < 2;
+}
+
+async function init_view() {
+  showLoginResult(await invoke("init_view"));
 }
 
 async function login() {
   let result = await invoke("login", { email: emailInput.value, password: pwInput.value });
   console.log("login result:", result);
-  if(result.message) {
-    loginMsgEl.textContent = result.message;
+  // The backend holds on to the password for the MFA step; don't keep it in the page.
+  if(result.tab_name != "login") {
+    pwInput.value = "";
   }
-  setTab(result.tab_name);
+  showLoginResult(result);
 }
 
 async function login_mfa() {
-  let result = await invoke("login_mfa", { email: emailInput.value, password: pwInput.value, mfaCode: mfaInput.value });
+  let result = await invoke("login_mfa", { mfaCode: mfaInput.value });
   console.log("login_mfa result:", result);
-  if(result.message) {
-    mfaMsgEl.textContent = result.message;
+  showLoginResult(result);
+}
+
+async function resend_mfa_code() {
+  mfaInput.value = "";
+  let result = await invoke("resend_mfa_code", { method: mfaMethodSelect.value || null });
+  console.log("resend_mfa_code result:", result);
+  showLoginResult(result);
+}
+
+async function restart_login() {
+  mfaInput.value = "";
+  showLoginResult(await invoke("restart_login"));
+}
+
+function selectedSyncScope() {
+  let kind = document.querySelector("#sync-scope-select").value;
+  if(kind == "last_days") {
+    return { kind: kind, days: 7 };
   }
-  setTab(result.tab_name);
+  return { kind: kind };
 }
 
 async function sync() {
-  let result = await invoke("sync");
+  syncStatusEl.textContent = "Syncing...";
+  let result = await invoke("sync", { scope: selectedSyncScope() });
   console.log("sync result:", result);
+  await refresh_sync_status();
+  await refresh_sync_history();
+}
+
+async function dry_run() {
+  let reportEl = document.querySelector("#dry-run-report");
+  reportEl.textContent = "Checking what a sync would do...";
+  let result = await invoke("sync", { dryRun: true, scope: selectedSyncScope() });
+  console.log("dry run result:", result);
+  if(!result.report) {
+    reportEl.textContent = result.message || "Nothing to report.";
+    return;
+  }
+  renderDryRunReport(reportEl, result.report);
+}
+
+function renderDryRunReport(reportEl, report) {
+  reportEl.replaceChildren();
+  let size = formatBytes(report.estimated_bytes);
+  if(report.unknown_sizes > 0) {
+    size += " plus " + report.unknown_sizes + " files of unknown size";
+  }
+  let summary = document.createElement("p");
+  summary.textContent = "Would download " + report.new_files.length + " new files (" + size + "); "
+    + report.existing_files.length + " already in the library; "
+    + report.conflicts.length + " conflicts.";
+  reportEl.appendChild(summary);
+
+  let otherActivities = Object.entries(report.non_media_activities);
+  if(otherActivities.length > 0) {
+    let other = document.createElement("p");
+    other.textContent = "Activities without photos or videos: "
+      + otherActivities.map(([kind, count]) => kind + " " + count).join(", ");
+    reportEl.appendChild(other);
+  }
+
+  let lines = [
+    ...report.conflicts.map((conflict) => "Conflict: " + conflict.path + " (" + conflict.reason + ")"),
+    ...report.errors.map((error) => "Error: " + error),
+    ...report.new_files.map((file) => "New: " + file.path + (file.bytes == null ? "" : " (" + formatBytes(file.bytes) + ")")),
+  ];
+  if(lines.length > 0) {
+    let list = document.createElement("ul");
+    for(let line of lines) {
+      let item = document.createElement("li");
+      item.textContent = line;
+      list.appendChild(item);
+    }
+    reportEl.appendChild(list);
+  }
+}
+
+function formatBytes(bytes) {
+  let units = ["B", "KB", "MB", "GB", "TB"];
+  let i = 0;
+  while(bytes >= 1024 && i < units.length - 1) {
+    bytes /= 1024;
+    i++;
+  }
+  return bytes.toFixed(i == 0 ? 0 : 1) + " " + units[i];
+}
+
+async function refresh_sync_history() {
+  let runs = await invoke("get_sync_history");
+  historyTbody.replaceChildren();
+  for(let run of runs) {
+    let started = new Date(run.started_at);
+    let duration = run.finished_at ? Math.round((new Date(run.finished_at) - started) / 1000) + " s" : "";
+    let row = document.createElement("tr");
+    for(let value of [
+      started.toLocaleString(), duration, run.students.join(", "),
+      run.new_items, run.skipped_items, run.failed_items, formatBytes(run.bytes_transferred)
+    ]) {
+      let cell = document.createElement("td");
+      cell.textContent = value;
+      row.appendChild(cell);
+    }
+    if(run.errors.length > 0) {
+      row.classList.add("error");
+      row.title = run.errors.join("\n");
+    }
+    historyTbody.appendChild(row);
+  }
+}
+
+async function refresh_sync_status() {
+  let status = await invoke("get_sync_status");
+  let text;
+  if(status.running) {
+    text = "Syncing...";
+  }
+  else if(status.last_sync) {
+    text = "Last sync: " + new Date(status.last_sync).toLocaleString() + " (" + status.last_message + ")";
+  }
+  else {
+    text = "Not synced yet";
+  }
+  if(status.next_sync) {
+    text += "; next sync: " + new Date(status.next_sync).toLocaleString();
+  }
+  syncStatusEl.textContent = text;
+}
+
+function updateScheduleInputs() {
+  let kind = scheduleKindSelect.value;
+  scheduleHoursInput.style.display = kind == "every_hours" ? "" : "none";
+  scheduleTimeInput.style.display = kind == "daily_at" ? "" : "none";
+}
+
+function updateLibraryInputs() {
+  let kind = document.querySelector("#library-kind-select").value;
+  document.querySelector("#library-local-fields").style.display = kind == "local" ? "" : "none";
+  document.querySelector("#library-s3-fields").style.display = kind == "s3" ? "" : "none";
+}
+
+async function load_settings() {
+  let settings = await invoke("get_settings");
+  let schedule = settings.schedule;
+  scheduleKindSelect.value = schedule.kind;
+  if(schedule.kind == "every_hours") {
+    scheduleHoursInput.value = schedule.hours;
+  }
+  else if(schedule.kind == "daily_at") {
+    scheduleTimeInput.value = schedule.time.slice(0, 5);
+  }
+  updateScheduleInputs();
+  debugLoggingInput.checked = settings.debug_logging;
+  document.querySelector("#webdav-enabled-input").checked = settings.webdav.enabled;
+  document.querySelector("#webdav-url-input").value = settings.webdav.url;
+  document.querySelector("#webdav-username-input").value = settings.webdav.username;
+  let library = settings.library;
+  document.querySelector("#library-kind-select").value = library.kind;
+  if(library.kind == "local") {
+    document.querySelector("#library-path-input").value = library.path;
+  }
+  else if(library.kind == "s3") {
+    document.querySelector("#s3-endpoint-input").value = library.endpoint;
+    document.querySelector("#s3-bucket-input").value = library.bucket;
+    document.querySelector("#s3-region-input").value = library.region;
+    document.querySelector("#s3-access-key-input").value = library.access_key;
+    document.querySelector("#s3-prefix-input").value = library.prefix;
+    document.querySelector("#s3-path-style-input").checked = library.path_style;
+  }
+  updateLibraryInputs();
+  renderSecretInputs(settings);
+}
+
+// Saved secrets never come back from the backend; their inputs stay empty, and leaving
+// them empty keeps the saved value.
+function renderSecretInputs(settings) {
+  let passwordInput = document.querySelector("#webdav-password-input");
+  passwordInput.value = "";
+  passwordInput.placeholder = settings.webdav_password_set ? "Password (saved)" : "Password";
+  let secretKeyInput = document.querySelector("#s3-secret-key-input");
+  secretKeyInput.value = "";
+  secretKeyInput.placeholder = settings.s3_secret_key_set ? "Secret key (saved)" : "Secret key";
+}
+
+async function set_library_location() {
+  let library = { kind: document.querySelector("#library-kind-select").value };
+  if(library.kind == "local") {
+    library.path = document.querySelector("#library-path-input").value || ".";
+  }
+  else if(library.kind == "s3") {
+    library.endpoint = document.querySelector("#s3-endpoint-input").value;
+    library.bucket = document.querySelector("#s3-bucket-input").value;
+    library.region = document.querySelector("#s3-region-input").value;
+    library.access_key = document.querySelector("#s3-access-key-input").value;
+    library.secret_key = document.querySelector("#s3-secret-key-input").value;
+    library.prefix = document.querySelector("#s3-prefix-input").value;
+    library.path_style = document.querySelector("#s3-path-style-input").checked;
+  }
+  try {
+    let settings = await invoke("set_library_location", { library: library });
+    renderSecretInputs(settings);
+  }
+  catch(e) {
+    syncStatusEl.textContent = "Could not save library location: " + e;
+  }
+}
+
+async function set_webdav_settings() {
+  let settings = await invoke("set_webdav_settings", {
+    webdav: {
+      enabled: document.querySelector("#webdav-enabled-input").checked,
+      url: document.querySelector("#webdav-url-input").value,
+      username: document.querySelector("#webdav-username-input").value,
+      password: document.querySelector("#webdav-password-input").value,
+    }
+  });
+  renderSecretInputs(settings);
+}
+
+async function load_activity_handlers() {
+  renderActivityHandlers(await invoke("get_activity_handlers"));
+}
+
+function renderActivityHandlers(handlers) {
+  document.querySelector("#handlers-p").replaceChildren(...handlers.map((handler) => {
+    let input = document.createElement("input");
+    input.type = "checkbox";
+    input.checked = handler.enabled;
+    input.addEventListener("change", async () => {
+      renderActivityHandlers(await invoke("set_activity_handler_enabled", { name: handler.name, enabled: input.checked }));
+    });
+    let label = document.createElement("label");
+    label.append(input, " " + handler.description);
+    return label;
+  }));
+}
+
+async function set_debug_logging() {
+  await invoke("set_debug_logging", { enabled: debugLoggingInput.checked });
+}
+
+async function set_schedule() {
+  let schedule = { kind: scheduleKindSelect.value };
+  if(schedule.kind == "every_hours") {
+    schedule.hours = parseInt(scheduleHoursInput.value);
+  }
+  else if(schedule.kind == "daily_at") {
+    schedule.time = scheduleTimeInput.value + ":00";
+  }
+  await invoke("set_schedule", { schedule: schedule });
+  await refresh_sync_status();
+}
+
+async function open_gallery() {
+  try {
+    libraryStudents = await invoke("list_library");
+  }
+  catch(e) {
+    syncStatusEl.textContent = "Could not open gallery: " + e;
+    return;
+  }
+  galleryStudentSelect.replaceChildren();
+  for(let student of libraryStudents) {
+    let option = document.createElement("option");
+    option.value = student.name;
+    option.textContent = student.name;
+    galleryStudentSelect.appendChild(option);
+  }
+  renderGallery();
+  setTab("gallery");
+}
+
+function renderGallery() {
+  galleryEl.replaceChildren();
+  let student = libraryStudents.find((s) => s.name == galleryStudentSelect.value);
+  if(!student) {
+    galleryEl.textContent = "Nothing synced yet.";
+    return;
+  }
+  for(let month of student.months) {
+    let days = new Map();
+    for(let item of month.items) {
+      let day = item.taken_at ? new Date(item.taken_at).toLocaleDateString() : month.month;
+      if(!days.has(day)) {
+        days.set(day, []);
+      }
+      days.get(day).push(item);
+    }
+
+    let monthEl = document.createElement("section");
+    let monthHeading = document.createElement("h2");
+    monthHeading.textContent = month.month;
+    monthEl.appendChild(monthHeading);
+    for(let [day, items] of days) {
+      let dayHeading = document.createElement("h3");
+      dayHeading.textContent = day;
+      monthEl.appendChild(dayHeading);
+
+      let grid = document.createElement("div");
+      grid.classList.add("gallery-grid");
+      for(let item of items) {
+        grid.appendChild(makeGalleryTile(item));
+      }
+      monthEl.appendChild(grid);
+    }
+    galleryEl.appendChild(monthEl);
+  }
+}
+
+function makeGalleryTile(item) {
+  let tile = document.createElement("button");
+  tile.type = "button";
+  tile.classList.add("gallery-tile");
+  if(item.kind == "photo") {
+    let img = document.createElement("img");
+    img.loading = "lazy";
+    img.src = convertFileSrc(item.path, "thumb");
+    tile.appendChild(img);
+  }
+  else {
+    tile.classList.add("gallery-video");
+    tile.textContent = "\u25B6";
+  }
+  if(item.caption) {
+    tile.title = item.caption;
+  }
+  tile.addEventListener("click", () => openLightbox(item));
+  return tile;
+}
+
+async function export_album() {
+  let statusEl = document.querySelector("#export-status-p");
+  let destDir = await window.__TAURI__.dialog.open({ directory: true, title: "Export album to..." });
+  if(!destDir) {
+    return;
+  }
+  let studentName = galleryStudentSelect.value;
+  destDir = destDir + "/" + studentName + " album";
+  statusEl.textContent = "Exporting...";
+  try {
+    let summary = await invoke("export_album", {
+      studentName: studentName,
+      destDir: destDir,
+      zip: document.querySelector("#export-zip-input").checked,
+    });
+    statusEl.textContent = "Exported " + summary.items + " items over " + summary.days + " days to " + (summary.zip_path || summary.path);
+  }
+  catch(e) {
+    statusEl.textContent = "Export failed: " + e;
+  }
+}
+
+async function export_for_importer() {
+  let statusEl = document.querySelector("#export-status-p");
+  let destDir = await window.__TAURI__.dialog.open({ directory: true, title: "Export library to..." });
+  if(!destDir) {
+    return;
+  }
+  statusEl.textContent = "Exporting...";
+  try {
+    let summary = await invoke("export_for_importer", {
+      target: document.querySelector("#importer-target-select").value,
+      destDir: destDir,
+    });
+    statusEl.textContent = "Exported " + summary.items + " items in " + summary.albums + " albums to " + summary.path;
+  }
+  catch(e) {
+    statusEl.textContent = "Export failed: " + e;
+  }
+}
+
+function openLightbox(item) {
+  let mediaEl = document.querySelector("#lightbox-media");
+  mediaEl.replaceChildren();
+  let src = convertFileSrc(item.path, "library");
+  if(item.kind == "photo") {
+    let img = document.createElement("img");
+    img.src = src;
+    mediaEl.appendChild(img);
+  }
+  else {
+    let video = document.createElement("video");
+    video.src = src;
+    video.controls = true;
+    video.autoplay = true;
+    mediaEl.appendChild(video);
+  }
+  document.querySelector("#lightbox-caption").textContent = item.caption || "";
+  lightboxEl.classList.remove("lightbox-hidden");
+}
+
+function closeLightbox() {
+  lightboxEl.classList.add("lightbox-hidden");
+  document.querySelector("#lightbox-media").replaceChildren();
 }
 
 window.addEventListener("DOMContentLoaded", () => {
@@ -54,6 +507,18 @@ window.addEventListener("DOMContentLoaded", () => {
   mfaInput = document.querySelector("#mfa-input");
   loginMsgEl = document.querySelector("#login-error-p");
   mfaMsgEl = document.querySelector("#mfa-error-p");
+  mfaInfoEl = document.querySelector("#mfa-info-p");
+  mfaMethodSelect = document.querySelector("#mfa-method-select");
+  syncStatusEl = document.querySelector("#sync-status-p");
+  profileEl = document.querySelector("#profile-p");
+  scheduleKindSelect = document.querySelector("#schedule-kind-select");
+  scheduleHoursInput = document.querySelector("#schedule-hours-input");
+  scheduleTimeInput = document.querySelector("#schedule-time-input");
+  historyTbody = document.querySelector("#history-tbody");
+  debugLoggingInput = document.querySelector("#debug-logging-input");
+  galleryStudentSelect = document.querySelector("#gallery-student-select");
+  galleryEl = document.querySelector("#gallery");
+  lightboxEl = document.querySelector("#lightbox");
   document.querySelector("#login-form").addEventListener("submit", (e) => {
     e.preventDefault();
     login();
@@ -62,12 +527,45 @@ window.addEventListener("DOMContentLoaded", () => {
     e.preventDefault();
     login_mfa();
   });
+  document.querySelector("#restart-login-button").addEventListener("click", restart_login);
+  document.querySelector("#resend-mfa-button").addEventListener("click", resend_mfa_code);
   document.querySelector("#loggedin-form").addEventListener("submit", (e) => {
     e.preventDefault();
     sync();
   });
+  document.querySelector("#schedule-form").addEventListener("submit", (e) => {
+    e.preventDefault();
+    set_schedule();
+  });
+  document.querySelector("#library-form").addEventListener("submit", (e) => {
+    e.preventDefault();
+    set_library_location();
+  });
+  document.querySelector("#library-kind-select").addEventListener("change", updateLibraryInputs);
+  document.querySelector("#webdav-form").addEventListener("submit", (e) => {
+    e.preventDefault();
+    set_webdav_settings();
+  });
+  scheduleKindSelect.addEventListener("change", updateScheduleInputs);
+  debugLoggingInput.addEventListener("change", set_debug_logging);
+  document.querySelector("#dry-run-button").addEventListener("click", dry_run);
+  document.querySelector("#open-gallery-button").addEventListener("click", open_gallery);
+  document.querySelector("#close-gallery-button").addEventListener("click", () => setTab("loggedin"));
+  galleryStudentSelect.addEventListener("change", renderGallery);
+  document.querySelector("#export-album-button").addEventListener("click", export_album);
+  document.querySelector("#export-importer-button").addEventListener("click", export_for_importer);
+  lightboxEl.addEventListener("click", (e) => {
+    if(e.target.tagName != "VIDEO") {
+      closeLightbox();
+    }
+  });
 
   init_view().then(() => {
     console.log("init_view returned");
   })
+  load_settings();
+  load_activity_handlers();
+  refresh_sync_status();
+  refresh_sync_history();
+  setInterval(refresh_sync_status, 30000);
 });